@@ -0,0 +1,86 @@
+use std::thread;
+use std::time::Duration;
+
+use fastboot::manifest::Manifest;
+use getopts::Options;
+use usbio::{poll_dev, UsbDevice};
+
+// Google
+const DEFAULT_VID: u16 = 0x18d1;
+const DEFAULT_PID: u16 = 0x4ee0;
+
+fn usage(program: &str, opts: &Options) {
+    let ver = env!("CARGO_PKG_VERSION");
+    let brief = format!("Version: {ver}\nUsage: {program} [options] <manifest.toml>");
+    println!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print help");
+    opts.optopt("", "vid", "Vendor ID", "<hex>");
+    opts.optopt("", "pid", "Product ID", "<hex>");
+    opts.optmulti("", "set", "Set a manifest variable", "<name=value>");
+    opts.optflag("n", "dry-run", "Check the files and print the steps");
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|err| {
+        eprintln!("{} failed to parse arguments ({})!", &program, err);
+        usage(&program, &opts);
+        std::process::exit(-1);
+    });
+
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        usage(&program, &opts);
+        std::process::exit(0);
+    }
+
+    let vid = match matches.opt_str("vid") {
+        Some(value) => u16::from_str_radix(&value, 16).expect("Parsing vendor ID failed"),
+        None => DEFAULT_VID,
+    };
+    let pid = match matches.opt_str("pid") {
+        Some(value) => u16::from_str_radix(&value, 16).expect("Parsing product ID failed"),
+        None => DEFAULT_PID,
+    };
+
+    let mut manifest = Manifest::load(&matches.free[0]).expect("Loading manifest failed");
+    for var in matches.opt_strs("set") {
+        let (name, value) = var
+            .split_once('=')
+            .expect("Variables are set as name=value");
+        manifest.set(name, value);
+    }
+
+    if matches.opt_present("n") {
+        manifest.verify().expect("Checking files failed");
+        for (i, step) in manifest.resolve().unwrap().iter().enumerate() {
+            println!("{}: {}", i + 1, step.command);
+        }
+        return;
+    }
+
+    let di = poll_dev(vid, pid).expect("Device not found, is it connected and in the right mode?");
+    let dev = UsbDevice::new(di);
+
+    // NOTE: Rebooting or continuing drops the connection, so wait for the
+    // device to go away and come back.
+    let reconnect = || {
+        thread::sleep(Duration::from_secs(2));
+        poll_dev(vid, pid).map(UsbDevice::new)
+    };
+    let report = |r: &fastboot::manifest::StepReport| match r.result {
+        None => println!("[{}/{}] {}", r.index, r.total, r.step.name),
+        Some(Ok(())) => println!("[{}/{}] OKAY ({:?})", r.index, r.total, r.elapsed),
+        Some(Err(e)) if r.step.optional => println!("[{}/{}] ignored: {e}", r.index, r.total),
+        Some(Err(e)) => println!("[{}/{}] FAILED: {e}", r.index, r.total),
+    };
+    match manifest.run(dev, reconnect, report) {
+        Ok(_) => println!("Flashed {}", matches.free[0]),
+        Err(e) => {
+            eprintln!("Flashing failed: {e}");
+            std::process::exit(1);
+        }
+    }
+}
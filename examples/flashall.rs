@@ -0,0 +1,84 @@
+use std::thread;
+use std::time::Duration;
+
+use fastboot::flashall::FlashAll;
+use fastboot::SlotSelect;
+use getopts::Options;
+use usbio::{poll_dev, UsbDevice};
+
+// Google
+const DEFAULT_VID: u16 = 0x18d1;
+const DEFAULT_PID: u16 = 0x4ee0;
+
+fn usage(program: &str, opts: &Options) {
+    let ver = env!("CARGO_PKG_VERSION");
+    let brief = format!("Version: {ver}\nUsage: {program} [options] <product dir or update zip>");
+    println!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print help");
+    opts.optopt("", "vid", "Vendor ID", "<hex>");
+    opts.optopt("", "pid", "Product ID", "<hex>");
+    opts.optopt(
+        "",
+        "slot",
+        "Slot to flash: current, other, all, a or b",
+        "<slot>",
+    );
+    opts.optflag(
+        "",
+        "cancel-snapshot",
+        "Cancel a pending Virtual A/B update instead of failing",
+    );
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|err| {
+        eprintln!("{} failed to parse arguments ({})!", &program, err);
+        usage(&program, &opts);
+        std::process::exit(-1);
+    });
+
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        usage(&program, &opts);
+        std::process::exit(0);
+    }
+
+    let vid = match matches.opt_str("vid") {
+        Some(value) => u16::from_str_radix(&value, 16).expect("Parsing vendor ID failed"),
+        None => DEFAULT_VID,
+    };
+    let pid = match matches.opt_str("pid") {
+        Some(value) => u16::from_str_radix(&value, 16).expect("Parsing product ID failed"),
+        None => DEFAULT_PID,
+    };
+
+    let path = &matches.free[0];
+    let mut flashall = match path.ends_with(".zip") {
+        true => FlashAll::open_zip(path).expect("Opening update package failed"),
+        false => FlashAll::new(path),
+    };
+    flashall.cancel_snapshot = matches.opt_present("cancel-snapshot");
+    if let Some(slot) = matches.opt_str("slot") {
+        flashall.slots = slot.parse::<SlotSelect>().expect("Parsing slot failed");
+    }
+
+    let di = poll_dev(vid, pid).expect("Device not found, is it connected and in the right mode?");
+    let dev = UsbDevice::new(di);
+
+    // NOTE: Rebooting into fastbootd drops the connection, so wait for the
+    // device to go away and come back.
+    let reconnect = || {
+        thread::sleep(Duration::from_secs(2));
+        poll_dev(vid, pid).map(UsbDevice::new)
+    };
+    match flashall.run(dev, reconnect) {
+        Ok(_) => println!("Flashed {path}"),
+        Err(e) => {
+            eprintln!("Flashing failed: {e}");
+            std::process::exit(1);
+        }
+    }
+}
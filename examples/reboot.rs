@@ -1,4 +1,4 @@
-use fastboot::Fastboot;
+use fastboot::{Fastboot, RebootTarget};
 use getopts::Options;
 use usbio::UsbDevice;
 
@@ -14,6 +14,12 @@ fn main() {
     let mut opts = Options::new();
     opts.optflag("h", "help", "Print help");
     opts.optflag("b", "bootloader", "Reboot into bootloader");
+    opts.optopt(
+        "t",
+        "target",
+        "Reboot into recovery, fastboot, powerdown or a custom target",
+        "<target>",
+    );
     opts.optopt("", "vid", "Vendor ID", "<hex>");
     opts.optopt("", "pid", "Product ID", "<hex>");
 
@@ -33,6 +39,12 @@ fn main() {
     }
 
     let to_bootloader = matches.opt_present("b");
+    let target = matches.opt_str("t").map(|t| match t.as_str() {
+        "recovery" => RebootTarget::Recovery,
+        "fastboot" => RebootTarget::Fastboot,
+        "powerdown" => RebootTarget::PowerDown,
+        _ => RebootTarget::Custom(t),
+    });
 
     let vid = match matches.opt_str("vid") {
         Some(value) => u16::from_str_radix(&value, 16).expect("Parsing vendor ID failed"),
@@ -50,8 +62,9 @@ fn main() {
     let mut dev = UsbDevice::new(di);
 
     // NOTE: The Fastboot trait gets us the necessary operations on the device.
-    match to_bootloader {
-        true => println!("Rebooting into bootloader: {:?}", dev.reboot_bootloader()),
-        false => println!("Rebooting: {:?}", dev.reboot()),
+    match (to_bootloader, target) {
+        (_, Some(t)) => println!("Rebooting into {t:?}: {:?}", dev.reboot_to(t.clone())),
+        (true, None) => println!("Rebooting into bootloader: {:?}", dev.reboot_bootloader()),
+        (false, None) => println!("Rebooting: {:?}", dev.reboot()),
     }
 }
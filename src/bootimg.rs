@@ -0,0 +1,384 @@
+//! Android boot images (`boot.img`), header versions 0 to 4.
+//!
+//! See `system/tools/mkbootimg/include/bootimg/bootimg.h` in AOSP. Every
+//! section starts on a page boundary and is padded with zeros to a full page.
+//! Versions 0 to 2 have a configurable page size; from version 3 on, it is
+//! fixed at 4096 bytes and load addresses are left to the vendor_boot image.
+
+use crate::bytes::{align_up, pad_to, put_cstr, Reader};
+
+pub const BOOT_MAGIC: &[u8; 8] = b"ANDROID!";
+
+const NAME_SIZE: usize = 16;
+const ARGS_SIZE: usize = 512;
+const EXTRA_ARGS_SIZE: usize = 1024;
+const V3_ARGS_SIZE: usize = 1536;
+const V3_PAGE_SIZE: u32 = 4096;
+
+const V0_HEADER_SIZE: usize = 1632;
+const V1_HEADER_SIZE: usize = 1648;
+const V2_HEADER_SIZE: usize = 1660;
+const V3_HEADER_SIZE: usize = 1580;
+const V4_HEADER_SIZE: usize = 1584;
+
+// The defaults of mkbootimg
+const DEFAULT_PAGE_SIZE: u32 = 2048;
+const DEFAULT_BASE: u32 = 0x1000_0000;
+const DEFAULT_KERNEL_OFFSET: u32 = 0x0000_8000;
+const DEFAULT_RAMDISK_OFFSET: u32 = 0x0100_0000;
+const DEFAULT_SECOND_OFFSET: u32 = 0x00f0_0000;
+const DEFAULT_TAGS_OFFSET: u32 = 0x0000_0100;
+const DEFAULT_DTB_OFFSET: u32 = 0x01f0_0000;
+
+/// A parsed boot image.
+///
+/// Fields that a header version does not have are ignored when writing, e.g.
+/// `second` for version 3 and later, or `signature` before version 4.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootImage {
+    pub header_version: u32,
+    /// Always 4096 for version 3 and later.
+    pub page_size: u32,
+    pub kernel: Vec<u8>,
+    pub ramdisk: Vec<u8>,
+    /// Second stage bootloader (versions 0 to 2)
+    pub second: Vec<u8>,
+    /// Recovery DTBO or ACPIO (versions 1 and 2)
+    pub recovery_dtbo: Vec<u8>,
+    /// Device tree blob (version 2)
+    pub dtb: Vec<u8>,
+    /// Boot signature (version 4)
+    pub signature: Vec<u8>,
+    pub kernel_addr: u32,
+    pub ramdisk_addr: u32,
+    pub second_addr: u32,
+    pub tags_addr: u32,
+    pub dtb_addr: u64,
+    /// OS version and security patch level, as packed by mkbootimg.
+    pub os_version: u32,
+    /// Product name (versions 0 to 2)
+    pub name: String,
+    /// Kernel command line; for versions 0 to 2, only its first 511 bytes.
+    pub cmdline: String,
+    /// The rest of the kernel command line (versions 0 to 2)
+    pub extra_cmdline: String,
+    /// Digest over the sections as computed by mkbootimg (versions 0 to 2).
+    /// It is kept as it is, bootloaders do not check it.
+    pub id: [u8; 32],
+    /// Anything after the last section, e.g. an AVB footer.
+    pub tail: Vec<u8>,
+}
+
+impl BootImage {
+    /// Creates an image from a kernel and a ramdisk, with the defaults of
+    /// mkbootimg.
+    pub fn new(header_version: u32, kernel: Vec<u8>, ramdisk: Vec<u8>) -> Self {
+        let page_size = match header_version {
+            0..=2 => DEFAULT_PAGE_SIZE,
+            _ => V3_PAGE_SIZE,
+        };
+        BootImage {
+            header_version,
+            page_size,
+            kernel,
+            ramdisk,
+            second: Vec::new(),
+            recovery_dtbo: Vec::new(),
+            dtb: Vec::new(),
+            signature: Vec::new(),
+            kernel_addr: DEFAULT_BASE + DEFAULT_KERNEL_OFFSET,
+            ramdisk_addr: DEFAULT_BASE + DEFAULT_RAMDISK_OFFSET,
+            second_addr: DEFAULT_BASE + DEFAULT_SECOND_OFFSET,
+            tags_addr: DEFAULT_BASE + DEFAULT_TAGS_OFFSET,
+            dtb_addr: (DEFAULT_BASE + DEFAULT_DTB_OFFSET) as u64,
+            os_version: 0,
+            name: String::new(),
+            cmdline: String::new(),
+            extra_cmdline: String::new(),
+            id: [0; 32],
+            tail: Vec::new(),
+        }
+    }
+
+    /// The full kernel command line.
+    pub fn full_cmdline(&self) -> String {
+        format!("{}{}", self.cmdline, self.extra_cmdline)
+    }
+
+    /// Sets the kernel command line, split up the way mkbootimg does for
+    /// versions 0 to 2.
+    pub fn set_cmdline(&mut self, cmdline: &str) {
+        let split = match self.header_version {
+            0..=2 if cmdline.len() >= ARGS_SIZE => cmdline.floor_char_boundary(ARGS_SIZE - 1),
+            _ => cmdline.len(),
+        };
+        self.cmdline = cmdline[..split].to_owned();
+        self.extra_cmdline = cmdline[split..].to_owned();
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader::new(data);
+        if r.bytes(8)? != BOOT_MAGIC {
+            return Err("Not a boot image".to_owned());
+        }
+        let version = Reader::new(data.get(40..44).unwrap_or(&[])).u32_le()?;
+        let mut img = BootImage::new(version, Vec::new(), Vec::new());
+
+        let mut sizes = Vec::new();
+        match version {
+            0..=2 => {
+                let kernel_size = r.u32_le()?;
+                img.kernel_addr = r.u32_le()?;
+                let ramdisk_size = r.u32_le()?;
+                img.ramdisk_addr = r.u32_le()?;
+                let second_size = r.u32_le()?;
+                img.second_addr = r.u32_le()?;
+                img.tags_addr = r.u32_le()?;
+                img.page_size = r.u32_le()?;
+                r.u32_le()?;
+                img.os_version = r.u32_le()?;
+                img.name = r.cstr(NAME_SIZE)?;
+                img.cmdline = r.cstr(ARGS_SIZE)?;
+                img.id = r.array()?;
+                img.extra_cmdline = r.cstr(EXTRA_ARGS_SIZE)?;
+                sizes.extend([kernel_size, ramdisk_size, second_size]);
+                if version >= 1 {
+                    sizes.push(r.u32_le()?);
+                    r.u64_le()?; // recovery_dtbo_offset
+                    r.u32_le()?; // header_size
+                }
+                if version >= 2 {
+                    sizes.push(r.u32_le()?);
+                    img.dtb_addr = r.u64_le()?;
+                }
+                if img.page_size == 0 || !img.page_size.is_power_of_two() {
+                    return Err(format!("Invalid page size {}", img.page_size));
+                }
+            }
+            3 | 4 => {
+                let kernel_size = r.u32_le()?;
+                let ramdisk_size = r.u32_le()?;
+                img.os_version = r.u32_le()?;
+                r.u32_le()?; // header_size
+                r.bytes(4 * 4)?; // reserved
+                r.u32_le()?; // header_version
+                img.cmdline = r.cstr(V3_ARGS_SIZE)?;
+                sizes.extend([kernel_size, ramdisk_size]);
+                if version == 4 {
+                    sizes.push(r.u32_le()?);
+                }
+            }
+            v => return Err(format!("Unsupported boot image header version {v}")),
+        }
+
+        // Sections in the order of `sizes`, each starting on a page boundary
+        let page = img.page_size as usize;
+        let mut offset = page;
+        let mut sections = Vec::new();
+        for size in sizes {
+            let size = size as usize;
+            let s = data
+                .get(offset..offset + size)
+                .ok_or_else(|| "Boot image is truncated".to_owned())?;
+            sections.push(s.to_vec());
+            offset += align_up(size, page);
+        }
+        img.tail = data.get(offset..).unwrap_or(&[]).to_vec();
+
+        let mut sections = sections.into_iter();
+        img.kernel = sections.next().unwrap_or_default();
+        img.ramdisk = sections.next().unwrap_or_default();
+        match version {
+            0..=2 => {
+                img.second = sections.next().unwrap_or_default();
+                img.recovery_dtbo = sections.next().unwrap_or_default();
+                img.dtb = sections.next().unwrap_or_default();
+            }
+            _ => img.signature = sections.next().unwrap_or_default(),
+        }
+        Ok(img)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let v = self.header_version;
+        let size = |s: &[u8]| -> Result<u32, String> {
+            u32::try_from(s.len()).map_err(|_| "Section exceeds 4 GiB".to_owned())
+        };
+        let mut out = Vec::new();
+        out.extend_from_slice(BOOT_MAGIC);
+
+        let sections: Vec<&[u8]> = match v {
+            0..=2 => {
+                let page = self.page_size as usize;
+                if page == 0 || !page.is_power_of_two() {
+                    return Err(format!("Invalid page size {page}"));
+                }
+                out.extend(size(&self.kernel)?.to_le_bytes());
+                out.extend(self.kernel_addr.to_le_bytes());
+                out.extend(size(&self.ramdisk)?.to_le_bytes());
+                out.extend(self.ramdisk_addr.to_le_bytes());
+                out.extend(size(&self.second)?.to_le_bytes());
+                out.extend(self.second_addr.to_le_bytes());
+                out.extend(self.tags_addr.to_le_bytes());
+                out.extend(self.page_size.to_le_bytes());
+                out.extend(v.to_le_bytes());
+                out.extend(self.os_version.to_le_bytes());
+                put_cstr(&mut out, &self.name, NAME_SIZE)?;
+                put_cstr(&mut out, &self.cmdline, ARGS_SIZE)?;
+                out.extend_from_slice(&self.id);
+                put_cstr(&mut out, &self.extra_cmdline, EXTRA_ARGS_SIZE)?;
+                let mut sections = vec![&self.kernel[..], &self.ramdisk, &self.second];
+                if v >= 1 {
+                    let dtbo_offset = match self.recovery_dtbo.is_empty() {
+                        true => 0,
+                        false => {
+                            sections
+                                .iter()
+                                .map(|s| align_up(s.len(), page))
+                                .sum::<usize>()
+                                + page
+                        }
+                    };
+                    out.extend(size(&self.recovery_dtbo)?.to_le_bytes());
+                    out.extend((dtbo_offset as u64).to_le_bytes());
+                    let header_size = match v {
+                        1 => V1_HEADER_SIZE,
+                        _ => V2_HEADER_SIZE,
+                    };
+                    out.extend((header_size as u32).to_le_bytes());
+                    sections.push(&self.recovery_dtbo);
+                }
+                if v >= 2 {
+                    out.extend(size(&self.dtb)?.to_le_bytes());
+                    out.extend(self.dtb_addr.to_le_bytes());
+                    sections.push(&self.dtb);
+                }
+                debug_assert_eq!(
+                    out.len(),
+                    [V0_HEADER_SIZE, V1_HEADER_SIZE, V2_HEADER_SIZE][v as usize]
+                );
+                sections
+            }
+            3 | 4 => {
+                let header_size = match v {
+                    3 => V3_HEADER_SIZE,
+                    _ => V4_HEADER_SIZE,
+                };
+                out.extend(size(&self.kernel)?.to_le_bytes());
+                out.extend(size(&self.ramdisk)?.to_le_bytes());
+                out.extend(self.os_version.to_le_bytes());
+                out.extend((header_size as u32).to_le_bytes());
+                out.extend([0; 16]);
+                out.extend(v.to_le_bytes());
+                put_cstr(&mut out, &self.cmdline, V3_ARGS_SIZE)?;
+                let mut sections = vec![&self.kernel[..], &self.ramdisk];
+                if v == 4 {
+                    out.extend(size(&self.signature)?.to_le_bytes());
+                    sections.push(&self.signature);
+                }
+                debug_assert_eq!(out.len(), header_size);
+                sections
+            }
+            v => return Err(format!("Unsupported boot image header version {v}")),
+        };
+
+        let page = match v {
+            0..=2 => self.page_size as usize,
+            _ => V3_PAGE_SIZE as usize,
+        };
+        pad_to(&mut out, page);
+        for s in sections {
+            out.extend_from_slice(s);
+            pad_to(&mut out, page);
+        }
+        out.extend_from_slice(&self.tail);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BootImage;
+
+    // A version 0 image as laid out by mkbootimg, with 2048 byte pages.
+    fn v0_image() -> Vec<u8> {
+        let mut img = vec![0; 4 * 2048];
+        img[..8].copy_from_slice(b"ANDROID!");
+        for (i, v) in [
+            5u32, 0x10008000, 3, 0x11000000, 0, 0x10f00000, 0x10000100, 2048,
+        ]
+        .iter()
+        .enumerate()
+        {
+            img[8 + 4 * i..12 + 4 * i].copy_from_slice(&v.to_le_bytes());
+        }
+        img[48..52].copy_from_slice(b"test");
+        img[64..79].copy_from_slice(b"console=ttyS0,1");
+        img[576] = 0xaa;
+        img[2048..2053].copy_from_slice(b"kern!");
+        img[4096..4099].copy_from_slice(b"rd!");
+        img.extend_from_slice(b"AVBf");
+        img
+    }
+
+    #[test]
+    fn test_parse_v0() {
+        let data = v0_image();
+        let img = BootImage::parse(&data).unwrap();
+        assert_eq!(img.header_version, 0);
+        assert_eq!(img.page_size, 2048);
+        assert_eq!(img.kernel, b"kern!");
+        assert_eq!(img.ramdisk, b"rd!");
+        assert_eq!(img.kernel_addr, 0x10008000);
+        assert_eq!(img.name, "test");
+        assert_eq!(img.cmdline, "console=ttyS0,1");
+        assert_eq!(img.id[0], 0xaa);
+        // An empty page before the footer
+        assert_eq!(img.tail.len(), 2048 + 4);
+        assert_eq!(img.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for v in 0..=4 {
+            let mut img = BootImage::new(v, vec![1; 5000], vec![2; 100]);
+            img.set_cmdline("console=ttyS0,115200");
+            img.second = vec![3; 10];
+            img.recovery_dtbo = vec![4; 20];
+            img.dtb = vec![5; 30];
+            img.signature = vec![6; 40];
+            img.tail = vec![7; 64];
+            let data = img.to_bytes().unwrap();
+            let page = img.page_size as usize;
+            assert_eq!(data.len() % page, 64);
+
+            let parsed = BootImage::parse(&data).unwrap();
+            assert_eq!(parsed.to_bytes().unwrap(), data);
+            assert_eq!(parsed.kernel, img.kernel);
+            assert_eq!(parsed.ramdisk, img.ramdisk);
+            assert_eq!(parsed.full_cmdline(), "console=ttyS0,115200");
+            assert_eq!(parsed.dtb.len(), if v == 2 { 30 } else { 0 });
+            assert_eq!(
+                parsed.recovery_dtbo.len(),
+                if v == 1 || v == 2 { 20 } else { 0 }
+            );
+            assert_eq!(parsed.signature.len(), if v == 4 { 40 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_long_cmdline() {
+        let cmdline = "x".repeat(600);
+        let mut img = BootImage::new(0, vec![], vec![]);
+        img.set_cmdline(&cmdline);
+        assert_eq!(img.cmdline.len(), 511);
+        assert_eq!(img.extra_cmdline.len(), 89);
+        let parsed = BootImage::parse(&img.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.full_cmdline(), cmdline);
+
+        assert!(BootImage::parse(b"ANDROID?").is_err());
+        let mut data = img.to_bytes().unwrap();
+        data[40] = 9;
+        assert!(BootImage::parse(&data).is_err());
+    }
+}
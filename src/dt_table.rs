@@ -0,0 +1,220 @@
+//! Android DT table images, as flashed to `dtbo` (and some `dtb`)
+//! partitions.
+//!
+//! See `system/libufdt/utils/src/dt_table.h` in AOSP. The image is a header,
+//! one entry per device tree (blob) and then the blobs. Entries carry an
+//! id, a revision and four custom words the bootloader uses to pick the
+//! overlays for the board. All fields are big endian. Like `mkdtimg`,
+//! identical blobs are only stored once.
+
+use std::fmt;
+
+use crate::bytes::Reader;
+
+pub const DT_TABLE_MAGIC: u32 = 0xd7b7_ab1e;
+pub const FDT_MAGIC: u32 = 0xd00d_feed;
+
+const HEADER_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtEntry {
+    pub id: u32,
+    pub rev: u32,
+    pub custom: [u32; 4],
+    /// The flattened device tree (overlay)
+    pub dtb: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtTable {
+    /// The page size of the bootloader, 2048 by default
+    pub page_size: u32,
+    pub version: u32,
+    pub entries: Vec<DtEntry>,
+}
+
+impl Default for DtTable {
+    fn default() -> Self {
+        DtTable {
+            page_size: 2048,
+            version: 0,
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl DtEntry {
+    pub fn new(dtb: Vec<u8>, id: u32, rev: u32) -> Self {
+        DtEntry {
+            id,
+            rev,
+            custom: [0; 4],
+            dtb,
+        }
+    }
+}
+
+impl DtTable {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader::new(data);
+        if r.u32_be()? != DT_TABLE_MAGIC {
+            return Err("Not a DT table image".to_owned());
+        }
+        let total_size = r.u32_be()? as usize;
+        let header_size = r.u32_be()? as usize;
+        let entry_size = r.u32_be()? as usize;
+        let count = r.u32_be()? as usize;
+        let offset = r.u32_be()? as usize;
+        let page_size = r.u32_be()?;
+        let version = r.u32_be()?;
+        let entries_end = count
+            .checked_mul(entry_size)
+            .and_then(|len| len.checked_add(offset));
+        if header_size < HEADER_SIZE
+            || entry_size < ENTRY_SIZE
+            || total_size > data.len()
+            || entries_end.is_none_or(|end| end > total_size)
+        {
+            return Err("Invalid DT table header".to_owned());
+        }
+        let data = &data[..total_size];
+
+        let mut entries = Vec::with_capacity(count);
+        for i in 0..count {
+            let start = offset + i * entry_size;
+            let mut r = Reader::new(&data[start..start + ENTRY_SIZE]);
+            let size = r.u32_be()? as usize;
+            let start = r.u32_be()? as usize;
+            let id = r.u32_be()?;
+            let rev = r.u32_be()?;
+            let custom = [r.u32_be()?, r.u32_be()?, r.u32_be()?, r.u32_be()?];
+            let dtb = data
+                .get(start..start.saturating_add(size))
+                .ok_or_else(|| format!("DT table entry {i} is out of bounds"))?;
+            entries.push(DtEntry {
+                id,
+                rev,
+                custom,
+                dtb: dtb.to_vec(),
+            });
+        }
+        Ok(DtTable {
+            page_size,
+            version,
+            entries,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut blobs: Vec<u8> = Vec::new();
+        let mut placed: Vec<(&[u8], usize)> = Vec::new();
+        let mut table = Vec::with_capacity(self.entries.len() * ENTRY_SIZE);
+        let dt_offset = HEADER_SIZE + self.entries.len() * ENTRY_SIZE;
+
+        for (i, e) in self.entries.iter().enumerate() {
+            if !e.dtb.starts_with(&FDT_MAGIC.to_be_bytes()) {
+                return Err(format!("DT table entry {i} is not a device tree blob"));
+            }
+            let offset = match placed.iter().find(|(dtb, _)| *dtb == &e.dtb[..]) {
+                Some(&(_, offset)) => offset,
+                None => {
+                    let offset = dt_offset + blobs.len();
+                    blobs.extend_from_slice(&e.dtb);
+                    placed.push((&e.dtb, offset));
+                    offset
+                }
+            };
+            table.extend((e.dtb.len() as u32).to_be_bytes());
+            table.extend((offset as u32).to_be_bytes());
+            table.extend(e.id.to_be_bytes());
+            table.extend(e.rev.to_be_bytes());
+            table.extend(e.custom.iter().flat_map(|c| c.to_be_bytes()));
+        }
+
+        let total_size = u32::try_from(dt_offset + blobs.len())
+            .map_err(|_| "DT table image too large".to_owned())?;
+        let mut out = Vec::with_capacity(total_size as usize);
+        for field in [
+            DT_TABLE_MAGIC,
+            total_size,
+            HEADER_SIZE as u32,
+            ENTRY_SIZE as u32,
+            self.entries.len() as u32,
+            HEADER_SIZE as u32,
+            self.page_size,
+            self.version,
+        ] {
+            out.extend(field.to_be_bytes());
+        }
+        out.extend(table);
+        out.extend(blobs);
+        Ok(out)
+    }
+}
+
+/// Lists the entries like `mkdtimg dump`.
+impl fmt::Display for DtTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "dt_table version {}, page size {}",
+            self.version, self.page_size
+        )?;
+        for (i, e) in self.entries.iter().enumerate() {
+            writeln!(
+                f,
+                "dt_table_entry[{i}]: size {}, id 0x{:08x}, rev 0x{:08x}, custom {:08x} {:08x} {:08x} {:08x}",
+                e.dtb.len(),
+                e.id,
+                e.rev,
+                e.custom[0],
+                e.custom[1],
+                e.custom[2],
+                e.custom[3]
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DtEntry, DtTable, FDT_MAGIC};
+
+    fn dtb(tag: u8, len: usize) -> Vec<u8> {
+        let mut dtb = FDT_MAGIC.to_be_bytes().to_vec();
+        dtb.resize(len, tag);
+        dtb
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut table = DtTable::default();
+        table.entries.push(DtEntry::new(dtb(1, 100), 0x100, 1));
+        table.entries.push(DtEntry::new(dtb(2, 60), 0x200, 1));
+        let mut sku = DtEntry::new(dtb(1, 100), 0x100, 2);
+        sku.custom = [1, 2, 3, 4];
+        table.entries.push(sku);
+
+        let data = table.to_bytes().unwrap();
+        // The first blob is shared by two entries.
+        assert_eq!(data.len(), 32 + 3 * 32 + 160);
+        assert_eq!(data[..4], [0xd7, 0xb7, 0xab, 0x1e]);
+        let parsed = DtTable::parse(&data).unwrap();
+        assert_eq!(parsed, table);
+        assert_eq!(parsed.entries[2].dtb, dtb(1, 100));
+        assert!(parsed.to_string().contains(
+            "dt_table_entry[2]: size 100, id 0x00000100, rev 0x00000002, custom 00000001"
+        ));
+
+        assert!(DtTable::parse(&data[..data.len() - 1]).is_err());
+        assert!(DtTable::parse(&data[4..]).is_err());
+        // An entry count far beyond the data is rejected up front.
+        let mut huge = data.clone();
+        huge[16..20].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(DtTable::parse(&huge).is_err());
+        table.entries.push(DtEntry::new(vec![0; 8], 0, 0));
+        assert!(table.to_bytes().is_err());
+    }
+}
@@ -1,7 +1,16 @@
 //! Traits, helpers, and type definitions for Fastboot host functionality.
 
 use std;
+use std::fmt;
 use std::io::{Read, Write};
+use std::str::FromStr;
+
+use crate::bootimg::BootImage;
+use crate::gpt::Gpt;
+use crate::lpmetadata::LpMetadata;
+use crate::sparse;
+use crate::trace;
+use crate::vbmeta::{self, VbMeta};
 
 /// Result wrapper that yields either a succesful result of a Fastboot operation
 /// or an error [`String`].
@@ -9,11 +18,223 @@ pub type FbResult<T> = Result<T, String>;
 
 const GETVAR_CMD: &[u8] = b"getvar:";
 const DOWNLOAD_CMD: &[u8] = b"download:";
+const STAGE_CMD: &[u8] = b"stage:";
 const FLASH_CMD: &[u8] = b"flash:";
 const ERASE_CMD: &[u8] = b"erase:";
+const BOOT_CMD: &[u8] = b"boot";
 const CONTINUE_CMD: &[u8] = b"continue";
 const REBOOT_CMD: &[u8] = b"reboot";
-const REBOOT_BOOTLOADER_CMD: &[u8] = b"reboot-bootloader";
+const POWERDOWN_CMD: &[u8] = b"powerdown";
+const SET_ACTIVE_CMD: &[u8] = b"set_active:";
+const FLASHING_CMD: &[u8] = b"flashing ";
+const CREATE_LOGICAL_PARTITION_CMD: &[u8] = b"create-logical-partition:";
+const DELETE_LOGICAL_PARTITION_CMD: &[u8] = b"delete-logical-partition:";
+const RESIZE_LOGICAL_PARTITION_CMD: &[u8] = b"resize-logical-partition:";
+const UPDATE_SUPER_CMD: &[u8] = b"update-super:";
+const SNAPSHOT_UPDATE_CMD: &[u8] = b"snapshot-update:";
+const FETCH_CMD: &[u8] = b"fetch:";
+const GSI_CMD: &[u8] = b"gsi:";
+const OEM_CMD: &[u8] = b"oem ";
+
+/// An A/B slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Slot {
+    A,
+    B,
+}
+
+impl Slot {
+    /// The suffix of the slot's partitions, e.g. `_a` for `boot_a`.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Slot::A => "_a",
+            Slot::B => "_b",
+        }
+    }
+
+    pub fn other(&self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.suffix()[1..])
+    }
+}
+
+// Devices report slots with or without the leading underscore.
+impl FromStr for Slot {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        match s.trim().trim_start_matches('_') {
+            "a" => Ok(Slot::A),
+            "b" => Ok(Slot::B),
+            _ => Err(format!("Unknown slot: {s}")),
+        }
+    }
+}
+
+/// Where to go when rebooting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RebootTarget {
+    /// The regular system.
+    System,
+    Bootloader,
+    Recovery,
+    /// Fastboot in userspace, i.e. fastbootd.
+    Fastboot,
+    /// Turn the device off instead of rebooting.
+    PowerDown,
+    /// Any other target the device knows, as in `reboot:<target>`.
+    Custom(String),
+}
+
+/// Parses the names of `fastboot reboot`, e.g. `bootloader`.
+impl FromStr for RebootTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        match s {
+            "" | "system" => Ok(RebootTarget::System),
+            "bootloader" => Ok(RebootTarget::Bootloader),
+            "recovery" => Ok(RebootTarget::Recovery),
+            "fastboot" => Ok(RebootTarget::Fastboot),
+            "powerdown" => Ok(RebootTarget::PowerDown),
+            target if target.contains(char::is_whitespace) => {
+                Err(format!("Invalid reboot target: {target}"))
+            }
+            target => Ok(RebootTarget::Custom(target.to_owned())),
+        }
+    }
+}
+
+/// Prints the name [`from_str`](RebootTarget::from_str) parses.
+impl fmt::Display for RebootTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RebootTarget::System => "system",
+            RebootTarget::Bootloader => "bootloader",
+            RebootTarget::Recovery => "recovery",
+            RebootTarget::Fastboot => "fastboot",
+            RebootTarget::PowerDown => "powerdown",
+            RebootTarget::Custom(target) => target,
+        })
+    }
+}
+
+impl RebootTarget {
+    fn command(&self) -> Vec<u8> {
+        let suffix = match self {
+            RebootTarget::System => "",
+            RebootTarget::Bootloader => "-bootloader",
+            RebootTarget::Recovery => "-recovery",
+            RebootTarget::Fastboot => "-fastboot",
+            RebootTarget::PowerDown => return POWERDOWN_CMD.to_vec(),
+            RebootTarget::Custom(target) => {
+                return [REBOOT_CMD, b":", target.as_bytes()].concat();
+            }
+        };
+        [REBOOT_CMD, suffix.as_bytes()].concat()
+    }
+}
+
+/// The state of a Virtual A/B update, see `getvar:snapshot-update-status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotUpdateStatus {
+    /// No update is in progress.
+    None,
+    /// An update was applied, but the snapshots have not been merged yet.
+    Snapshotted,
+    /// The snapshots are being merged.
+    Merging,
+}
+
+impl FromStr for SnapshotUpdateStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        match s.trim() {
+            "none" => Ok(SnapshotUpdateStatus::None),
+            "snapshotted" => Ok(SnapshotUpdateStatus::Snapshotted),
+            "merging" => Ok(SnapshotUpdateStatus::Merging),
+            s => Err(format!("Unknown snapshot update status: {s}")),
+        }
+    }
+}
+
+/// What to do with a pending Virtual A/B update.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotUpdateAction {
+    /// Drop the update, so that partitions can be flashed again.
+    Cancel,
+    /// Finish merging the update.
+    Merge,
+}
+
+/// Whether a GSI, booted through Dynamic System Updates (DSU), is running.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GsiStatus {
+    NotRunning,
+    /// Running, with the name of the active DSU slot if the device tells it.
+    Running(Option<String>),
+}
+
+impl FromStr for GsiStatus {
+    type Err = String;
+
+    // fastbootd answers with e.g. `Not running` or `Running active DSU: dsu`.
+    fn from_str(s: &str) -> FbResult<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("not running") {
+            return Ok(GsiStatus::NotRunning);
+        }
+        match s.strip_prefix("Running") {
+            Some(rest) => {
+                let dsu = rest.split_once(':').map(|(_, d)| d.trim().to_owned());
+                Ok(GsiStatus::Running(dsu.filter(|d| !d.is_empty())))
+            }
+            None => Err(format!("Unknown GSI status: {s}")),
+        }
+    }
+}
+
+/// Whether the bootloader allows flashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockState {
+    Locked,
+    Unlocked,
+}
+
+/// The slots a command applies to, like the `--slot` option of the fastboot
+/// CLI.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotSelect {
+    /// The currently active slot.
+    Current,
+    /// The slot that is not active.
+    Other,
+    /// Both slots.
+    All,
+    Slot(Slot),
+}
+
+impl FromStr for SlotSelect {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        match s {
+            "current" => Ok(SlotSelect::Current),
+            "other" => Ok(SlotSelect::Other),
+            "all" => Ok(SlotSelect::All),
+            s => s.parse().map(SlotSelect::Slot),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 enum Reply {
@@ -42,7 +263,7 @@ impl<'s> From<&'s mut [u8]> for Reply {
                 }
             }
             _ => {
-                eprintln!("Received: {kind:08x?}: {s}");
+                trace::unknown_reply(kind, &s);
                 Reply::Fail(s.into_owned())
             }
         }
@@ -59,11 +280,26 @@ const FB_MAX_REPLY_LEN: usize = 512;
 // received from the USB I/O implementation.
 // See u-boot/doc/README.android-fastboot-protocol
 fn fb_send<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
+    trace::command(payload);
     io.write_all(payload).map_err(|err| err.to_string())?;
+    io.flush().map_err(|err| err.to_string())?;
+    fb_read(io)
+}
+
+fn fb_read<T: Fastboot>(io: &mut T) -> FbResult<Reply> {
     loop {
         let mut buff = [0; FB_MAX_REPLY_LEN];
         match io.read(&mut buff) {
-            Ok(received) => return Ok(Reply::from(&mut buff[..received])),
+            Ok(received) => {
+                let reply = Reply::from(&mut buff[..received]);
+                match &reply {
+                    Reply::Okay(message) => trace::reply("OKAY", message),
+                    Reply::Fail(message) => trace::reply("FAIL", message),
+                    Reply::Info(message) => trace::reply("INFO", message),
+                    Reply::Data(size) => trace::reply("DATA", &size.to_string()),
+                }
+                return Ok(reply);
+            }
             Err(err) => {
                 match err.kind() {
                     std::io::ErrorKind::TimedOut => {
@@ -82,6 +318,180 @@ fn fb_send<T: Fastboot>(io: &mut T, payload: &[u8]) -> FbResult<Reply> {
     }
 }
 
+// How much of a DATA phase is read from the device at once.
+const FB_DATA_CHUNK_LEN: usize = 1024 * 1024;
+
+// Reads the `size` bytes of a DATA phase from the device into `out`.
+fn fb_read_data<T: Fastboot, W: Write>(io: &mut T, size: usize, out: &mut W) -> FbResult<()> {
+    let mut buff = vec![0; size.min(FB_DATA_CHUNK_LEN)];
+    let mut left = size;
+    while left > 0 {
+        let len = left.min(buff.len());
+        match io.read(&mut buff[..len]) {
+            Ok(0) => return Err("DATA: Device sent no data".to_owned()),
+            Ok(received) => {
+                out.write_all(&buff[..received])
+                    .map_err(|err| err.to_string())?;
+                left -= received;
+            }
+            // See `fb_read`
+            Err(err) if err.kind() == std::io::ErrorKind::TimedOut => continue,
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+    trace::data_in(size);
+    Ok(())
+}
+
+// Sends a command that announces `size` bytes of data, e.g. `download`, then
+// streams the data from `data` once the device accepts the size. `progress` is
+// called with the number of bytes sent so far after every chunk.
+fn fb_send_data<T, R, P>(
+    io: &mut T,
+    cmd: &[u8],
+    mut data: R,
+    size: usize,
+    mut progress: P,
+) -> FbResult<()>
+where
+    T: Fastboot,
+    R: Read,
+    P: FnMut(usize),
+{
+    // The size is sent as 8 hex digits.
+    if size > u32::MAX as usize {
+        return Err(format!("DATA: {size} bytes exceed the protocol limit"));
+    }
+    let mut payload = Vec::with_capacity(cmd.len() + 8);
+    payload.extend_from_slice(cmd);
+    payload.extend_from_slice(format!("{size:08x}").as_bytes());
+
+    match fb_send(io, &payload)? {
+        Reply::Data(s) if s == size => {}
+        Reply::Data(s) => return Err(format!("DATA: Expected {size} bytes, got {s}")),
+        Reply::Fail(message) => return Err(message),
+        _ => return Err("Unknown failure".to_owned()),
+    }
+
+    let mut buff = vec![0; size.min(FB_DATA_CHUNK_LEN)];
+    let mut sent = 0;
+    while sent < size {
+        let len = (size - sent).min(buff.len());
+        data.read_exact(&mut buff[..len])
+            .map_err(|err| format!("DATA: {err}"))?;
+        if sent == 0 {
+            trace::data_out(&buff[..len], size);
+        }
+        io.write_all(&buff[..len]).map_err(|err| err.to_string())?;
+        sent += len;
+        progress(sent);
+    }
+    io.flush().map_err(|err| err.to_string())?;
+
+    let mut reply = fb_read(io)?;
+    while let Reply::Info(message) = reply {
+        println!("{message}");
+        reply = fb_read(io)?;
+    }
+    match reply {
+        Reply::Okay(_) => Ok(()),
+        Reply::Fail(message) => Err(message),
+        _ => Err("Unknown failure".to_owned()),
+    }
+}
+
+// Like `fb_send`, but passes INFO replies on to the user and keeps waiting for
+// the final reply. Some commands, e.g. unlocking, need a confirmation on the
+// device, which may take a while. The INFO messages are collected in `info`.
+fn fb_send_info<T: Fastboot>(
+    io: &mut T,
+    payload: &[u8],
+    info: &mut Vec<String>,
+) -> FbResult<Reply> {
+    let mut reply = fb_send(io, payload)?;
+    while let Reply::Info(message) = reply {
+        println!("{message}");
+        info.push(message);
+        reply = fb_read(io)?;
+    }
+    Ok(reply)
+}
+
+// Sends a command with an argument and expects an OKAY reply.
+fn fb_command<T: Fastboot>(io: &mut T, cmd: &[u8], arg: &str) -> FbResult<String> {
+    let mut payload = Vec::with_capacity(cmd.len() + arg.len());
+    payload.extend_from_slice(cmd);
+    payload.extend_from_slice(arg.as_bytes());
+    match fb_send_info(io, &payload, &mut Vec::new())? {
+        Reply::Okay(message) => Ok(message),
+        Reply::Fail(message) => Err(message),
+        _ => Err("Unknown failure".to_owned()),
+    }
+}
+
+// Flashes a raw or sparse image as sparse pieces of at most `max` bytes.
+fn fb_flash_split<T: Fastboot, R: Read>(
+    io: &mut T,
+    partition: &str,
+    data: R,
+    size: u64,
+    max: u64,
+) -> FbResult<()> {
+    let max = usize::try_from(max).unwrap_or(usize::MAX);
+    for piece in sparse::split_from(data, size, max)? {
+        let piece = piece?;
+        io.download_from(piece.reader(), piece.size(), |_| {})?;
+        io.flash(partition)?;
+    }
+    Ok(())
+}
+
+// Calls back with the number of bytes read by each read.
+struct ReadProgress<R, P>(R, P);
+
+impl<R: Read, P: FnMut(usize)> Read for ReadProgress<R, P> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.0.read(buf)?;
+        (self.1)(n);
+        Ok(n)
+    }
+}
+
+// Gets a variable the device may not know. Only a FAIL reply means that it
+// does not; transport errors are passed on.
+pub(crate) fn fb_getvar_opt<T: Fastboot>(io: &mut T, var: &str) -> FbResult<Option<String>> {
+    match fb_send(io, &[GETVAR_CMD, var.as_bytes()].concat())? {
+        Reply::Okay(value) => Ok(Some(value)),
+        Reply::Fail(_) => Ok(None),
+        _ => Err("Unknown failure".to_owned()),
+    }
+}
+
+// Boolean variables are reported as `yes` or `no`.
+fn parse_yes_no(var: &str, value: &str) -> FbResult<bool> {
+    match value.trim() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        v => Err(format!("{var}: expected yes or no, got {v}")),
+    }
+}
+
+fn parse_number<N: FromStr>(var: &str, value: &str) -> FbResult<N> {
+    let v = value.trim();
+    v.parse()
+        .map_err(|_| format!("{var}: expected a number, got {v}"))
+}
+
+// Sizes are mostly reported in hex with a `0x` prefix, sometimes in decimal.
+fn parse_size(var: &str, value: &str) -> FbResult<u64> {
+    let v = value.trim();
+    match v.strip_prefix("0x").or_else(|| v.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => v.parse(),
+    }
+    .map_err(|_| format!("{var}: expected a size, got {v}"))
+}
+
 /// The `Fastboot` trait provides Fastboot-protocol host-side interface.
 ///
 /// There are no required methods. The only requirement is that an object,
@@ -104,32 +514,35 @@ pub trait Fastboot: Read + Write + Sized {
 
     /// Downloads provided data into a client.
     fn download(&mut self, data: &[u8]) -> FbResult<()> {
-        // Wrapped in block to drop len as soon as possible
-        let cmd = {
-            let mut cmd = Vec::with_capacity(DOWNLOAD_CMD.len() + 8);
-            let mut len = format!("{:08x}", data.len()).into_bytes();
-            cmd.extend_from_slice(DOWNLOAD_CMD);
-            cmd.append(&mut len);
-            cmd
-        };
-        let reply = fb_send(self, &cmd)?;
+        self.download_from(data, data.len(), |_| {})
+    }
 
-        match reply {
-            Reply::Data(size) if size == data.len() => {
-                let reply = fb_send(self, data)?;
-                match reply {
-                    Reply::Okay(_) => Ok(()),
-                    Reply::Fail(message) => Err(message),
-                    Reply::Info(message) => {
-                        println!("{message}");
-                        Err(message)
-                    }
-                    _ => Err("Unknown failure".to_owned()),
-                }
-            }
-            Reply::Fail(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
-        }
+    /// Downloads `size` bytes read from `data` into a client, without holding
+    /// them in memory all at once. `progress` is called with the number of
+    /// bytes sent so far.
+    fn download_from<R: Read, P: FnMut(usize)>(
+        &mut self,
+        data: R,
+        size: usize,
+        progress: P,
+    ) -> FbResult<()> {
+        fb_send_data(self, DOWNLOAD_CMD, data, size, progress)
+    }
+
+    /// Stages provided data on a client without flashing it, e.g. keys that
+    /// a following OEM command consumes.
+    fn stage(&mut self, data: &[u8]) -> FbResult<()> {
+        self.stage_from(data, data.len(), |_| {})
+    }
+
+    /// Like [`Fastboot::download_from`], but stages the data.
+    fn stage_from<R: Read, P: FnMut(usize)>(
+        &mut self,
+        data: R,
+        size: usize,
+        progress: P,
+    ) -> FbResult<()> {
+        fb_send_data(self, STAGE_CMD, data, size, progress)
     }
 
     /// Flashes downloaded data into a specified partition.
@@ -162,6 +575,23 @@ pub trait Fastboot: Read + Write + Sized {
         }
     }
 
+    /// Boots downloaded data, e.g. a boot image, without flashing it.
+    fn boot(&mut self) -> FbResult<()> {
+        let reply = fb_send(self, BOOT_CMD)?;
+        match reply {
+            Reply::Okay(_) => Ok(()),
+            Reply::Fail(message) => Err(message),
+            _ => Err("Unknown failure".to_owned()),
+        }
+    }
+
+    /// Builds a boot image, e.g. from a kernel and a ramdisk with
+    /// [`BootImage::new`], downloads it and boots it.
+    fn boot_image(&mut self, image: &BootImage) -> FbResult<()> {
+        self.download(&image.to_bytes()?)?;
+        self.boot()
+    }
+
     /// Continue booting as normal (if possible).
     /// NOTE: We cannot call this `continue` because of Rust syntax.
     fn continue_boot(&mut self) -> FbResult<()> {
@@ -175,23 +605,480 @@ pub trait Fastboot: Read + Write + Sized {
 
     /// Reboots a client.
     fn reboot(&mut self) -> FbResult<()> {
-        let reply = fb_send(self, REBOOT_CMD)?;
-        match reply {
-            Reply::Okay(_) => Ok(()),
-            Reply::Fail(message) => Err(message),
-            _ => Err("Unknown failure".to_owned()),
-        }
+        self.reboot_to(RebootTarget::System)
     }
 
     /// Reboots a client.
     fn reboot_bootloader(&mut self) -> FbResult<()> {
-        let reply = fb_send(self, REBOOT_BOOTLOADER_CMD)?;
+        self.reboot_to(RebootTarget::Bootloader)
+    }
+
+    /// Reboots a client into the given target, or powers it down.
+    fn reboot_to(&mut self, target: RebootTarget) -> FbResult<()> {
+        let reply = fb_send(self, &target.command())?;
         match reply {
             Reply::Okay(_) => Ok(()),
             Reply::Fail(message) => Err(message),
             _ => Err("Unknown failure".to_owned()),
         }
     }
+
+    /// Marks a slot as active, i.e. to be booted next.
+    fn set_active(&mut self, slot: Slot) -> FbResult<()> {
+        fb_command(self, SET_ACTIVE_CMD, &slot.to_string()).map(|_| ())
+    }
+
+    /// Gets the currently active slot.
+    fn current_slot(&mut self) -> FbResult<Slot> {
+        self.getvar("current-slot")?.parse()
+    }
+
+    /// Gets the number of slots, which is 0 on devices without A/B support.
+    fn slot_count(&mut self) -> FbResult<usize> {
+        let var = "slot-count";
+        parse_number(var, &self.getvar(var)?)
+    }
+
+    /// Tells whether a slot has booted successfully.
+    fn slot_successful(&mut self, slot: Slot) -> FbResult<bool> {
+        let var = format!("slot-successful:{slot}");
+        parse_yes_no(&var, &self.getvar(&var)?)
+    }
+
+    /// Tells whether a slot is marked unbootable.
+    fn slot_unbootable(&mut self, slot: Slot) -> FbResult<bool> {
+        let var = format!("slot-unbootable:{slot}");
+        parse_yes_no(&var, &self.getvar(&var)?)
+    }
+
+    /// Gets the number of boot attempts left for a slot.
+    fn slot_retry_count(&mut self, slot: Slot) -> FbResult<u32> {
+        let var = format!("slot-retry-count:{slot}");
+        parse_number(&var, &self.getvar(&var)?)
+    }
+
+    /// Tells whether a partition has A/B slots.
+    fn has_slot(&mut self, partition: &str) -> FbResult<bool> {
+        let var = format!("has-slot:{partition}");
+        parse_yes_no(&var, &self.getvar(&var)?)
+    }
+
+    /// Resolves a partition name to the partitions of the selected slots.
+    /// Partitions without slots are returned as they are.
+    fn slot_partitions(&mut self, partition: &str, slots: SlotSelect) -> FbResult<Vec<String>> {
+        // Bootloaders without A/B support may not know the variable.
+        let var = format!("has-slot:{partition}");
+        let has_slot = match fb_getvar_opt(self, &var)? {
+            Some(value) => parse_yes_no(&var, &value)?,
+            None => false,
+        };
+        if !has_slot {
+            return Ok(vec![partition.to_owned()]);
+        }
+        let slots = match slots {
+            SlotSelect::Current => vec![self.current_slot()?],
+            SlotSelect::Other => vec![self.current_slot()?.other()],
+            SlotSelect::All => vec![Slot::A, Slot::B],
+            SlotSelect::Slot(slot) => vec![slot],
+        };
+        Ok(slots
+            .iter()
+            .map(|s| format!("{partition}{}", s.suffix()))
+            .collect())
+    }
+
+    /// Flashes downloaded data into a partition of the selected slots. Fails if
+    /// a Virtual A/B update is pending; call
+    /// [`check_snapshot_update`](Fastboot::check_snapshot_update) with
+    /// `cancel` first to cancel it instead.
+    fn flash_slot(&mut self, partition: &str, slots: SlotSelect) -> FbResult<()> {
+        self.check_snapshot_update(false)?;
+        for p in self.slot_partitions(partition, slots)? {
+            self.flash(&p)?;
+        }
+        Ok(())
+    }
+
+    /// Tells whether fastboot runs in userspace (fastbootd), which is where
+    /// logical partitions are handled. Bootloaders may not know the variable.
+    fn is_userspace(&mut self) -> FbResult<bool> {
+        let var = "is-userspace";
+        match fb_getvar_opt(self, var)? {
+            Some(value) => parse_yes_no(var, &value),
+            None => Ok(false),
+        }
+    }
+
+    /// Gets the name of the partition holding the logical partitions, which
+    /// is `super` unless the device tells otherwise.
+    fn super_partition_name(&mut self) -> FbResult<String> {
+        let name = fb_getvar_opt(self, "super-partition-name")?;
+        Ok(name.unwrap_or_else(|| "super".to_owned()))
+    }
+
+    /// Tells whether a partition is a logical partition inside `super`.
+    fn is_logical(&mut self, partition: &str) -> FbResult<bool> {
+        let var = format!("is-logical:{partition}");
+        parse_yes_no(&var, &self.getvar(&var)?)
+    }
+
+    /// Creates a logical partition of `size` bytes.
+    fn create_logical_partition(&mut self, partition: &str, size: u64) -> FbResult<()> {
+        let arg = format!("{partition}:{size}");
+        fb_command(self, CREATE_LOGICAL_PARTITION_CMD, &arg).map(|_| ())
+    }
+
+    /// Deletes a logical partition.
+    fn delete_logical_partition(&mut self, partition: &str) -> FbResult<()> {
+        fb_command(self, DELETE_LOGICAL_PARTITION_CMD, partition).map(|_| ())
+    }
+
+    /// Resizes a logical partition to `size` bytes.
+    fn resize_logical_partition(&mut self, partition: &str, size: u64) -> FbResult<()> {
+        let arg = format!("{partition}:{size}");
+        fb_command(self, RESIZE_LOGICAL_PARTITION_CMD, &arg).map(|_| ())
+    }
+
+    /// Updates the metadata of a super partition from downloaded data, e.g.
+    /// `super_empty.img`. With `wipe`, all logical partitions are dropped
+    /// instead of being carried over.
+    fn update_super(&mut self, partition: &str, wipe: bool) -> FbResult<()> {
+        let arg = match wipe {
+            true => format!("{partition}:wipe"),
+            false => partition.to_owned(),
+        };
+        fb_command(self, UPDATE_SUPER_CMD, &arg).map(|_| ())
+    }
+
+    /// Like [`update_super`](Fastboot::update_super), but downloads
+    /// `super_empty` first, after checking that images of the given sizes
+    /// will fit into their logical partitions.
+    fn update_super_image(
+        &mut self,
+        partition: &str,
+        super_empty: &[u8],
+        images: &[(&str, u64)],
+        wipe: bool,
+    ) -> FbResult<()> {
+        LpMetadata::parse_image(super_empty)?.check_fit(images)?;
+        self.download(super_empty)?;
+        self.update_super(partition, wipe)
+    }
+
+    /// Gets the state of a Virtual A/B update.
+    fn snapshot_update_status(&mut self) -> FbResult<SnapshotUpdateStatus> {
+        self.getvar("snapshot-update-status")?.parse()
+    }
+
+    /// Cancels or merges a pending Virtual A/B update.
+    fn snapshot_update(&mut self, action: SnapshotUpdateAction) -> FbResult<()> {
+        let arg = match action {
+            SnapshotUpdateAction::Cancel => "cancel",
+            SnapshotUpdateAction::Merge => "merge",
+        };
+        fb_command(self, SNAPSHOT_UPDATE_CMD, arg).map(|_| ())
+    }
+
+    /// Makes sure no Virtual A/B update is pending, which would keep the
+    /// device from flashing some partitions. With `cancel`, a pending update
+    /// is cancelled, otherwise this fails.
+    fn check_snapshot_update(&mut self, cancel: bool) -> FbResult<()> {
+        // Devices without Virtual A/B do not know the variable.
+        let status: SnapshotUpdateStatus = match fb_getvar_opt(self, "snapshot-update-status")? {
+            Some(value) => value.parse()?,
+            None => return Ok(()),
+        };
+        match (status, cancel) {
+            (SnapshotUpdateStatus::None, _) => Ok(()),
+            (_, true) => self.snapshot_update(SnapshotUpdateAction::Cancel),
+            (status, false) => Err(format!(
+                "Snapshot update pending ({status:?}), cancel it before flashing"
+            )),
+        }
+    }
+
+    /// Downloads an image and flashes it into a partition. Logical partitions
+    /// are resized to fit the image first, as the fastboot CLI does. Fails if
+    /// a Virtual A/B update is pending, like
+    /// [`flash_slot`](Fastboot::flash_slot).
+    fn flash_image(&mut self, partition: &str, data: &[u8]) -> FbResult<()> {
+        self.flash_image_from(partition, data, data.len(), |_| {})
+    }
+
+    /// Like [`flash_image`](Fastboot::flash_image), but streams `size` bytes
+    /// read from `data`, see [`download_from`](Fastboot::download_from).
+    /// Sparse images resize logical partitions to their expanded size. Images
+    /// larger than `max-download-size` are flashed in sparse pieces, like
+    /// [`flash_sparse_from`](Fastboot::flash_sparse_from) does.
+    fn flash_image_from<R: Read, P: FnMut(usize)>(
+        &mut self,
+        partition: &str,
+        mut data: R,
+        size: usize,
+        mut progress: P,
+    ) -> FbResult<()> {
+        self.check_snapshot_update(false)?;
+        let mut header = vec![0; size.min(sparse::HEADER_SIZE)];
+        data.read_exact(&mut header)
+            .map_err(|err| format!("DATA: {err}"))?;
+        let expanded = sparse::expanded_size(&header);
+        let data = std::io::Cursor::new(header).chain(data);
+
+        // Bootloaders without logical partitions may not know the variable.
+        let var = format!("is-logical:{partition}");
+        let logical = match fb_getvar_opt(self, &var)? {
+            Some(value) => parse_yes_no(&var, &value)?,
+            None => false,
+        };
+        if logical {
+            self.resize_logical_partition(partition, expanded.unwrap_or(size as u64))?;
+        }
+        let var = "max-download-size";
+        let max = match fb_getvar_opt(self, var)? {
+            Some(max) => parse_size(var, &max)?,
+            None => u64::MAX,
+        };
+        if size as u64 <= max {
+            self.download_from(data, size, progress)?;
+            return self.flash(partition);
+        }
+        let mut read = 0;
+        let data = ReadProgress(data, |n| {
+            read += n;
+            progress(read)
+        });
+        fb_flash_split(self, partition, data, size as u64, max)
+    }
+
+    /// Like [`flash_image`](Fastboot::flash_image), but first checks the
+    /// image against its hash descriptor in `vbmeta`. Partitions verified
+    /// with a hashtree descriptor instead, e.g. `system`, are rejected, as
+    /// checking them needs the whole tree.
+    fn flash_verified(&mut self, partition: &str, data: &[u8], vbmeta: &VbMeta) -> FbResult<()> {
+        vbmeta.verify_image(partition, data)?;
+        self.flash_image(partition, data)
+    }
+
+    /// Flashes a vbmeta image with `flags` added to its header flags, like
+    /// `fastboot --disable-verity --disable-verification` does for
+    /// [`vbmeta::FLAG_HASHTREE_DISABLED`] and
+    /// [`vbmeta::FLAG_VERIFICATION_DISABLED`].
+    fn flash_vbmeta(&mut self, partition: &str, data: &[u8], flags: u32) -> FbResult<()> {
+        let mut data = data.to_vec();
+        vbmeta::add_flags(&mut data, flags)?;
+        self.flash_image(partition, &data)
+    }
+
+    /// Gets the size of a partition in bytes.
+    fn partition_size(&mut self, partition: &str) -> FbResult<u64> {
+        let var = format!("partition-size:{partition}");
+        parse_size(&var, &self.getvar(&var)?)
+    }
+
+    /// Checks that every partition in `gpt` exists on the device with the
+    /// same size, according to `partition-size:`.
+    fn check_gpt(&mut self, gpt: &Gpt) -> FbResult<()> {
+        let mut mismatches = Vec::new();
+        for p in &gpt.partitions {
+            let expected = p
+                .sectors()
+                .checked_mul(gpt.block_size)
+                .ok_or_else(|| format!("{}: invalid size", p.name))?;
+            let var = format!("partition-size:{}", p.name);
+            match fb_getvar_opt(self, &var)? {
+                Some(size) => {
+                    let size = parse_size(&var, &size)?;
+                    if size != expected {
+                        mismatches.push(format!("{} is {size} bytes, not {expected}", p.name));
+                    }
+                }
+                None => mismatches.push(format!("{} is missing", p.name)),
+            }
+        }
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(format!("Layout differs: {}", mismatches.join(", "))),
+        }
+    }
+
+    /// Writes a partition table with `flash:gpt`.
+    fn flash_gpt(&mut self, gpt: &Gpt) -> FbResult<()> {
+        self.download(&gpt.to_bytes())?;
+        self.flash("gpt")
+    }
+
+    /// Gets the largest download the device accepts at once.
+    fn max_download_size(&mut self) -> FbResult<u64> {
+        let var = "max-download-size";
+        parse_size(var, &self.getvar(var)?)
+    }
+
+    /// Flashes an image as one or more sparse images that each fit into
+    /// `max-download-size`, like the fastboot CLI does for large images. Raw
+    /// images are converted first.
+    fn flash_sparse(&mut self, partition: &str, data: &[u8]) -> FbResult<()> {
+        self.flash_sparse_from(partition, data, data.len() as u64)
+    }
+
+    /// Like [`flash_sparse`](Fastboot::flash_sparse), but reads the image of
+    /// `size` bytes from `data` while flashing, see [`sparse::split_from`].
+    fn flash_sparse_from<R: Read>(&mut self, partition: &str, data: R, size: u64) -> FbResult<()> {
+        let max = self.max_download_size()?;
+        fb_flash_split(self, partition, data, size, max)
+    }
+
+    /// Runs a vendor specific OEM command, e.g. `oem format`, and returns the
+    /// final reply. INFO replies are printed as they come.
+    fn oem(&mut self, command: &str) -> FbResult<String> {
+        fb_command(self, OEM_CMD, command)
+    }
+
+    /// Reads `size` bytes from a partition, starting at `offset`, and writes
+    /// them into `out`. Without a `size`, the partition is read up to its end.
+    /// Large reads are split into multiple fetches of at most `max-fetch-size`.
+    /// Returns the number of bytes read.
+    fn fetch<W: Write>(
+        &mut self,
+        partition: &str,
+        offset: u64,
+        size: Option<u64>,
+        out: &mut W,
+    ) -> FbResult<u64> {
+        let size = match size {
+            Some(size) => size,
+            None => self
+                .partition_size(partition)?
+                .checked_sub(offset)
+                .ok_or_else(|| format!("{partition}: offset beyond end"))?,
+        };
+        // Without the variable, there is no limit.
+        let max = match fb_getvar_opt(self, "max-fetch-size")? {
+            Some(max) => parse_size("max-fetch-size", &max)?.max(1),
+            None => size.max(1),
+        };
+
+        let mut done = 0;
+        while done < size {
+            let len = (size - done).min(max);
+            let arg = format!("{partition}:0x{:08x}:0x{len:08x}", offset + done);
+            let mut cmd = Vec::with_capacity(FETCH_CMD.len() + arg.len());
+            cmd.extend_from_slice(FETCH_CMD);
+            cmd.extend_from_slice(arg.as_bytes());
+
+            match fb_send(self, &cmd)? {
+                Reply::Data(n) if n as u64 == len => fb_read_data(self, n, out)?,
+                Reply::Data(n) => {
+                    // Drain what the device sends anyway, so that the session
+                    // stays usable.
+                    fb_read_data(self, n, &mut std::io::sink())?;
+                    fb_read(self)?;
+                    return Err(format!("DATA: Expected {len} bytes, got {n}"));
+                }
+                Reply::Fail(message) => return Err(message),
+                _ => return Err("Unknown failure".to_owned()),
+            }
+            match fb_read(self)? {
+                Reply::Okay(_) => {}
+                Reply::Fail(message) => return Err(message),
+                _ => return Err("Unknown failure".to_owned()),
+            }
+            done += len;
+        }
+        Ok(done)
+    }
+
+    /// Wipes an installed GSI, so that the device boots its own system again.
+    fn gsi_wipe(&mut self) -> FbResult<()> {
+        fb_command(self, GSI_CMD, "wipe").map(|_| ())
+    }
+
+    /// Disables an installed GSI without wiping it.
+    fn gsi_disable(&mut self) -> FbResult<()> {
+        fb_command(self, GSI_CMD, "disable").map(|_| ())
+    }
+
+    /// Tells whether a GSI is running.
+    fn gsi_status(&mut self) -> FbResult<GsiStatus> {
+        let mut info = Vec::new();
+        let okay = match fb_send_info(self, b"gsi:status", &mut info)? {
+            Reply::Okay(message) => message,
+            Reply::Fail(message) => return Err(message),
+            _ => return Err("Unknown failure".to_owned()),
+        };
+        // The status mostly comes as INFO.
+        info.into_iter()
+            .chain(Some(okay))
+            .find(|m| !m.trim().is_empty())
+            .ok_or_else(|| "gsi:status: no answer".to_owned())?
+            .parse()
+    }
+
+    /// Gets whether the bootloader is unlocked.
+    fn lock_state(&mut self) -> FbResult<LockState> {
+        let var = "unlocked";
+        match parse_yes_no(var, &self.getvar(var)?)? {
+            true => Ok(LockState::Unlocked),
+            false => Ok(LockState::Locked),
+        }
+    }
+
+    /// Tells whether the bootloader enforces verified boot.
+    fn secure(&mut self) -> FbResult<bool> {
+        let var = "secure";
+        parse_yes_no(var, &self.getvar(var)?)
+    }
+
+    /// Locks the bootloader, so that partitions cannot be flashed anymore.
+    ///
+    /// NOTE: This may wipe user data and waits for a confirmation on the
+    /// device.
+    fn flashing_lock(&mut self) -> FbResult<()> {
+        fb_command(self, FLASHING_CMD, "lock").map(|_| ())
+    }
+
+    /// Unlocks the bootloader, so that partitions can be flashed.
+    ///
+    /// NOTE: This may wipe user data and waits for a confirmation on the
+    /// device.
+    fn flashing_unlock(&mut self) -> FbResult<()> {
+        fb_command(self, FLASHING_CMD, "unlock").map(|_| ())
+    }
+
+    /// Locks the bootloader-critical partitions, e.g. the bootloader itself.
+    fn flashing_lock_critical(&mut self) -> FbResult<()> {
+        fb_command(self, FLASHING_CMD, "lock_critical").map(|_| ())
+    }
+
+    /// Unlocks the bootloader-critical partitions, e.g. the bootloader itself.
+    fn flashing_unlock_critical(&mut self) -> FbResult<()> {
+        fb_command(self, FLASHING_CMD, "unlock_critical").map(|_| ())
+    }
+
+    /// Tells whether the bootloader may be unlocked, i.e. whether unlocking
+    /// is allowed in the OS settings.
+    fn unlock_ability(&mut self) -> FbResult<bool> {
+        let mut info = Vec::new();
+        let okay = match fb_send_info(self, b"flashing get_unlock_ability", &mut info)? {
+            Reply::Okay(message) => message,
+            Reply::Fail(message) => return Err(message),
+            _ => return Err("Unknown failure".to_owned()),
+        };
+        // The answer comes as e.g. `get_unlock_ability: 1`, mostly as INFO.
+        info.iter()
+            .chain(Some(&okay))
+            .filter_map(|m| m.rsplit(':').next()?.trim().parse::<u8>().ok())
+            .next_back()
+            .map(|a| a != 0)
+            .ok_or_else(|| "get_unlock_ability: no answer".to_owned())
+    }
+
+    /// Erases a partition of the selected slots.
+    fn erase_slot(&mut self, partition: &str, slots: SlotSelect) -> FbResult<()> {
+        for p in self.slot_partitions(partition, slots)? {
+            self.erase(&p)?;
+        }
+        Ok(())
+    }
 }
 
 // TODO: not sure if it's a right way to do things
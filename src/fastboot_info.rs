@@ -0,0 +1,231 @@
+//! `fastboot-info.txt`, the flashing order shipped with AOSP builds.
+//!
+//! The file starts with `version 1`, followed by one step per line:
+//!
+//! ```text
+//! version 1
+//! flash boot
+//! flash --apply-vbmeta vbmeta
+//! flash --slot-other system system_other.img
+//! reboot fastboot
+//! update-super
+//! flash system
+//! if-wipe erase userdata
+//! ```
+//!
+//! See [`FlashAll::run_info`](crate::flashall::FlashAll::run_info) for how
+//! the steps are carried out.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::fastboot::{FbResult, RebootTarget};
+
+/// The newest version of the format that is understood.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    Flash {
+        partition: String,
+        /// The image file, `<partition>.img` by default
+        image: String,
+        /// Apply the vbmeta flags of the flashing options to the image.
+        apply_vbmeta: bool,
+        /// Flash the slot that is not active, instead of the chosen one.
+        slot_other: bool,
+    },
+    Reboot(RebootTarget),
+    /// Update the `super` metadata from `super_empty.img`.
+    UpdateSuper,
+    Erase(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    pub step: Step,
+    /// Only done when wiping user data (`if-wipe`).
+    pub if_wipe: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FastbootInfo {
+    pub version: u32,
+    pub tasks: Vec<Task>,
+}
+
+fn parse_step(line: &str) -> FbResult<Step> {
+    let mut words = line.split_whitespace();
+    let err = || format!("Invalid fastboot-info.txt step: {line}");
+    let step = match words.next() {
+        Some("flash") => {
+            let mut apply_vbmeta = false;
+            let mut slot_other = false;
+            let mut args = Vec::new();
+            for word in words.by_ref() {
+                match word {
+                    "--apply-vbmeta" => apply_vbmeta = true,
+                    "--slot-other" => slot_other = true,
+                    w if w.starts_with("--") => return Err(err()),
+                    w => args.push(w),
+                }
+            }
+            let (partition, image) = match args[..] {
+                [partition] => (partition, format!("{partition}.img")),
+                [partition, image] => (partition, image.to_owned()),
+                _ => return Err(err()),
+            };
+            Step::Flash {
+                partition: partition.to_owned(),
+                image,
+                apply_vbmeta,
+                slot_other,
+            }
+        }
+        Some("reboot") => Step::Reboot(words.next().unwrap_or_default().parse()?),
+        Some("update-super") => Step::UpdateSuper,
+        Some("erase") => Step::Erase(words.next().ok_or_else(err)?.to_owned()),
+        _ => return Err(err()),
+    };
+    match words.next() {
+        Some(_) => Err(err()),
+        None => Ok(step),
+    }
+}
+
+impl FromStr for FastbootInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        let mut lines = s
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'));
+        let version = lines
+            .next()
+            .and_then(|l| l.strip_prefix("version "))
+            .and_then(|v| v.trim().parse().ok())
+            .ok_or_else(|| "fastboot-info.txt does not start with a version".to_owned())?;
+        if version > VERSION {
+            return Err(format!("Unsupported fastboot-info.txt version {version}"));
+        }
+
+        let mut tasks = Vec::new();
+        for line in lines {
+            let (if_wipe, line) = match line.strip_prefix("if-wipe ") {
+                Some(rest) => (true, rest),
+                None => (false, line),
+            };
+            tasks.push(Task {
+                step: parse_step(line)?,
+                if_wipe,
+            });
+        }
+        Ok(FastbootInfo { version, tasks })
+    }
+}
+
+/// A step with its partitions resolved for a device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resolved {
+    Flash {
+        partition: String,
+        image: String,
+        /// vbmeta header flags to set before flashing
+        vbmeta_flags: Option<u32>,
+    },
+    Reboot(RebootTarget),
+    UpdateSuper {
+        partition: String,
+        wipe: bool,
+    },
+    Erase(String),
+}
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Resolved::Flash {
+                partition,
+                image,
+                vbmeta_flags,
+            } => {
+                write!(f, "flash {partition} {image}")?;
+                match vbmeta_flags {
+                    Some(flags) => write!(f, " (vbmeta flags {flags})"),
+                    None => Ok(()),
+                }
+            }
+            Resolved::Reboot(target) => write!(f, "reboot {target}"),
+            Resolved::UpdateSuper { partition, wipe } => match wipe {
+                true => write!(f, "update-super {partition} wipe"),
+                false => write!(f, "update-super {partition}"),
+            },
+            Resolved::Erase(partition) => write!(f, "erase {partition}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FastbootInfo, Step, Task};
+    use crate::fastboot::RebootTarget;
+
+    #[test]
+    fn test_parse() {
+        let info: FastbootInfo = "version 1\n\
+            # boot images\n\
+            flash boot\n\
+            flash --apply-vbmeta vbmeta\n\
+            flash --slot-other system system_other.img\n\
+            reboot fastboot\n\
+            update-super\n\
+            if-wipe erase userdata\n\
+            reboot powerdown\n"
+            .parse()
+            .unwrap();
+        assert_eq!(info.version, 1);
+        assert_eq!(info.tasks.len(), 7);
+        assert_eq!(
+            info.tasks[1].step,
+            Step::Flash {
+                partition: "vbmeta".to_owned(),
+                image: "vbmeta.img".to_owned(),
+                apply_vbmeta: true,
+                slot_other: false,
+            }
+        );
+        assert_eq!(
+            info.tasks[2].step,
+            Step::Flash {
+                partition: "system".to_owned(),
+                image: "system_other.img".to_owned(),
+                apply_vbmeta: false,
+                slot_other: true,
+            }
+        );
+        assert_eq!(info.tasks[3].step, Step::Reboot(RebootTarget::Fastboot));
+        assert_eq!(info.tasks[4].step, Step::UpdateSuper);
+        assert_eq!(
+            info.tasks[5],
+            Task {
+                step: Step::Erase("userdata".to_owned()),
+                if_wipe: true,
+            }
+        );
+        assert_eq!(info.tasks[6].step, Step::Reboot(RebootTarget::PowerDown));
+
+        assert!("flash boot".parse::<FastbootInfo>().is_err());
+        assert!("version 2\nflash boot".parse::<FastbootInfo>().is_err());
+        assert!("version 1\nflash --force boot"
+            .parse::<FastbootInfo>()
+            .is_err());
+        assert!("version 1\nflash a b c".parse::<FastbootInfo>().is_err());
+        assert!("version 1\nformat userdata"
+            .parse::<FastbootInfo>()
+            .is_err());
+        assert!("version 1\nupdate-super now"
+            .parse::<FastbootInfo>()
+            .is_err());
+    }
+}
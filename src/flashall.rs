@@ -0,0 +1,486 @@
+//! Flashing a whole product output directory, like `fastboot flashall`, or
+//! an update package, like `fastboot update`.
+//!
+//! The directory or package holds `android-info.txt` with the requirements
+//! the device has to meet, and one `<partition>.img` per partition. With a
+//! `super_empty.img`, the partitions it lists are logical ones: they are
+//! flashed from fastbootd after updating the `super` metadata, everything
+//! else is flashed first, from the bootloader.
+
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::fastboot::{fb_getvar_opt, Fastboot, FbResult, RebootTarget, SlotSelect};
+use crate::fastboot_info::{FastbootInfo, Resolved, Step, Task};
+use crate::lpmetadata::LpMetadata;
+use crate::sparse;
+use crate::vbmeta;
+use crate::zip::ZipArchive;
+
+/// Partitions in the order they are flashed.
+const IMAGES: &[&str] = &[
+    "boot",
+    "init_boot",
+    "dtbo",
+    "pvmfw",
+    "recovery",
+    "vendor_boot",
+    "vendor_kernel_boot",
+    "vbmeta",
+    "vbmeta_system",
+    "vbmeta_vendor",
+    "odm",
+    "odm_dlkm",
+    "product",
+    "system",
+    "system_dlkm",
+    "system_ext",
+    "vendor",
+    "vendor_dlkm",
+];
+
+/// A line of `android-info.txt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    /// Only applies to this product (`require-for-product:`).
+    pub product: Option<String>,
+    /// A variable, where `board` stands for `product`, or `partition-exists`.
+    pub name: String,
+    /// Accepted values; a trailing `*` matches any suffix.
+    pub values: Vec<String>,
+    /// The device must match none of the values instead (`reject`).
+    pub reject: bool,
+}
+
+impl Requirement {
+    fn matches(&self, value: &str) -> bool {
+        self.values.iter().any(|v| match v.strip_suffix('*') {
+            Some(prefix) => value.starts_with(prefix),
+            None => value == v,
+        })
+    }
+}
+
+/// The requirements of `android-info.txt`.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct AndroidInfo {
+    pub requirements: Vec<Requirement>,
+}
+
+impl FromStr for AndroidInfo {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        let mut requirements = Vec::new();
+        for line in s.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (product, reject, rest) = if let Some(rest) = line.strip_prefix("require ") {
+                (None, false, rest)
+            } else if let Some(rest) = line.strip_prefix("reject ") {
+                (None, true, rest)
+            } else if let Some(rest) = line.strip_prefix("require-for-product:") {
+                let (product, rest) = rest
+                    .split_once(' ')
+                    .ok_or_else(|| format!("Invalid requirement: {line}"))?;
+                (Some(product.to_owned()), false, rest)
+            } else if line.starts_with("board=") {
+                (None, false, line)
+            } else {
+                return Err(format!("Unknown requirement: {line}"));
+            };
+            let (name, values) = rest
+                .trim()
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid requirement: {line}"))?;
+            requirements.push(Requirement {
+                product,
+                name: name.to_owned(),
+                values: values.split('|').map(str::to_owned).collect(),
+                reject,
+            });
+        }
+        Ok(AndroidInfo { requirements })
+    }
+}
+
+impl AndroidInfo {
+    /// Checks the requirements against the variables of a device.
+    pub fn check<F: Fastboot>(&self, dev: &mut F) -> FbResult<()> {
+        let product = dev.getvar("product")?;
+        for r in &self.requirements {
+            if r.product.as_ref().is_some_and(|p| *p != product) {
+                continue;
+            }
+            let (value, matched) = match r.name.as_str() {
+                "partition-exists" => {
+                    let mut exists = false;
+                    for p in &r.values {
+                        let var = format!("partition-size:{p}");
+                        exists |= fb_getvar_opt(dev, &var)?.is_some();
+                    }
+                    ("missing".to_owned(), exists)
+                }
+                "board" | "product" => (product.clone(), r.matches(&product)),
+                var => {
+                    let value = dev.getvar(var)?;
+                    let matched = r.matches(&value);
+                    (value, matched)
+                }
+            };
+            if matched == r.reject {
+                let verb = if r.reject { "rejects" } else { "requires" };
+                return Err(format!(
+                    "Device {} is {value}, but the image {verb} {}",
+                    r.name,
+                    r.values.join(" or ")
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What [`FlashAll::run`] is going to flash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Plan {
+    /// Partitions flashed from the bootloader, in order
+    pub bootloader: Vec<&'static str>,
+    /// Logical partitions flashed from fastbootd, in order
+    pub fastbootd: Vec<&'static str>,
+    pub super_empty: Option<Vec<u8>>,
+}
+
+/// Where the images come from.
+#[derive(Debug, Clone)]
+enum Source {
+    Dir(PathBuf),
+    Zip(ZipArchive),
+}
+
+impl Source {
+    /// The size of a file, if it exists.
+    fn size(&self, name: &str) -> Option<u64> {
+        match self {
+            Source::Dir(dir) => fs::metadata(dir.join(name)).ok().map(|m| m.len()),
+            Source::Zip(zip) => zip.entry(name).map(|e| e.size),
+        }
+    }
+
+    fn open(&self, name: &str) -> FbResult<Box<dyn Read>> {
+        match self {
+            Source::Dir(dir) => {
+                let path = dir.join(name);
+                match File::open(&path) {
+                    Ok(file) => Ok(Box::new(file)),
+                    Err(e) => Err(format!("{}: {e}", path.display())),
+                }
+            }
+            Source::Zip(zip) => Ok(Box::new(zip.open_entry(name)?)),
+        }
+    }
+
+    fn read(&self, name: &str) -> FbResult<Vec<u8>> {
+        let mut data = Vec::new();
+        self.open(name)?
+            .read_to_end(&mut data)
+            .map_err(|e| format!("{name}: {e}"))?;
+        Ok(data)
+    }
+}
+
+/// A product output directory or update package to flash.
+#[derive(Debug, Clone)]
+pub struct FlashAll {
+    source: Source,
+    /// Where to flash partitions with A/B slots
+    pub slots: SlotSelect,
+    /// Also do the `if-wipe` steps of `fastboot-info.txt`.
+    pub wipe: bool,
+    /// vbmeta header flags added by `flash --apply-vbmeta` steps, see
+    /// [`vbmeta::add_flags`].
+    pub vbmeta_flags: Option<u32>,
+    /// Cancel a pending Virtual A/B update instead of failing, see
+    /// [`Fastboot::check_snapshot_update`].
+    pub cancel_snapshot: bool,
+}
+
+impl FlashAll {
+    /// Flashes the images in a product output directory.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        FlashAll {
+            source: Source::Dir(dir.as_ref().to_owned()),
+            slots: SlotSelect::Current,
+            wipe: false,
+            vbmeta_flags: None,
+            cancel_snapshot: false,
+        }
+    }
+
+    /// Flashes the images in an update package, as `fastboot update` does.
+    /// Stored and deflated entries are streamed right out of the archive.
+    pub fn open_zip<P: AsRef<Path>>(path: P) -> FbResult<Self> {
+        Ok(FlashAll {
+            source: Source::Zip(ZipArchive::open(path)?),
+            slots: SlotSelect::Current,
+            wipe: false,
+            vbmeta_flags: None,
+            cancel_snapshot: false,
+        })
+    }
+
+    /// Splits the partitions with an image into those flashed from the
+    /// bootloader and the logical ones, flashed from fastbootd.
+    pub fn plan(&self) -> FbResult<Plan> {
+        let super_empty = match self.source.size("super_empty.img") {
+            Some(_) => Some(self.source.read("super_empty.img")?),
+            None => None,
+        };
+        let logical: Vec<String> = match &super_empty {
+            Some(data) => LpMetadata::parse_image(data)?
+                .partitions
+                .iter()
+                .map(|p| {
+                    let name = p.name.as_str();
+                    let base = name.strip_suffix("_a").or(name.strip_suffix("_b"));
+                    base.unwrap_or(name).to_owned()
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let (fastbootd, bootloader) = IMAGES
+            .iter()
+            .filter(|p| self.source.size(&format!("{p}.img")).is_some())
+            .partition(|p| logical.iter().any(|l| l == *p));
+        Ok(Plan {
+            bootloader,
+            fastbootd,
+            super_empty,
+        })
+    }
+
+    fn flash_file<F: Fastboot>(&self, dev: &mut F, partition: &str) -> FbResult<()> {
+        let name = format!("{partition}.img");
+        let size = self.file_size(&name)? as usize;
+        for p in dev.slot_partitions(partition, self.slots)? {
+            dev.flash_image_from(&p, self.source.open(&name)?, size, |_| {})?;
+        }
+        Ok(())
+    }
+
+    fn file_size(&self, name: &str) -> FbResult<u64> {
+        self.source
+            .size(name)
+            .ok_or_else(|| format!("{name} is missing"))
+    }
+
+    /// The size of the partition image once flashed, which for sparse
+    /// images is their expanded size.
+    fn image_size(&self, partition: &str) -> FbResult<u64> {
+        let name = format!("{partition}.img");
+        let size = self.file_size(&name)?;
+        let mut header = Vec::new();
+        self.source
+            .open(&name)?
+            .take(sparse::HEADER_SIZE as u64)
+            .read_to_end(&mut header)
+            .map_err(|e| format!("{name}: {e}"))?;
+        Ok(sparse::expanded_size(&header).unwrap_or(size))
+    }
+
+    /// Checks `android-info.txt` and that no Virtual A/B update is pending
+    /// (see [`cancel_snapshot`](FlashAll::cancel_snapshot)), then flashes all
+    /// images, in the order of
+    /// `fastboot-info.txt` if there is one (see
+    /// [`run_info`](FlashAll::run_info)). Otherwise, for logical partitions,
+    /// the device is rebooted into fastbootd unless it already runs it;
+    /// `reconnect` is then called to get hold of it again. Returns the device
+    /// as last connected, which is not rebooted at the end.
+    pub fn run<T, C>(&self, mut dev: T, mut reconnect: C) -> FbResult<T>
+    where
+        T: Read + Write,
+        C: FnMut() -> FbResult<T>,
+    {
+        let info = self.source.read("android-info.txt")?;
+        String::from_utf8_lossy(&info)
+            .parse::<AndroidInfo>()?
+            .check(&mut dev)?;
+        if let Some(info) = self.fastboot_info()? {
+            return self.run_info(&info, dev, reconnect);
+        }
+
+        dev.check_snapshot_update(self.cancel_snapshot)?;
+        let plan = self.plan()?;
+        for partition in plan.bootloader {
+            self.flash_file(&mut dev, partition)?;
+        }
+        let super_empty = match plan.super_empty {
+            Some(data) => data,
+            None => return Ok(dev),
+        };
+
+        if !dev.is_userspace()? {
+            dev.reboot_to(RebootTarget::Fastboot)?;
+            dev = reconnect()?;
+        }
+        let super_name = dev.super_partition_name()?;
+        let mut sizes = Vec::new();
+        for partition in &plan.fastbootd {
+            let size = self.image_size(partition)?;
+            for p in dev.slot_partitions(partition, self.slots)? {
+                sizes.push((p, size));
+            }
+        }
+        let sizes: Vec<(&str, u64)> = sizes.iter().map(|(p, s)| (p.as_str(), *s)).collect();
+        dev.update_super_image(&super_name, &super_empty, &sizes, false)?;
+        for partition in plan.fastbootd {
+            self.flash_file(&mut dev, partition)?;
+        }
+        Ok(dev)
+    }
+
+    /// Reads `fastboot-info.txt`, if there is one.
+    pub fn fastboot_info(&self) -> FbResult<Option<FastbootInfo>> {
+        if self.source.size("fastboot-info.txt").is_none() {
+            return Ok(None);
+        }
+        let info = self.source.read("fastboot-info.txt")?;
+        String::from_utf8_lossy(&info).parse().map(Some)
+    }
+
+    fn resolve<F: Fastboot>(&self, task: &Task, dev: &mut F) -> FbResult<Vec<Resolved>> {
+        if task.if_wipe && !self.wipe {
+            return Ok(Vec::new());
+        }
+        let resolved = match &task.step {
+            Step::Flash {
+                partition,
+                image,
+                apply_vbmeta,
+                slot_other,
+            } => {
+                if self.source.size(image).is_none() {
+                    return Err(format!("{image} is missing"));
+                }
+                let slots = match slot_other {
+                    true => SlotSelect::Other,
+                    false => self.slots,
+                };
+                dev.slot_partitions(partition, slots)?
+                    .into_iter()
+                    .map(|partition| Resolved::Flash {
+                        partition,
+                        image: image.clone(),
+                        vbmeta_flags: self.vbmeta_flags.filter(|_| *apply_vbmeta),
+                    })
+                    .collect()
+            }
+            Step::Reboot(target) => vec![Resolved::Reboot(target.clone())],
+            Step::UpdateSuper => vec![Resolved::UpdateSuper {
+                partition: dev.super_partition_name()?,
+                wipe: self.wipe,
+            }],
+            Step::Erase(partition) => dev
+                .slot_partitions(partition, self.slots)?
+                .into_iter()
+                .map(Resolved::Erase)
+                .collect(),
+        };
+        Ok(resolved)
+    }
+
+    /// Resolves the steps of `info` for a device without changing anything
+    /// on it. Slots of logical partitions may only resolve correctly once
+    /// the device runs fastbootd.
+    pub fn dry_run<F: Fastboot>(
+        &self,
+        info: &FastbootInfo,
+        dev: &mut F,
+    ) -> FbResult<Vec<Resolved>> {
+        let mut steps = Vec::new();
+        for task in &info.tasks {
+            steps.extend(self.resolve(task, dev)?);
+        }
+        Ok(steps)
+    }
+
+    /// Carries out the steps of `info`. Each step is resolved right before
+    /// it runs, so slots are looked up in the mode the device is in by then.
+    /// `reconnect` is called after every reboot, except into the system.
+    pub fn run_info<T, C>(&self, info: &FastbootInfo, mut dev: T, mut reconnect: C) -> FbResult<T>
+    where
+        T: Read + Write,
+        C: FnMut() -> FbResult<T>,
+    {
+        dev.check_snapshot_update(self.cancel_snapshot)?;
+        for task in &info.tasks {
+            for step in self.resolve(task, &mut dev)? {
+                match step {
+                    Resolved::Flash {
+                        partition,
+                        image,
+                        vbmeta_flags: Some(flags),
+                    } => {
+                        let mut data = self.source.read(&image)?;
+                        vbmeta::add_flags(&mut data, flags)?;
+                        dev.flash_image(&partition, &data)?;
+                    }
+                    Resolved::Flash {
+                        partition, image, ..
+                    } => {
+                        let size = self.file_size(&image)? as usize;
+                        dev.flash_image_from(&partition, self.source.open(&image)?, size, |_| {})?;
+                    }
+                    Resolved::Reboot(target) => {
+                        dev.reboot_to(target.clone())?;
+                        if !matches!(target, RebootTarget::System | RebootTarget::PowerDown) {
+                            dev = reconnect()?;
+                        }
+                    }
+                    Resolved::UpdateSuper { partition, wipe } => {
+                        let super_empty = self.source.read("super_empty.img")?;
+                        dev.update_super_image(&partition, &super_empty, &[], wipe)?;
+                    }
+                    Resolved::Erase(partition) => dev.erase(&partition)?,
+                }
+            }
+        }
+        Ok(dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AndroidInfo, Requirement};
+
+    #[test]
+    fn test_android_info() {
+        let info: AndroidInfo = "# comment\n\
+            board=sm8550|sm8650\n\
+            require version-bootloader=1.2*\n\
+            reject version-baseband=0.9\n\
+            require-for-product:lynx partition-exists=vendor_dlkm\n"
+            .parse()
+            .unwrap();
+        assert_eq!(info.requirements.len(), 4);
+        assert_eq!(
+            info.requirements[0],
+            Requirement {
+                product: None,
+                name: "board".to_owned(),
+                values: vec!["sm8550".to_owned(), "sm8650".to_owned()],
+                reject: false,
+            }
+        );
+        assert!(info.requirements[1].matches("1.2.3"));
+        assert!(!info.requirements[1].matches("1.3"));
+        assert!(info.requirements[2].reject);
+        assert_eq!(info.requirements[3].product.as_deref(), Some("lynx"));
+
+        assert!("require board".parse::<AndroidInfo>().is_err());
+        assert!("prefer board=x".parse::<AndroidInfo>().is_err());
+    }
+}
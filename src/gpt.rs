@@ -0,0 +1,530 @@
+//! GUID partition tables, as written to raw devices with `flash:gpt`.
+//!
+//! Layouts are described like U-Boot's `gpt write` partition strings:
+//!
+//! ```text
+//! uuid_disk=<guid>;name=boot,size=64M,type=system;name=rootfs,size=-
+//! ```
+//!
+//! Every partition needs a `name`; `start`, `size`, `uuid` and `type` are
+//! optional. Sizes take `K`, `M`, `G` and `T` suffixes and a size of `-`
+//! fills the rest of the disk. Partitions without a `start` begin after the
+//! previous one, aligned to 1 MiB. GUIDs that are not given are derived
+//! from the layout, so the same layout always gives the same image.
+
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+use crate::bytes::{crc32, Reader};
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: usize = 92;
+const ENTRY_SIZE: usize = 128;
+const NUM_ENTRIES: usize = 128;
+const NAME_LEN: usize = 36;
+const ALIGNMENT: u64 = 1 << 20;
+
+/// A GUID, in the mixed-endian order it is stored on disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    /// Linux filesystem data
+    pub const LINUX: Guid = Guid::from_fields(
+        0x0fc6_3daf,
+        0x8483,
+        0x4772,
+        [0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d, 0xe4],
+    );
+    /// EFI system partition
+    pub const SYSTEM: Guid = Guid::from_fields(
+        0xc12a_7328,
+        0xf81f,
+        0x11d2,
+        [0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b],
+    );
+
+    const fn from_fields(a: u32, b: u16, c: u16, d: [u8; 8]) -> Self {
+        let a = a.to_le_bytes();
+        let b = b.to_le_bytes();
+        let c = c.to_le_bytes();
+        Guid([
+            a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5],
+            d[6], d[7],
+        ])
+    }
+
+    /// A random-looking (version 4) GUID derived from `seed`.
+    fn derive(seed: &str) -> Self {
+        let hash = Sha256::digest(seed.as_bytes());
+        let mut g: [u8; 16] = hash[..16].try_into().unwrap();
+        g[7] = (g[7] & 0x0f) | 0x40;
+        g[8] = (g[8] & 0x3f) | 0x80;
+        Guid(g)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-",
+            u32::from_le_bytes(g[0..4].try_into().unwrap()),
+            u16::from_le_bytes(g[4..6].try_into().unwrap()),
+            u16::from_le_bytes(g[6..8].try_into().unwrap()),
+        )?;
+        for (i, b) in g[8..].iter().enumerate() {
+            if i == 2 {
+                write!(f, "-")?;
+            }
+            write!(f, "{b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Guid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linux" => return Ok(Guid::LINUX),
+            "system" => return Ok(Guid::SYSTEM),
+            _ => (),
+        }
+        let err = || format!("Invalid GUID: {s}");
+        let parts: Vec<&str> = s.split('-').collect();
+        if parts.iter().map(|p| p.len()).collect::<Vec<_>>() != [8, 4, 4, 4, 12] {
+            return Err(err());
+        }
+        let hex: String = parts.concat();
+        let mut b = [0; 16];
+        for (i, byte) in b.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2).ok_or_else(err)?, 16)
+                .map_err(|_| err())?;
+        }
+        let d: [u8; 8] = b[8..].try_into().unwrap();
+        Ok(Guid::from_fields(
+            u32::from_be_bytes(b[0..4].try_into().unwrap()),
+            u16::from_be_bytes(b[4..6].try_into().unwrap()),
+            u16::from_be_bytes(b[6..8].try_into().unwrap()),
+            d,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GptPartition {
+    pub name: String,
+    pub type_guid: Guid,
+    pub guid: Guid,
+    pub first_lba: u64,
+    /// Inclusive
+    pub last_lba: u64,
+    pub attributes: u64,
+}
+
+impl GptPartition {
+    /// Zero for a malformed entry that ends before it starts.
+    pub fn sectors(&self) -> u64 {
+        match self.last_lba.checked_sub(self.first_lba) {
+            Some(n) => n.saturating_add(1),
+            None => 0,
+        }
+    }
+}
+
+/// A GUID partition table for a disk of `disk_sectors` blocks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Gpt {
+    pub block_size: u64,
+    pub disk_sectors: u64,
+    pub disk_guid: Guid,
+    pub partitions: Vec<GptPartition>,
+}
+
+/// Parses sizes like `512`, `64K` or `1G`.
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'K') => (&s[..s.len() - 1], 10),
+        Some(b'M') => (&s[..s.len() - 1], 20),
+        Some(b'G') => (&s[..s.len() - 1], 30),
+        Some(b'T') => (&s[..s.len() - 1], 40),
+        _ => (s, 0),
+    };
+    let num = match num.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => num.parse(),
+    };
+    num.ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("Invalid size: {s}"))
+}
+
+impl Gpt {
+    fn entry_sectors(&self) -> u64 {
+        ((NUM_ENTRIES * ENTRY_SIZE) as u64).div_ceil(self.block_size)
+    }
+
+    pub fn first_usable_lba(&self) -> u64 {
+        2 + self.entry_sectors()
+    }
+
+    pub fn last_usable_lba(&self) -> u64 {
+        self.disk_sectors - 2 - self.entry_sectors()
+    }
+
+    /// Builds a table from a layout description (see the module
+    /// documentation) for a disk of `disk_size` bytes.
+    pub fn from_layout(layout: &str, disk_size: u64, block_size: u64) -> Result<Self, String> {
+        if !matches!(block_size, 512 | 4096) {
+            return Err("Invalid disk or block size".to_owned());
+        }
+        let mut gpt = Gpt {
+            block_size,
+            disk_sectors: disk_size / block_size,
+            disk_guid: Guid::derive(layout),
+            partitions: Vec::new(),
+        };
+        if gpt.disk_sectors < 2 * gpt.first_usable_lba() {
+            return Err("Invalid disk or block size".to_owned());
+        }
+        let align = (ALIGNMENT / block_size).max(1);
+        let mut next = gpt.first_usable_lba();
+
+        for desc in layout.split(';').map(str::trim).filter(|d| !d.is_empty()) {
+            if let Some(guid) = desc.strip_prefix("uuid_disk=") {
+                gpt.disk_guid = guid.parse()?;
+                continue;
+            }
+            let mut name = None;
+            let mut start = None;
+            let mut size = None;
+            let mut guid = None;
+            let mut type_guid = Guid::LINUX;
+            for field in desc.split(',') {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or_else(|| format!("Invalid partition field: {field}"))?;
+                match key {
+                    "name" => name = Some(value.to_owned()),
+                    "start" => start = Some(parse_size(value)?),
+                    "size" if value == "-" => size = None,
+                    "size" => size = Some(parse_size(value)?),
+                    "uuid" => guid = Some(value.parse()?),
+                    "type" => type_guid = value.parse()?,
+                    _ => return Err(format!("Unknown partition field: {key}")),
+                }
+            }
+            let name = name.ok_or_else(|| format!("Partition without a name: {desc}"))?;
+            if name.encode_utf16().count() > NAME_LEN {
+                return Err(format!("{name}: name is too long"));
+            }
+            let first_lba = match start {
+                Some(start) if start % block_size == 0 => start / block_size,
+                Some(_) => return Err(format!("{name}: start is not block aligned")),
+                None => next.next_multiple_of(align),
+            };
+            let last_lba = match size {
+                Some(size) => first_lba
+                    .checked_add(size.div_ceil(block_size))
+                    .ok_or_else(|| format!("{name}: does not fit"))?
+                    .saturating_sub(1),
+                None => gpt.last_usable_lba(),
+            };
+            if first_lba < next || last_lba < first_lba || last_lba > gpt.last_usable_lba() {
+                return Err(format!("{name}: does not fit"));
+            }
+            if gpt.partitions.len() == NUM_ENTRIES {
+                return Err("Too many partitions".to_owned());
+            }
+            gpt.partitions.push(GptPartition {
+                guid: guid.unwrap_or_else(|| Guid::derive(&format!("{}:{name}", gpt.disk_guid))),
+                name,
+                type_guid,
+                first_lba,
+                last_lba,
+                attributes: 0,
+            });
+            next = last_lba + 1;
+        }
+        Ok(gpt)
+    }
+
+    /// Parses the primary table from the start of a disk or of an image
+    /// made with [`to_bytes`](Gpt::to_bytes). The disk size is taken from
+    /// the header.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let block_size = [512, 4096]
+            .into_iter()
+            .find(|&bs| data.get(bs..bs + SIGNATURE.len()) == Some(SIGNATURE))
+            .ok_or_else(|| "No GPT header found".to_owned())?;
+        let header = &data[block_size..];
+        let mut r = Reader::new(header);
+        r.bytes(SIGNATURE.len())?;
+        let revision = r.u32_le()?;
+        let header_size = r.u32_le()? as usize;
+        if revision != REVISION || !(HEADER_SIZE..=block_size).contains(&header_size) {
+            return Err("Unsupported GPT header".to_owned());
+        }
+        let header_crc = r.u32_le()?;
+        r.u32_le()?;
+        let _my_lba = r.u64_le()?;
+        let alternate_lba = r.u64_le()?;
+        let _first_usable = r.u64_le()?;
+        let _last_usable = r.u64_le()?;
+        let disk_guid = Guid(r.array()?);
+        let entries_lba = r.u64_le()? as usize;
+        let num_entries = r.u32_le()? as usize;
+        let entry_size = r.u32_le()? as usize;
+        let entries_crc = r.u32_le()?;
+
+        let mut h = header
+            .get(..header_size)
+            .ok_or_else(|| "GPT header is truncated".to_owned())?
+            .to_vec();
+        h[16..20].fill(0);
+        if crc32(&h) != header_crc {
+            return Err("GPT header checksum mismatch".to_owned());
+        }
+        let entries = entries_lba
+            .checked_mul(block_size)
+            .and_then(|start| data.get(start..))
+            .zip(num_entries.checked_mul(entry_size))
+            .and_then(|(entries, len)| entries.get(..len))
+            .ok_or_else(|| "GPT entries are truncated".to_owned())?;
+        if entry_size < ENTRY_SIZE || crc32(entries) != entries_crc {
+            return Err("GPT entries checksum mismatch".to_owned());
+        }
+
+        let mut partitions = Vec::new();
+        for e in entries.chunks(entry_size) {
+            let mut r = Reader::new(e);
+            let type_guid = Guid(r.array()?);
+            if type_guid == Guid::default() {
+                continue;
+            }
+            let guid = Guid(r.array()?);
+            let first_lba = r.u64_le()?;
+            let last_lba = r.u64_le()?;
+            let attributes = r.u64_le()?;
+            let mut name = Vec::with_capacity(NAME_LEN);
+            for _ in 0..NAME_LEN {
+                name.push(r.u16_le()?);
+            }
+            let end = name.iter().position(|&c| c == 0).unwrap_or(NAME_LEN);
+            partitions.push(GptPartition {
+                name: String::from_utf16_lossy(&name[..end]),
+                type_guid,
+                guid,
+                first_lba,
+                last_lba,
+                attributes,
+            });
+        }
+        let gpt = Gpt {
+            block_size: block_size as u64,
+            disk_sectors: alternate_lba.checked_add(1).unwrap_or(0),
+            disk_guid,
+            partitions,
+        };
+        if gpt.disk_sectors < 2 * gpt.first_usable_lba() {
+            return Err("Invalid GPT disk size".to_owned());
+        }
+        Ok(gpt)
+    }
+
+    fn entries(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(NUM_ENTRIES * ENTRY_SIZE);
+        for p in &self.partitions {
+            out.extend(p.type_guid.0);
+            out.extend(p.guid.0);
+            out.extend(p.first_lba.to_le_bytes());
+            out.extend(p.last_lba.to_le_bytes());
+            out.extend(p.attributes.to_le_bytes());
+            let mut name: Vec<u16> = p.name.encode_utf16().collect();
+            name.resize(NAME_LEN, 0);
+            out.extend(name.iter().flat_map(|c| c.to_le_bytes()));
+        }
+        out.resize(NUM_ENTRIES * ENTRY_SIZE, 0);
+        out
+    }
+
+    fn header(&self, my_lba: u64, alternate_lba: u64, entries_lba: u64, entries: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.block_size as usize);
+        out.extend(SIGNATURE);
+        out.extend(REVISION.to_le_bytes());
+        out.extend((HEADER_SIZE as u32).to_le_bytes());
+        out.extend([0; 8]);
+        out.extend(my_lba.to_le_bytes());
+        out.extend(alternate_lba.to_le_bytes());
+        out.extend(self.first_usable_lba().to_le_bytes());
+        out.extend(self.last_usable_lba().to_le_bytes());
+        out.extend(self.disk_guid.0);
+        out.extend(entries_lba.to_le_bytes());
+        out.extend((NUM_ENTRIES as u32).to_le_bytes());
+        out.extend((ENTRY_SIZE as u32).to_le_bytes());
+        out.extend(crc32(entries).to_le_bytes());
+        let crc = crc32(&out);
+        out[16..20].copy_from_slice(&crc.to_le_bytes());
+        out.resize(self.block_size as usize, 0);
+        out
+    }
+
+    /// Serializes the protective MBR, the primary header and the partition
+    /// entries, i.e. everything before the first usable block.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut mbr = vec![0; self.block_size as usize];
+        let sectors = (self.disk_sectors - 1).min(u32::MAX as u64) as u32;
+        mbr[446..462].copy_from_slice(
+            &[
+                [0x00, 0x00, 0x02, 0x00, 0xee, 0xff, 0xff, 0xff],
+                [1, 0, 0, 0, 0, 0, 0, 0],
+            ]
+            .concat(),
+        );
+        mbr[458..462].copy_from_slice(&sectors.to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+
+        let entries = self.entries();
+        let mut out = mbr;
+        out.extend(self.header(1, self.disk_sectors - 1, 2, &entries));
+        out.extend_from_slice(&entries);
+        out.resize((self.first_usable_lba() * self.block_size) as usize, 0);
+        out
+    }
+
+    /// Serializes the backup entries and header, which belong at the end of
+    /// the disk, after the last usable block.
+    pub fn to_backup_bytes(&self) -> Vec<u8> {
+        let entries = self.entries();
+        let mut out = entries.clone();
+        out.resize((self.entry_sectors() * self.block_size) as usize, 0);
+        let my_lba = self.disk_sectors - 1;
+        out.extend(self.header(my_lba, 1, self.last_usable_lba() + 1, &entries));
+        out
+    }
+}
+
+impl fmt::Display for Gpt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Disk {}, {} blocks", self.disk_guid, self.disk_sectors)?;
+        for p in &self.partitions {
+            writeln!(
+                f,
+                "{:<20} {:>12} {:>12} {:>14}  {}",
+                p.name,
+                p.first_lba,
+                p.last_lba,
+                p.sectors() * self.block_size,
+                p.type_guid
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gpt, Guid};
+    use crate::bytes::crc32;
+
+    #[test]
+    fn test_guid() {
+        let guid = "0FC63DAF-8483-4772-8E79-3D69D8477DE4";
+        assert_eq!(guid.parse::<Guid>(), Ok(Guid::LINUX));
+        assert_eq!(Guid::LINUX.to_string(), guid);
+        assert_eq!(&Guid::LINUX.0[..4], &[0xaf, 0x3d, 0xc6, 0x0f]);
+        assert!("0FC63DAF-8483-4772-8E79".parse::<Guid>().is_err());
+    }
+
+    #[test]
+    fn test_layout() {
+        let layout = "uuid_disk=6a6d7cf4-58c1-4a3a-9d0b-3b0fd2c4f3a1;\
+            name=boot,size=64M,type=system;name=misc,start=66M,size=4K;name=rootfs,size=-";
+        let gpt = Gpt::from_layout(layout, 1 << 30, 512).unwrap();
+        assert_eq!(gpt.partitions.len(), 3);
+        assert_eq!(gpt.partitions[0].first_lba, 2048);
+        assert_eq!(gpt.partitions[0].sectors() * 512, 64 << 20);
+        assert_eq!(gpt.partitions[0].type_guid, Guid::SYSTEM);
+        assert_eq!(gpt.partitions[1].first_lba, 66 << 11);
+        assert_eq!(gpt.partitions[1].sectors(), 8);
+        assert_eq!(gpt.partitions[2].first_lba, 67 << 11);
+        assert_eq!(gpt.partitions[2].last_lba, (1 << 21) - 34);
+        assert_eq!(
+            Gpt::from_layout(layout, 1 << 30, 512).unwrap(),
+            gpt,
+            "Derived GUIDs are not stable"
+        );
+
+        assert!(Gpt::from_layout("name=a,size=2G", 1 << 30, 512).is_err());
+        assert!(Gpt::from_layout("name=a,size=1M;name=b,start=0,size=1M", 1 << 30, 512).is_err());
+        assert!(Gpt::from_layout("size=1M", 1 << 30, 512).is_err());
+        assert!(Gpt::from_layout("name=a,flags=1", 1 << 30, 512).is_err());
+        assert!(Gpt::from_layout("name=a", 1 << 30, 0).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for block_size in [512, 4096] {
+            let gpt =
+                Gpt::from_layout("name=boot,size=1M;name=userdata", 64 << 20, block_size).unwrap();
+            let data = gpt.to_bytes();
+            assert_eq!(data.len() as u64, gpt.first_usable_lba() * block_size);
+            assert_eq!(&data[510..512], &[0x55, 0xaa]);
+            assert_eq!(Gpt::parse(&data).unwrap(), gpt);
+
+            let backup = gpt.to_backup_bytes();
+            let last = &backup[backup.len() - block_size as usize..];
+            assert_eq!(&last[..8], b"EFI PART");
+            assert_eq!(backup[..16], data[2 * block_size as usize..][..16]);
+
+            let mut data = data;
+            data[block_size as usize + 40] ^= 1;
+            assert!(Gpt::parse(&data).is_err());
+        }
+
+        // Sizes and counts from a corrupt header must not panic.
+        let data = Gpt::from_layout("name=boot", 64 << 20, 4096)
+            .unwrap()
+            .to_bytes();
+        let mut data = data;
+        data[4096 + 12..4096 + 16].copy_from_slice(&4096u32.to_le_bytes());
+        assert!(Gpt::parse(&data[..4096 + 200]).is_err());
+        data[4096 + 80..4096 + 88].copy_from_slice(&[0xff; 8]);
+        assert!(Gpt::parse(&data).is_err());
+
+        let mut p = Gpt::from_layout("name=boot", 64 << 20, 512)
+            .unwrap()
+            .partitions[0]
+            .clone();
+        p.last_lba = p.first_lba - 2;
+        assert_eq!(p.sectors(), 0);
+        p.first_lba = 0;
+        p.last_lba = u64::MAX;
+        assert_eq!(p.sectors(), u64::MAX);
+
+        // A disk size that does not fit is rejected, even with a valid CRC.
+        for alternate_lba in [0, 1, 66, u64::MAX] {
+            let mut data = Gpt::from_layout("name=boot", 64 << 20, 512)
+                .unwrap()
+                .to_bytes();
+            let header = &mut data[512..512 + 92];
+            header[32..40].copy_from_slice(&u64::to_le_bytes(alternate_lba));
+            header[16..20].fill(0);
+            let crc = crc32(header);
+            header[16..20].copy_from_slice(&crc.to_le_bytes());
+            assert!(Gpt::parse(&data).is_err(), "{alternate_lba}");
+        }
+
+        assert!(
+            Gpt::from_layout("name=a,start=4096,size=0xffffffffffffffff", 1 << 30, 512).is_err()
+        );
+        assert!(Gpt::from_layout("name=a", 1 << 30, 1024).is_err());
+    }
+}
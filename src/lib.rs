@@ -1,13 +1,43 @@
+pub mod bootimg;
+mod bytes;
+pub mod dt_table;
 pub mod fastboot;
-pub use fastboot::Fastboot;
+pub mod fastboot_info;
+pub mod flashall;
+pub mod gpt;
+pub mod lpmetadata;
+#[cfg(feature = "manifest")]
+pub mod manifest;
+pub mod record;
+pub mod sparse;
+mod trace;
+pub mod vbmeta;
+pub mod vendor_boot;
+mod zip;
+pub use fastboot::{
+    Fastboot, GsiStatus, LockState, RebootTarget, Slot, SlotSelect, SnapshotUpdateAction,
+    SnapshotUpdateStatus,
+};
 
 #[cfg(test)]
 mod tests {
-    use crate::fastboot::Fastboot;
+    use crate::bootimg::BootImage;
+    use crate::fastboot::{
+        Fastboot, GsiStatus, LockState, RebootTarget, Slot, SlotSelect, SnapshotUpdateAction,
+        SnapshotUpdateStatus,
+    };
+    use crate::flashall::FlashAll;
+    use crate::gpt::Gpt;
+    use crate::lpmetadata::LpMetadata;
+    use crate::sparse::SparseImage;
+    use crate::vbmeta::{Descriptor, HashDescriptor, VbMeta};
     use std::cell::RefCell;
+    use std::collections::VecDeque;
     use std::error::Error;
     use std::fmt;
     use std::io;
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
 
     extern crate double;
     use self::double::Mock;
@@ -25,6 +55,7 @@ mod tests {
     }
 
     impl fmt::Display for CloneableError {
+        #[allow(deprecated)]
         fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
             write!(f, "{}", self.description())
         }
@@ -83,6 +114,87 @@ mod tests {
         }
     }
 
+    // Accepts every write and answers the reads with `replies` in order.
+    fn script(mock: &MockUsb, replies: &[&'static str]) {
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        let replies: RefCell<VecDeque<_>> = RefCell::new(replies.iter().copied().collect());
+        mock.read.use_closure(Box::new(move |buf| {
+            let reply = replies.borrow_mut().pop_front().unwrap_or("FAIL");
+            unsafe { reply.as_ptr().copy_to_nonoverlapping(buf, reply.len()) };
+            Ok(reply.len())
+        }));
+    }
+
+    // Accepts every write, but fails every read, like a broken link.
+    fn broken(mock: &MockUsb) {
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        mock.read.use_closure(Box::new(|_| {
+            Err(CloneableError {
+                kind: io::ErrorKind::BrokenPipe,
+                description: "broken pipe".to_owned(),
+            })
+        }));
+    }
+
+    fn written(mock: &MockUsb) -> Vec<String> {
+        mock.write
+            .calls()
+            .into_iter()
+            .map(|c| String::from_utf8_lossy(&c).into_owned())
+            .collect()
+    }
+
+    // What the flashing helpers send, told apart from image data by prefix.
+    const COMMANDS: &[&str] = &[
+        "getvar:",
+        "download:",
+        "flash:",
+        "erase:",
+        "reboot",
+        "update-super:",
+        "resize-logical-partition:",
+        "snapshot-update:",
+        "oem ",
+    ];
+
+    fn commands(mock: &MockUsb) -> Vec<String> {
+        written(mock)
+            .into_iter()
+            .filter(|w| COMMANDS.iter().any(|c| w.starts_with(c)))
+            .collect()
+    }
+
+    // A scratch directory for image files, removed again when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("fastboot-{name}-{}", std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn write(&self, file: &str, contents: impl AsRef<[u8]>) {
+            let path = self.0.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+
+    impl Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
     #[test]
     fn test_getvar() {
         let mut mock = MockUsb::default();
@@ -136,6 +248,40 @@ mod tests {
         assert_eq!(Err("".to_owned()), mock.download(&vec![0; 1024]));
     }
 
+    #[test]
+    fn test_stage() {
+        let mut mock = MockUsb::default();
+
+        script(&mock, &["DATA00000004", "INFOkey accepted", "OKAY"]);
+        assert_eq!(Ok(()), mock.stage(b"keys"));
+
+        script(&mock, &["DATA00000003", "FAILtoo large"]);
+        assert_eq!(
+            Err("DATA: Expected 4 bytes, got 3".to_owned()),
+            mock.stage(b"keys")
+        );
+        assert_eq!(Err("too large".to_owned()), mock.stage(b"keys"));
+
+        let mut sent = Vec::new();
+        let data = vec![0x5a; 3 * 1024 * 1024];
+        script(&mock, &["DATA00300000", "OKAY"]);
+        assert_eq!(
+            Ok(()),
+            mock.download_from(&data[..], data.len(), |n| sent.push(n))
+        );
+        assert_eq!(sent, [1 << 20, 2 << 20, 3 << 20]);
+        assert_eq!(
+            written(&mock)[..5],
+            [
+                "stage:00000004",
+                "keys",
+                "stage:00000004",
+                "stage:00000004",
+                "download:00300000",
+            ]
+        );
+    }
+
     #[test]
     fn test_flash() {
         let mut mock = MockUsb::default();
@@ -159,6 +305,806 @@ mod tests {
         assert_eq!(Err("".to_owned()), mock.flash("something"));
     }
 
+    #[test]
+    fn test_gpt() {
+        let gpt = Gpt::from_layout("name=boot,size=1M;name=rootfs,size=3M", 8 << 20, 512).unwrap();
+
+        let mut mock = MockUsb::default();
+        script(&mock, &["OKAY0x100000", "OKAY0x300000"]);
+        assert_eq!(Ok(()), mock.check_gpt(&gpt));
+        script(&mock, &["OKAY0x100000", "FAILno such partition"]);
+        assert_eq!(
+            Err("Layout differs: rootfs is missing".to_owned()),
+            mock.check_gpt(&gpt)
+        );
+        script(&mock, &["OKAY0x200000", "OKAY0x300000"]);
+        assert_eq!(
+            Err("Layout differs: boot is 2097152 bytes, not 1048576".to_owned()),
+            mock.check_gpt(&gpt)
+        );
+        script(&mock, &["OKAY0x100000", "OKAYbig"]);
+        assert!(mock
+            .check_gpt(&gpt)
+            .unwrap_err()
+            .contains("expected a size"));
+        broken(&mock);
+        assert!(!mock.check_gpt(&gpt).unwrap_err().contains("missing"));
+
+        let mut mock = MockUsb::default();
+        script(&mock, &["DATA00004400", "OKAY", "OKAY"]);
+        assert_eq!(Ok(()), mock.flash_gpt(&gpt));
+        let written = written(&mock);
+        assert_eq!(written[0], "download:00004400");
+        assert_eq!(written.last().unwrap(), "flash:gpt");
+    }
+
+    #[test]
+    fn test_update_super_image() {
+        let mut md = LpMetadata::new(16 << 20, 4096, 2);
+        md.add_partition("system_a", 0, "default").unwrap();
+        let super_empty = md.to_empty_image().unwrap();
+
+        let mut mock = MockUsb::default();
+        assert!(mock
+            .update_super_image("super", &super_empty, &[("system_a", 16 << 20)], false)
+            .is_err());
+        assert!(mock
+            .update_super_image("super", &super_empty, &[("vendor_a", 4096)], false)
+            .is_err());
+        assert!(written(&mock).is_empty());
+
+        // Geometry, header and one partition, group and block device each
+        assert_eq!(super_empty.len(), 4096 + 128 + 52 + 48 + 64);
+        script(&mock, &["DATA00001124", "OKAY", "OKAY"]);
+        assert_eq!(
+            Ok(()),
+            mock.update_super_image("super", &super_empty, &[("system_a", 8 << 20)], true)
+        );
+        let written = written(&mock);
+        assert_eq!(written[0], "download:00001124");
+        assert_eq!(written.last().unwrap(), "update-super:super:wipe");
+    }
+
+    #[test]
+    fn test_logical_partitions() {
+        let mut mock = MockUsb::default();
+
+        script(&mock, &["OKAYyes", "OKAY", "OKAY", "OKAY", "OKAY"]);
+        assert_eq!(Ok(true), mock.is_userspace());
+        assert_eq!(Ok(()), mock.create_logical_partition("system_b", 4096));
+        assert_eq!(Ok(()), mock.delete_logical_partition("product_b"));
+        assert_eq!(Ok(()), mock.update_super("super", true));
+        assert_eq!(Ok(()), mock.update_super("super", false));
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:is-userspace",
+                "create-logical-partition:system_b:4096",
+                "delete-logical-partition:product_b",
+                "update-super:super:wipe",
+                "update-super:super",
+            ]
+        );
+
+        // Bootloaders may not know the variables, but a broken link is no
+        // answer.
+        let mut mock = MockUsb::default();
+        script(
+            &mock,
+            &["FAILunknown variable", "FAILunknown variable", "OKAYsys"],
+        );
+        assert_eq!(Ok(false), mock.is_userspace());
+        assert_eq!(Ok("super".to_owned()), mock.super_partition_name());
+        assert_eq!(Ok("sys".to_owned()), mock.super_partition_name());
+        broken(&mock);
+        assert!(mock.is_userspace().is_err());
+        assert!(mock.super_partition_name().is_err());
+
+        let mut mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "OKAYnone",
+                "OKAYyes",
+                "OKAY",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        assert_eq!(Ok(()), mock.flash_image("system_a", b"data"));
+        assert_eq!(Ok(()), mock.flash_image("boot_a", b"data"));
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:snapshot-update-status",
+                "getvar:is-logical:system_a",
+                "resize-logical-partition:system_a:4",
+                "getvar:max-download-size",
+                "download:00000004",
+                "data",
+                "flash:system_a",
+                "getvar:snapshot-update-status",
+                "getvar:is-logical:boot_a",
+                "getvar:max-download-size",
+                "download:00000004",
+                "data",
+                "flash:boot_a",
+            ]
+        );
+
+        // A transport error is not taken for a partition that is not logical.
+        let mut mock = MockUsb::default();
+        mock.write.use_closure(Box::new(|buf| Ok(buf.len())));
+        let replies = RefCell::new(0);
+        mock.read.use_closure(Box::new(move |buf| {
+            *replies.borrow_mut() += 1;
+            match *replies.borrow() {
+                1 => {
+                    unsafe { b"OKAYnone".as_ptr().copy_to_nonoverlapping(buf, 8) };
+                    Ok(8)
+                }
+                _ => Err(CloneableError {
+                    kind: io::ErrorKind::BrokenPipe,
+                    description: "broken pipe".to_owned(),
+                }),
+            }
+        }));
+        assert!(mock.flash_image("system_a", b"data").is_err());
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:snapshot-update-status",
+                "getvar:is-logical:system_a"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_vbmeta() {
+        use sha2::{Digest, Sha256};
+
+        let vbmeta = VbMeta {
+            required_libavb_version: (1, 0),
+            algorithm: 0,
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release: String::new(),
+            public_key: Vec::new(),
+            descriptors: vec![Descriptor::Hash(HashDescriptor {
+                partition_name: "boot".to_owned(),
+                image_size: 4,
+                hash_algorithm: "sha256".to_owned(),
+                salt: b"salt".to_vec(),
+                digest: Sha256::digest(b"saltdata").to_vec(),
+                flags: 0,
+            })],
+            hash_ok: true,
+        };
+
+        let mut mock = MockUsb::default();
+        assert!(mock.flash_verified("boot_a", b"date", &vbmeta).is_err());
+        assert!(written(&mock).is_empty());
+        script(
+            &mock,
+            &[
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        assert_eq!(Ok(()), mock.flash_verified("boot_a", b"data", &vbmeta));
+        assert_eq!(written(&mock).last().unwrap(), "flash:boot_a");
+
+        let mut mock = MockUsb::default();
+        assert!(mock.flash_vbmeta("vbmeta_a", b"data", 2).is_err());
+        let mut image = b"AVB0".to_vec();
+        image.resize(256, 0);
+        image[123] = 4;
+        script(
+            &mock,
+            &[
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000100",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        assert_eq!(Ok(()), mock.flash_vbmeta("vbmeta_a", &image, 3));
+        let calls = mock.write.calls();
+        let data = calls.iter().find(|c| c.starts_with(b"AVB0")).unwrap();
+        assert_eq!(data[120..124], [0, 0, 0, 7]);
+        assert_eq!(written(&mock).last().unwrap(), "flash:vbmeta_a");
+    }
+
+    #[test]
+    fn test_flashall() {
+        let dir = TempDir::new("flashall");
+        let mut md = LpMetadata::new(16 << 20, 4096, 2);
+        md.add_partition("system_a", 0, "default").unwrap();
+        md.add_partition("system_b", 0, "default").unwrap();
+        dir.write("super_empty.img", md.to_empty_image().unwrap());
+        dir.write("boot.img", b"boot");
+        // Sparse images are resized to, and checked against super with, their
+        // expanded size, and split when larger than max-download-size.
+        let system: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8).collect();
+        let system = SparseImage::from_raw(&system, 4096).unwrap().to_bytes();
+        dir.write("system.img", system);
+        dir.write("android-info.txt", "require board=lynx\n");
+
+        let flashall = FlashAll::new(&*dir);
+        let plan = flashall.plan().unwrap();
+        assert_eq!(plan.bootloader, ["boot"]);
+        assert_eq!(plan.fastbootd, ["system"]);
+
+        let mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "OKAYlynx",
+                "FAILunknown variable",
+                "OKAYyes",
+                "OKAYa",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+                "OKAYno",
+                "OKAY",
+                "OKAYsuper",
+                "OKAYyes",
+                "OKAYa",
+                "DATA00001158",
+                "OKAY",
+                "OKAY",
+                "OKAYyes",
+                "OKAYa",
+                "FAILunknown variable",
+                "OKAYyes",
+                "OKAY",
+                "OKAY0x1100",
+                "DATA00001034",
+                "OKAY",
+                "OKAY",
+                "DATA00001034",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        assert!(flashall.run(mock.clone(), || Ok(mock.clone())).is_ok());
+        assert_eq!(
+            commands(&mock),
+            [
+                "getvar:product",
+                "getvar:snapshot-update-status",
+                "getvar:has-slot:boot",
+                "getvar:current-slot",
+                "getvar:snapshot-update-status",
+                "getvar:is-logical:boot_a",
+                "getvar:max-download-size",
+                "download:00000004",
+                "flash:boot_a",
+                "getvar:is-userspace",
+                "reboot-fastboot",
+                "getvar:super-partition-name",
+                "getvar:has-slot:system",
+                "getvar:current-slot",
+                "download:00001158",
+                "update-super:super",
+                "getvar:has-slot:system",
+                "getvar:current-slot",
+                "getvar:snapshot-update-status",
+                "getvar:is-logical:system_a",
+                "resize-logical-partition:system_a:8192",
+                "getvar:max-download-size",
+                "download:00001034",
+                "flash:system_a",
+                "download:00001034",
+                "flash:system_a",
+            ]
+        );
+
+        dir.write("android-info.txt", "require board=other\n");
+        let mock = MockUsb::default();
+        script(&mock, &["OKAYlynx"]);
+        assert_eq!(
+            Err("Device board is lynx, but the image requires other".to_owned()),
+            flashall.run(mock.clone(), || Ok(mock.clone())).map(|_| ())
+        );
+        assert_eq!(written(&mock), ["getvar:product"]);
+
+        // A broken link is no missing partition.
+        dir.write("android-info.txt", "reject partition-exists=vendor_dlkm\n");
+        let mock = MockUsb::default();
+        broken(&mock);
+        let replies = RefCell::new(0);
+        mock.read.use_closure(Box::new(move |buf| {
+            *replies.borrow_mut() += 1;
+            match *replies.borrow() {
+                1 => {
+                    unsafe { b"OKAYlynx".as_ptr().copy_to_nonoverlapping(buf, 8) };
+                    Ok(8)
+                }
+                _ => Err(CloneableError {
+                    kind: io::ErrorKind::BrokenPipe,
+                    description: "broken pipe".to_owned(),
+                }),
+            }
+        }));
+        let result = flashall.run(mock.clone(), || Ok(mock.clone()));
+        assert!(result.is_err());
+        assert_eq!(
+            written(&mock),
+            ["getvar:product", "getvar:partition-size:vendor_dlkm"]
+        );
+    }
+
+    #[test]
+    fn test_flashall_zip() {
+        let dir = TempDir::new("update");
+        let path = dir.join("update.zip");
+        dir.write(
+            "update.zip",
+            crate::zip::tests::zip(&[
+                ("android-info.txt", b"require board=lynx\n"),
+                ("boot.img", b"boot"),
+            ]),
+        );
+        let update = FlashAll::open_zip(&path).unwrap();
+        assert_eq!(update.plan().unwrap().bootloader, ["boot"]);
+
+        let mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "OKAYlynx",
+                "FAILunknown variable",
+                "OKAYno",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        assert!(update.run(mock.clone(), || Ok(mock.clone())).is_ok());
+        assert_eq!(
+            commands(&mock),
+            [
+                "getvar:product",
+                "getvar:snapshot-update-status",
+                "getvar:has-slot:boot",
+                "getvar:snapshot-update-status",
+                "getvar:is-logical:boot",
+                "getvar:max-download-size",
+                "download:00000004",
+                "flash:boot",
+            ]
+        );
+
+        // A pending update fails the run, unless it is to be cancelled.
+        let mut update = update;
+        let mock = MockUsb::default();
+        script(&mock, &["OKAYlynx", "OKAYmerging"]);
+        assert!(update.run(mock.clone(), || Ok(mock.clone())).is_err());
+        assert!(!commands(&mock).iter().any(|w| w.starts_with("flash:")));
+        update.cancel_snapshot = true;
+        let mock = MockUsb::default();
+        script(&mock, &["OKAYlynx", "OKAYmerging", "OKAY"]);
+        assert!(update.run(mock.clone(), || Ok(mock.clone())).is_err());
+        assert!(commands(&mock).contains(&"snapshot-update:cancel".to_owned()));
+
+        // A corrupted image fails its download, before it is flashed.
+        let mut data = crate::zip::tests::zip(&[
+            ("android-info.txt", b"require board=lynx\n"),
+            ("boot.img", b"boot"),
+        ]);
+        let name = data.windows(8).position(|w| w == b"boot.img").unwrap();
+        data[name + 9] ^= 0x10;
+        dir.write("update.zip", data);
+        let update = FlashAll::open_zip(&path).unwrap();
+        let mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "OKAYlynx",
+                "FAILunknown variable",
+                "OKAYno",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000004",
+            ],
+        );
+        let result = update.run(mock.clone(), || Ok(mock.clone()));
+        assert!(result.unwrap_err().contains("CRC mismatch"));
+        assert!(!commands(&mock).iter().any(|w| w.starts_with("flash:")));
+
+        std::fs::remove_file(&path).unwrap();
+        assert!(FlashAll::open_zip(&path).is_err());
+    }
+
+    #[test]
+    fn test_fastboot_info() {
+        let dir = TempDir::new("info");
+        let mut md = LpMetadata::new(16 << 20, 4096, 2);
+        md.add_partition("system_a", 0, "default").unwrap();
+        let mut vbmeta = b"AVB0".to_vec();
+        vbmeta.resize(256, 0);
+        dir.write("super_empty.img", md.to_empty_image().unwrap());
+        dir.write("boot.img", b"boot");
+        dir.write("vbmeta.img", vbmeta);
+        dir.write("system.img", b"syst");
+        dir.write("android-info.txt", "require board=lynx\n");
+        dir.write(
+            "fastboot-info.txt",
+            "version 1\n\
+            flash boot\n\
+            flash --apply-vbmeta vbmeta\n\
+            reboot fastboot\n\
+            update-super\n\
+            flash system\n\
+            if-wipe erase userdata\n",
+        );
+
+        let mut flashall = FlashAll::new(&*dir);
+        flashall.vbmeta_flags = Some(3);
+        let info = flashall.fastboot_info().unwrap().unwrap();
+        let mut mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "OKAYyes",
+                "OKAYa",
+                "OKAYyes",
+                "OKAYa",
+                "OKAYsuper",
+                "OKAYyes",
+                "OKAYa",
+            ],
+        );
+        let steps: Vec<String> = flashall
+            .dry_run(&info, &mut mock)
+            .unwrap()
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(
+            steps,
+            [
+                "flash boot_a boot.img",
+                "flash vbmeta_a vbmeta.img (vbmeta flags 3)",
+                "reboot fastboot",
+                "update-super super",
+                "flash system_a system.img",
+            ]
+        );
+        assert!(written(&mock).iter().all(|w| w.starts_with("getvar:")));
+
+        let mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "OKAYlynx",
+                "FAILunknown variable",
+                "OKAYyes",
+                "OKAYa",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+                "OKAYyes",
+                "OKAYa",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "FAILunknown variable",
+                "DATA00000100",
+                "OKAY",
+                "OKAY",
+                "OKAY",
+                "OKAYsuper",
+                "DATA00001124",
+                "OKAY",
+                "OKAY",
+                "OKAYyes",
+                "OKAYa",
+                "FAILunknown variable",
+                "OKAYyes",
+                "OKAY",
+                "FAILunknown variable",
+                "DATA00000004",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        let mut reconnects = 0;
+        let reconnect = || {
+            reconnects += 1;
+            Ok(mock.clone())
+        };
+        assert!(flashall.run(mock.clone(), reconnect).is_ok());
+        assert_eq!(reconnects, 1);
+        let calls = mock.write.calls();
+        let vbmeta = calls.iter().find(|c| c.starts_with(b"AVB0")).unwrap();
+        assert_eq!(vbmeta[120..124], [0, 0, 0, 3]);
+        let sent: Vec<String> = commands(&mock)
+            .into_iter()
+            .filter(|w| !w.starts_with("getvar:"))
+            .collect();
+        assert_eq!(
+            sent,
+            [
+                "download:00000004",
+                "flash:boot_a",
+                "download:00000100",
+                "flash:vbmeta_a",
+                "reboot-fastboot",
+                "download:00001124",
+                "update-super:super",
+                "resize-logical-partition:system_a:4",
+                "download:00000004",
+                "flash:system_a",
+            ]
+        );
+
+        // A broken link is no bootloader without a super partition name.
+        dir.write("fastboot-info.txt", "version 1\nupdate-super\n");
+        let info = flashall.fastboot_info().unwrap().unwrap();
+        let mut mock = MockUsb::default();
+        broken(&mock);
+        assert!(flashall.dry_run(&info, &mut mock).is_err());
+    }
+
+    #[cfg(feature = "manifest")]
+    #[test]
+    fn test_manifest() {
+        use crate::manifest::Manifest;
+        use sha2::{Digest, Sha256};
+
+        let dir = TempDir::new("manifest");
+        let sha256: String = Sha256::digest(b"uboot")
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        let rootfs: Vec<u8> = (0..3 * 4096u32).map(|i| (i % 251) as u8).collect();
+        dir.write("out/u-boot.itb", b"uboot");
+        dir.write("out/rootfs.ext4", &rootfs);
+        dir.write(
+            "board.toml",
+            format!(
+                "required = [\"images\"]\n\
+                [partitions]\n\
+                uboot = {{ file = \"${{images}}/u-boot.itb\", sha256 = \"{sha256}\" }}\n\
+                rootfs = {{ file = \"${{images}}/rootfs.ext4\", sparse = true }}\n\
+                [[steps]]\nerase = \"env\"\noptional = true\n\
+                [[steps]]\nflash = \"uboot\"\n\
+                [[steps]]\nflash = \"rootfs\"\n\
+                [[steps]]\noem = \"format\"\n\
+                [[steps]]\nreboot = \"system\"\n"
+            ),
+        );
+
+        let mut manifest = Manifest::load(dir.join("board.toml")).unwrap();
+        manifest.set("images", "out");
+        let mock = MockUsb::default();
+        script(
+            &mock,
+            &[
+                "FAILunknown partition",
+                "DATA00000005",
+                "OKAY",
+                "OKAY",
+                "OKAY0x2000",
+                "DATA00001034",
+                "OKAY",
+                "OKAY",
+                "DATA00001040",
+                "OKAY",
+                "OKAY",
+                "DATA00001034",
+                "OKAY",
+                "OKAY",
+                "INFOformatting",
+                "OKAY",
+                "OKAY",
+            ],
+        );
+        let mut reports = Vec::new();
+        let report = |r: &crate::manifest::StepReport| {
+            reports.push((r.index, r.total, r.result.map(Result::is_ok)));
+        };
+        let result = manifest.run(mock.clone(), || Ok(mock.clone()), report);
+        assert!(result.is_ok());
+        assert_eq!(reports.len(), 10);
+        assert_eq!(reports[0], (1, 5, None));
+        assert_eq!(reports[1], (1, 5, Some(false)));
+        assert!(reports[2..].iter().all(|r| r.2 != Some(false)));
+        assert_eq!(
+            commands(&mock),
+            [
+                "erase:env",
+                "download:00000005",
+                "flash:uboot",
+                "getvar:max-download-size",
+                "download:00001034",
+                "flash:rootfs",
+                "download:00001040",
+                "flash:rootfs",
+                "download:00001034",
+                "flash:rootfs",
+                "oem format",
+                "reboot",
+            ]
+        );
+
+        // Nothing is sent when a file does not match its checksum.
+        dir.write("out/u-boot.itb", b"u-boot");
+        let mock = MockUsb::default();
+        let result = manifest.run(mock.clone(), || Ok(mock.clone()), |_| {});
+        assert!(result.unwrap_err().contains("SHA-256"));
+        assert!(mock.write.calls().is_empty());
+
+        // Nor when it changes after the files were verified.
+        dir.write("out/u-boot.itb", b"uboot");
+        let mock = MockUsb::default();
+        let report = |r: &crate::manifest::StepReport| {
+            if r.index == 1 && r.result.is_none() {
+                dir.write("out/u-boot.itb", b"u-boot");
+            }
+        };
+        let result = manifest.run(mock.clone(), || Ok(mock.clone()), report);
+        assert!(result.unwrap_err().contains("SHA-256"));
+        assert_eq!(commands(&mock), ["erase:env"]);
+    }
+
+    #[test]
+    fn test_snapshot_update() {
+        let mut mock = MockUsb::default();
+
+        script(&mock, &["OKAYmerging", "OKAY", "OKAY"]);
+        assert_eq!(
+            Ok(SnapshotUpdateStatus::Merging),
+            mock.snapshot_update_status()
+        );
+        assert_eq!(Ok(()), mock.snapshot_update(SnapshotUpdateAction::Cancel));
+        assert_eq!(Ok(()), mock.snapshot_update(SnapshotUpdateAction::Merge));
+
+        script(&mock, &["OKAYsnapshotted", "OKAYsnapshotted", "OKAY"]);
+        assert!(mock.flash_image("system_a", b"data").is_err());
+        assert_eq!(Ok(()), mock.check_snapshot_update(true));
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:snapshot-update-status",
+                "snapshot-update:cancel",
+                "snapshot-update:merge",
+                "getvar:snapshot-update-status",
+                "getvar:snapshot-update-status",
+                "snapshot-update:cancel",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fetch() {
+        let mut mock = MockUsb::default();
+
+        script(
+            &mock,
+            &[
+                "OKAY0xa",
+                "OKAY0x8",
+                "DATA00000008",
+                "abcd",
+                "efgh",
+                "OKAY",
+                "DATA00000002",
+                "ij",
+                "OKAY",
+            ],
+        );
+        let mut out = Vec::new();
+        assert_eq!(Ok(10), mock.fetch("boot_a", 0, None, &mut out));
+        assert_eq!(out, b"abcdefghij");
+
+        script(
+            &mock,
+            &["FAIL", "DATA00000004", "efgh", "OKAY", "FAIL", "FAILdenied"],
+        );
+        let mut out = Vec::new();
+        assert_eq!(Ok(4), mock.fetch("vendor_boot_a", 4, Some(4), &mut out));
+        assert_eq!(out, b"efgh");
+        assert_eq!(
+            Err("denied".to_owned()),
+            mock.fetch("userdata", 0, Some(4), &mut out)
+        );
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:partition-size:boot_a",
+                "getvar:max-fetch-size",
+                "fetch:boot_a:0x00000000:0x00000008",
+                "fetch:boot_a:0x00000008:0x00000002",
+                "getvar:max-fetch-size",
+                "fetch:vendor_boot_a:0x00000004:0x00000004",
+                "getvar:max-fetch-size",
+                "fetch:userdata:0x00000000:0x00000004",
+            ]
+        );
+
+        // Data of an unexpected size is drained, so the next command works.
+        let mut mock = MockUsb::default();
+        script(&mock, &["OKAY0x4", "DATA00000002", "ab", "OKAY", "OKAYyes"]);
+        let mut out = Vec::new();
+        assert!(mock.fetch("misc", 0, Some(4), &mut out).is_err());
+        assert!(out.is_empty());
+        assert_eq!(Ok(true), mock.is_userspace());
+
+        // A broken link is not taken for a device without a fetch limit.
+        let mut mock = MockUsb::default();
+        broken(&mock);
+        assert!(mock.fetch("misc", 0, Some(4), &mut Vec::new()).is_err());
+        assert_eq!(written(&mock), ["getvar:max-fetch-size"]);
+    }
+
+    #[test]
+    fn test_gsi() {
+        let mut mock = MockUsb::default();
+
+        script(
+            &mock,
+            &[
+                "OKAY",
+                "FAILno GSI installed",
+                "INFONot running",
+                "OKAY",
+                "INFORunning active DSU: dsu",
+                "OKAY",
+                "OKAYRunning",
+                "INFOBroken",
+                "OKAY",
+            ],
+        );
+        assert_eq!(Ok(()), mock.gsi_disable());
+        assert_eq!(Err("no GSI installed".to_owned()), mock.gsi_wipe());
+        assert_eq!(Ok(GsiStatus::NotRunning), mock.gsi_status());
+        assert_eq!(
+            Ok(GsiStatus::Running(Some("dsu".to_owned()))),
+            mock.gsi_status()
+        );
+        assert_eq!(Ok(GsiStatus::Running(None)), mock.gsi_status());
+        assert!(mock.gsi_status().is_err());
+        assert_eq!(
+            written(&mock),
+            [
+                "gsi:disable",
+                "gsi:wipe",
+                "gsi:status",
+                "gsi:status",
+                "gsi:status",
+                "gsi:status",
+            ]
+        );
+    }
+
     #[test]
     fn test_erase() {
         let mut mock = MockUsb::default();
@@ -194,4 +1140,146 @@ mod tests {
         }));
         assert_eq!(Ok(()), mock.reboot());
     }
+
+    #[test]
+    fn test_boot_image() {
+        let mut mock = MockUsb::default();
+
+        let mut img = BootImage::new(2, b"kernel".to_vec(), b"ramdisk".to_vec());
+        img.set_cmdline("console=ttyS0");
+        let data = img.to_bytes().unwrap();
+        script(&mock, &["DATA00001800", "OKAY", "OKAY"]);
+        assert_eq!(Ok(()), mock.boot_image(&img));
+        let calls = mock.write.calls();
+        assert_eq!(calls[0], b"download:00001800");
+        assert_eq!(calls[1], data);
+        assert_eq!(calls[2], b"boot");
+    }
+
+    #[test]
+    fn test_reboot_to() {
+        let mut mock = MockUsb::default();
+
+        script(&mock, &["OKAY", "OKAY", "OKAY", "OKAY", "FAILunknown"]);
+        assert_eq!(Ok(()), mock.reboot_bootloader());
+        assert_eq!(Ok(()), mock.reboot_to(RebootTarget::Recovery));
+        assert_eq!(Ok(()), mock.reboot_to(RebootTarget::Fastboot));
+        assert_eq!(Ok(()), mock.reboot_to(RebootTarget::PowerDown));
+        assert_eq!(
+            Err("unknown".to_owned()),
+            mock.reboot_to(RebootTarget::Custom("edl".to_owned()))
+        );
+        assert_eq!(
+            written(&mock),
+            [
+                "reboot-bootloader",
+                "reboot-recovery",
+                "reboot-fastboot",
+                "powerdown",
+                "reboot:edl",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_slots() {
+        let mut mock = MockUsb::default();
+
+        script(&mock, &["OKAY"]);
+        assert_eq!(Ok(()), mock.set_active(Slot::B));
+        assert_eq!(written(&mock), ["set_active:b"]);
+
+        let mut mock = MockUsb::default();
+        script(&mock, &["OKAY_a", "OKAY2", "OKAYyes", "OKAYno", "OKAY3"]);
+        assert_eq!(Ok(Slot::A), mock.current_slot());
+        assert_eq!(Ok(2), mock.slot_count());
+        assert_eq!(Ok(true), mock.slot_successful(Slot::A));
+        assert_eq!(Ok(false), mock.slot_unbootable(Slot::B));
+        assert_eq!(Ok(3), mock.slot_retry_count(Slot::B));
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:current-slot",
+                "getvar:slot-count",
+                "getvar:slot-successful:a",
+                "getvar:slot-unbootable:b",
+                "getvar:slot-retry-count:b",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_slot_partitions() {
+        let mut mock = MockUsb::default();
+
+        script(
+            &mock,
+            &[
+                "FAIL", "OKAYyes", "OKAYa", "OKAY", "OKAYnone", "OKAYno", "OKAY",
+            ],
+        );
+        assert_eq!(Ok(()), mock.flash_slot("boot", SlotSelect::Other));
+        assert_eq!(Ok(()), mock.flash_slot("gpt", SlotSelect::All));
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:snapshot-update-status",
+                "getvar:has-slot:boot",
+                "getvar:current-slot",
+                "flash:boot_b",
+                "getvar:snapshot-update-status",
+                "getvar:has-slot:gpt",
+                "flash:gpt",
+            ]
+        );
+
+        let mut mock = MockUsb::default();
+        script(&mock, &["OKAYyes", "OKAY", "OKAY"]);
+        assert_eq!(Ok(()), mock.erase_slot("system", SlotSelect::All));
+        assert_eq!(
+            written(&mock),
+            ["getvar:has-slot:system", "erase:system_a", "erase:system_b"]
+        );
+
+        // Transport errors are not taken for a bootloader without slots.
+        let mut mock = MockUsb::default();
+        broken(&mock);
+        assert!(mock.slot_partitions("boot", SlotSelect::Current).is_err());
+        assert_eq!(Ok(SlotSelect::Slot(Slot::B)), "b".parse());
+        assert!("c".parse::<SlotSelect>().is_err());
+    }
+
+    #[test]
+    fn test_lock_state() {
+        let mut mock = MockUsb::default();
+
+        script(&mock, &["OKAYno", "OKAYyes"]);
+        assert_eq!(Ok(LockState::Locked), mock.lock_state());
+        assert_eq!(Ok(true), mock.secure());
+
+        script(
+            &mock,
+            &[
+                "INFOPlease confirm on the device",
+                "INFOUnlocking...",
+                "OKAY",
+                "INFOget_unlock_ability: 1",
+                "OKAY",
+                "FAILnot allowed",
+            ],
+        );
+        assert_eq!(Ok(()), mock.flashing_unlock());
+        assert_eq!(Ok(true), mock.unlock_ability());
+        assert_eq!(Err("not allowed".to_owned()), mock.flashing_lock_critical());
+        assert_eq!(
+            written(&mock),
+            [
+                "getvar:unlocked",
+                "getvar:secure",
+                "flashing unlock",
+                "flashing get_unlock_ability",
+                "flashing lock_critical",
+            ]
+        );
+    }
 }
@@ -0,0 +1,746 @@
+//! Android logical partition (LP) metadata, as found in `super` partitions.
+//!
+//! See `system/core/fs_mgr/liblp/include/liblp/metadata_format.h` in AOSP. A
+//! `super` partition starts with 4 KiB reserved for the partition table of
+//! the device, followed by the geometry, its backup, and then all metadata
+//! slots and their backups. The geometry tells how large and how many the
+//! slots are; each slot holds a header and four tables: partitions, extents,
+//! partition groups and block devices. `super_empty.img` only consists of the
+//! geometry and a single copy of the metadata.
+
+use sha2::{Digest, Sha256};
+
+use crate::bytes::{put_cstr, Reader};
+
+pub const LP_GEOMETRY_MAGIC: u32 = 0x616c_4467;
+pub const LP_HEADER_MAGIC: u32 = 0x414c_5030;
+/// All sizes and offsets in extents are counted in sectors of this size.
+pub const LP_SECTOR_SIZE: u64 = 512;
+
+const RESERVED_BYTES: usize = 4096;
+const GEOMETRY_SIZE: usize = 4096;
+const GEOMETRY_STRUCT_SIZE: usize = 52;
+const MAJOR_VERSION: u16 = 10;
+const HEADER_V1_0_SIZE: usize = 128;
+const HEADER_V1_2_SIZE: usize = 256;
+const NAME_SIZE: usize = 36;
+const PARTITION_SIZE: usize = 52;
+const EXTENT_SIZE: usize = 24;
+const GROUP_SIZE: usize = 48;
+const BLOCK_DEVICE_SIZE: usize = 64;
+
+pub const PARTITION_ATTR_READONLY: u32 = 1 << 0;
+pub const PARTITION_ATTR_SLOT_SUFFIXED: u32 = 1 << 1;
+pub const PARTITION_ATTR_UPDATED: u32 = 1 << 2;
+pub const PARTITION_ATTR_DISABLED: u32 = 1 << 3;
+
+/// Where the metadata slots are and how large they are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    pub metadata_max_size: u32,
+    pub metadata_slot_count: u32,
+    pub logical_block_size: u32,
+}
+
+/// What an extent maps to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentTarget {
+    /// A range of a block device, starting at a physical sector.
+    Linear { block_device: u32, sector: u64 },
+    /// Zeros.
+    Zero,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub num_sectors: u64,
+    pub target: ExtentTarget,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    pub name: String,
+    /// `PARTITION_ATTR_*` flags
+    pub attributes: u32,
+    /// Index into [`LpMetadata::groups`]
+    pub group: u32,
+    pub extents: Vec<Extent>,
+}
+
+impl Partition {
+    /// The size of the partition in bytes, `u64::MAX` if that overflows.
+    pub fn size(&self) -> u64 {
+        self.sectors()
+            .and_then(|s| s.checked_mul(LP_SECTOR_SIZE))
+            .unwrap_or(u64::MAX)
+    }
+
+    fn sectors(&self) -> Option<u64> {
+        self.extents
+            .iter()
+            .try_fold(0u64, |sum, e| sum.checked_add(e.num_sectors))
+    }
+}
+
+/// Partitions in a group share a size limit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub flags: u32,
+    /// 0 means unlimited.
+    pub maximum_size: u64,
+}
+
+/// A physical partition that logical partitions are mapped onto.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockDevice {
+    pub partition_name: String,
+    /// The first sector usable by logical partitions, i.e. after the metadata.
+    pub first_logical_sector: u64,
+    pub alignment: u32,
+    pub alignment_offset: u32,
+    pub size: u64,
+    pub flags: u32,
+}
+
+/// One copy of LP metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LpMetadata {
+    pub geometry: Geometry,
+    pub minor_version: u16,
+    /// Header flags (minor version 2 and later)
+    pub flags: u32,
+    pub partitions: Vec<Partition>,
+    pub groups: Vec<Group>,
+    pub block_devices: Vec<BlockDevice>,
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn table(r: &mut Reader) -> Result<(usize, usize, usize), String> {
+    Ok((
+        r.u32_le()? as usize,
+        r.u32_le()? as usize,
+        r.u32_le()? as usize,
+    ))
+}
+
+impl Geometry {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader::new(data);
+        if r.u32_le()? != LP_GEOMETRY_MAGIC {
+            return Err("Not LP metadata geometry".to_owned());
+        }
+        let struct_size = r.u32_le()? as usize;
+        if struct_size != GEOMETRY_STRUCT_SIZE {
+            return Err(format!("Unsupported geometry size {struct_size}"));
+        }
+        let checksum: [u8; 32] = r.array()?;
+        let g = Geometry {
+            metadata_max_size: r.u32_le()?,
+            metadata_slot_count: r.u32_le()?,
+            logical_block_size: r.u32_le()?,
+        };
+        if g.to_struct()[8..40] != checksum {
+            return Err("Geometry checksum mismatch".to_owned());
+        }
+        if g.metadata_slot_count == 0
+            || !(g.metadata_max_size as u64).is_multiple_of(LP_SECTOR_SIZE)
+        {
+            return Err("Invalid geometry".to_owned());
+        }
+        Ok(g)
+    }
+
+    fn to_struct(self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(GEOMETRY_STRUCT_SIZE);
+        out.extend(LP_GEOMETRY_MAGIC.to_le_bytes());
+        out.extend((GEOMETRY_STRUCT_SIZE as u32).to_le_bytes());
+        out.extend([0; 32]);
+        out.extend(self.metadata_max_size.to_le_bytes());
+        out.extend(self.metadata_slot_count.to_le_bytes());
+        out.extend(self.logical_block_size.to_le_bytes());
+        let checksum = sha256(&out);
+        out[8..40].copy_from_slice(&checksum);
+        out
+    }
+
+    /// The geometry padded to its reserved size.
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut out = self.to_struct();
+        out.resize(GEOMETRY_SIZE, 0);
+        out
+    }
+}
+
+impl LpMetadata {
+    /// Creates empty metadata for a `super` partition of `device_size`
+    /// bytes, with a `default` group and 1 MiB alignment, like `lpmake`.
+    pub fn new(device_size: u64, metadata_max_size: u32, metadata_slot_count: u32) -> Self {
+        let alignment = 1 << 20;
+        let metadata = (RESERVED_BYTES + 2 * GEOMETRY_SIZE) as u64
+            + 2 * metadata_slot_count as u64 * metadata_max_size as u64;
+        LpMetadata {
+            geometry: Geometry {
+                metadata_max_size,
+                metadata_slot_count,
+                logical_block_size: 4096,
+            },
+            minor_version: 0,
+            flags: 0,
+            partitions: Vec::new(),
+            groups: vec![Group {
+                name: "default".to_owned(),
+                flags: 0,
+                maximum_size: 0,
+            }],
+            block_devices: vec![BlockDevice {
+                partition_name: "super".to_owned(),
+                first_logical_sector: metadata.next_multiple_of(alignment as u64) / LP_SECTOR_SIZE,
+                alignment,
+                alignment_offset: 0,
+                size: device_size,
+                flags: 0,
+            }],
+        }
+    }
+
+    /// Adds a partition group limited to `maximum_size` bytes.
+    pub fn add_group(&mut self, name: &str, maximum_size: u64) -> Result<(), String> {
+        if self.groups.iter().any(|g| g.name == name) {
+            return Err(format!("{name}: group exists"));
+        }
+        self.groups.push(Group {
+            name: name.to_owned(),
+            flags: 0,
+            maximum_size,
+        });
+        Ok(())
+    }
+
+    /// Parses `super_empty.img` or the beginning of a raw (not sparse)
+    /// `super` image, using the first metadata slot.
+    pub fn parse_image(data: &[u8]) -> Result<Self, String> {
+        // super_empty.img starts right with the geometry.
+        let (geometry_offset, metadata_offset) = match Geometry::parse(data) {
+            Ok(_) => (0, GEOMETRY_SIZE),
+            Err(_) => (RESERVED_BYTES, RESERVED_BYTES + 2 * GEOMETRY_SIZE),
+        };
+        let geometry = Geometry::parse(data.get(geometry_offset..).unwrap_or(&[]))?;
+        Self::parse(geometry, data.get(metadata_offset..).unwrap_or(&[]))
+    }
+
+    /// Parses a copy of metadata described by `geometry`.
+    pub fn parse(geometry: Geometry, data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader::new(data);
+        if r.u32_le()? != LP_HEADER_MAGIC {
+            return Err("Not LP metadata".to_owned());
+        }
+        let major = r.u16_le()?;
+        let minor_version = r.u16_le()?;
+        if major != MAJOR_VERSION || minor_version > 2 {
+            return Err(format!(
+                "Unsupported LP metadata version {major}.{minor_version}"
+            ));
+        }
+        let header_size = r.u32_le()? as usize;
+        let min_header_size = match minor_version {
+            2.. => HEADER_V1_2_SIZE,
+            _ => HEADER_V1_0_SIZE,
+        };
+        if header_size < min_header_size {
+            return Err(format!("Invalid LP metadata header size {header_size}"));
+        }
+        let header_checksum: [u8; 32] = r.array()?;
+        let tables_size = r.u32_le()? as usize;
+        let tables_checksum: [u8; 32] = r.array()?;
+        let partitions = table(&mut r)?;
+        let extents = table(&mut r)?;
+        let groups = table(&mut r)?;
+        let block_devices = table(&mut r)?;
+        let flags = match minor_version {
+            2.. => r.u32_le()?,
+            _ => 0,
+        };
+
+        let mut header = data
+            .get(..header_size)
+            .ok_or_else(|| "LP metadata header is truncated".to_owned())?
+            .to_vec();
+        header[12..44].fill(0);
+        if sha256(&header) != header_checksum {
+            return Err("LP metadata header checksum mismatch".to_owned());
+        }
+        let tables = data
+            .get(header_size..)
+            .and_then(|t| t.get(..tables_size))
+            .ok_or_else(|| "LP metadata tables are truncated".to_owned())?;
+        if sha256(tables) != tables_checksum {
+            return Err("LP metadata tables checksum mismatch".to_owned());
+        }
+
+        let entries = |(offset, num, size): (usize, usize, usize), min: usize| {
+            let end = num
+                .checked_mul(size)
+                .and_then(|len| len.checked_add(offset))
+                .filter(|&end| size >= min && end <= tables.len())
+                .ok_or_else(|| "Invalid LP metadata table".to_owned())?;
+            Ok::<_, String>(tables[offset..end].chunks(size))
+        };
+
+        let mut all_extents = Vec::new();
+        for e in entries(extents, EXTENT_SIZE)? {
+            let mut r = Reader::new(e);
+            let num_sectors = r.u64_le()?;
+            let target_type = r.u32_le()?;
+            let target_data = r.u64_le()?;
+            let target_source = r.u32_le()?;
+            let target = match target_type {
+                0 => ExtentTarget::Linear {
+                    block_device: target_source,
+                    sector: target_data,
+                },
+                1 => ExtentTarget::Zero,
+                t => return Err(format!("Unknown extent target type {t}")),
+            };
+            all_extents.push(Extent {
+                num_sectors,
+                target,
+            });
+        }
+
+        let mut parts = Vec::new();
+        for p in entries(partitions, PARTITION_SIZE)? {
+            let mut r = Reader::new(p);
+            let name = r.cstr(NAME_SIZE)?;
+            let attributes = r.u32_le()?;
+            let first = r.u32_le()? as usize;
+            let num = r.u32_le()? as usize;
+            let group = r.u32_le()?;
+            let extents = all_extents
+                .get(first..first + num)
+                .ok_or_else(|| format!("{name}: extents out of bounds"))?
+                .to_vec();
+            let part = Partition {
+                name,
+                attributes,
+                group,
+                extents,
+            };
+            if part
+                .sectors()
+                .and_then(|s| s.checked_mul(LP_SECTOR_SIZE))
+                .is_none()
+            {
+                return Err(format!("{}: invalid size", part.name));
+            }
+            parts.push(part);
+        }
+
+        let mut grps = Vec::new();
+        for g in entries(groups, GROUP_SIZE)? {
+            let mut r = Reader::new(g);
+            grps.push(Group {
+                name: r.cstr(NAME_SIZE)?,
+                flags: r.u32_le()?,
+                maximum_size: r.u64_le()?,
+            });
+        }
+
+        let mut devs = Vec::new();
+        for d in entries(block_devices, BLOCK_DEVICE_SIZE)? {
+            let mut r = Reader::new(d);
+            let first_logical_sector = r.u64_le()?;
+            if first_logical_sector.checked_mul(LP_SECTOR_SIZE).is_none() {
+                return Err("Invalid LP metadata block device".to_owned());
+            }
+            let alignment = r.u32_le()?;
+            let alignment_offset = r.u32_le()?;
+            let size = r.u64_le()?;
+            devs.push(BlockDevice {
+                partition_name: r.cstr(NAME_SIZE)?,
+                first_logical_sector,
+                alignment,
+                alignment_offset,
+                size,
+                flags: r.u32_le()?,
+            });
+        }
+
+        if parts.iter().any(|p| p.group as usize >= grps.len()) {
+            return Err("Partition refers to an unknown group".to_owned());
+        }
+        Ok(LpMetadata {
+            geometry,
+            minor_version,
+            flags,
+            partitions: parts,
+            groups: grps,
+            block_devices: devs,
+        })
+    }
+
+    /// Serializes one copy of the metadata: the header and the tables.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut partitions = Vec::new();
+        let mut extents = Vec::new();
+        let mut index = 0u32;
+        for p in &self.partitions {
+            put_cstr(&mut partitions, &p.name, NAME_SIZE)?;
+            partitions.extend(p.attributes.to_le_bytes());
+            partitions.extend(index.to_le_bytes());
+            partitions.extend((p.extents.len() as u32).to_le_bytes());
+            partitions.extend(p.group.to_le_bytes());
+            for e in &p.extents {
+                let (target_type, data, source) = match e.target {
+                    ExtentTarget::Linear {
+                        block_device,
+                        sector,
+                    } => (0u32, sector, block_device),
+                    ExtentTarget::Zero => (1, 0, 0),
+                };
+                extents.extend(e.num_sectors.to_le_bytes());
+                extents.extend(target_type.to_le_bytes());
+                extents.extend(data.to_le_bytes());
+                extents.extend(source.to_le_bytes());
+            }
+            index += p.extents.len() as u32;
+        }
+        let mut groups = Vec::new();
+        for g in &self.groups {
+            put_cstr(&mut groups, &g.name, NAME_SIZE)?;
+            groups.extend(g.flags.to_le_bytes());
+            groups.extend(g.maximum_size.to_le_bytes());
+        }
+        let mut devices = Vec::new();
+        for d in &self.block_devices {
+            devices.extend(d.first_logical_sector.to_le_bytes());
+            devices.extend(d.alignment.to_le_bytes());
+            devices.extend(d.alignment_offset.to_le_bytes());
+            devices.extend(d.size.to_le_bytes());
+            put_cstr(&mut devices, &d.partition_name, NAME_SIZE)?;
+            devices.extend(d.flags.to_le_bytes());
+        }
+
+        let header_size = match self.minor_version {
+            2.. => HEADER_V1_2_SIZE,
+            _ => HEADER_V1_0_SIZE,
+        };
+        let tables = [&partitions[..], &extents, &groups, &devices].concat();
+        let mut out = Vec::with_capacity(header_size + tables.len());
+        out.extend(LP_HEADER_MAGIC.to_le_bytes());
+        out.extend(MAJOR_VERSION.to_le_bytes());
+        out.extend(self.minor_version.to_le_bytes());
+        out.extend((header_size as u32).to_le_bytes());
+        out.extend([0; 32]);
+        out.extend((tables.len() as u32).to_le_bytes());
+        out.extend(sha256(&tables));
+        let mut offset = 0;
+        for (t, size) in [
+            (&partitions, PARTITION_SIZE),
+            (&extents, EXTENT_SIZE),
+            (&groups, GROUP_SIZE),
+            (&devices, BLOCK_DEVICE_SIZE),
+        ] {
+            out.extend((offset as u32).to_le_bytes());
+            out.extend(((t.len() / size) as u32).to_le_bytes());
+            out.extend((size as u32).to_le_bytes());
+            offset += t.len();
+        }
+        if header_size == HEADER_V1_2_SIZE {
+            out.extend(self.flags.to_le_bytes());
+        }
+        out.resize(header_size, 0);
+        let checksum = sha256(&out);
+        out[12..44].copy_from_slice(&checksum);
+
+        out.extend_from_slice(&tables);
+        if out.len() > self.geometry.metadata_max_size as usize {
+            return Err("LP metadata exceeds the maximum size".to_owned());
+        }
+        Ok(out)
+    }
+
+    /// Serializes the metadata as `super_empty.img`.
+    pub fn to_empty_image(&self) -> Result<Vec<u8>, String> {
+        let mut out = self.geometry.to_bytes();
+        out.extend(self.to_bytes()?);
+        Ok(out)
+    }
+
+    /// Serializes the metadata area at the start of a `super` partition: the
+    /// reserved area, the geometry and every metadata slot, twice each.
+    pub fn to_super_header(&self) -> Result<Vec<u8>, String> {
+        let slot = self.geometry.metadata_max_size as usize;
+        let slots = self.geometry.metadata_slot_count as usize;
+        let mut metadata = self.to_bytes()?;
+        metadata.resize(slot, 0);
+
+        let mut out = vec![0; RESERVED_BYTES];
+        let geometry = self.geometry.to_bytes();
+        out.extend_from_slice(&geometry);
+        out.extend_from_slice(&geometry);
+        for _ in 0..2 * slots {
+            out.extend_from_slice(&metadata);
+        }
+        Ok(out)
+    }
+
+    pub fn partition(&self, name: &str) -> Option<&Partition> {
+        self.partitions.iter().find(|p| p.name == name)
+    }
+
+    /// Bytes available to logical partitions on all block devices.
+    pub fn usable_size(&self) -> u64 {
+        self.block_devices
+            .iter()
+            .map(|d| {
+                d.size
+                    .saturating_sub(d.first_logical_sector.saturating_mul(LP_SECTOR_SIZE))
+            })
+            .fold(0, u64::saturating_add)
+    }
+
+    /// Checks whether images of the given sizes fit into their partitions
+    /// once resized, i.e. within the limits of their groups and of the block
+    /// devices. Partitions without an image keep their current size.
+    pub fn check_fit(&self, images: &[(&str, u64)]) -> Result<(), String> {
+        let block = (self.geometry.logical_block_size as u64).max(LP_SECTOR_SIZE);
+        for (name, _) in images {
+            if self.partition(name).is_none() {
+                return Err(format!("{name}: no such logical partition"));
+            }
+        }
+        let size_of = |p: &Partition| -> u64 {
+            match images.iter().find(|(n, _)| *n == p.name) {
+                Some((_, size)) => size.checked_next_multiple_of(block).unwrap_or(u64::MAX),
+                None => p.size(),
+            }
+        };
+
+        for (i, g) in self.groups.iter().enumerate() {
+            let used: u64 = self
+                .partitions
+                .iter()
+                .filter(|p| p.group as usize == i)
+                .map(size_of)
+                .fold(0, u64::saturating_add);
+            if g.maximum_size != 0 && used > g.maximum_size {
+                return Err(format!(
+                    "Group {} needs {used} bytes, but may only use {}",
+                    g.name, g.maximum_size
+                ));
+            }
+        }
+        let used = self
+            .partitions
+            .iter()
+            .map(size_of)
+            .fold(0, u64::saturating_add);
+        let usable = self.usable_size();
+        if used > usable {
+            return Err(format!(
+                "Logical partitions need {used} bytes, but only {usable} are available"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Adds an empty partition to a group.
+    pub fn add_partition(
+        &mut self,
+        name: &str,
+        attributes: u32,
+        group: &str,
+    ) -> Result<(), String> {
+        if self.partition(name).is_some() {
+            return Err(format!("{name}: partition exists"));
+        }
+        let group = self
+            .groups
+            .iter()
+            .position(|g| g.name == group)
+            .ok_or_else(|| format!("{group}: no such group"))?;
+        self.partitions.push(Partition {
+            name: name.to_owned(),
+            attributes,
+            group: group as u32,
+            extents: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Resizes a partition. Shrinking drops sectors from the end; growing
+    /// appends an extent after everything allocated on the first block
+    /// device, aligned to its alignment.
+    pub fn resize_partition(&mut self, name: &str, size: u64) -> Result<(), String> {
+        let block = (self.geometry.logical_block_size as u64).max(LP_SECTOR_SIZE);
+        self.check_fit(&[(name, size)])?;
+
+        let sectors = size.next_multiple_of(block) / LP_SECTOR_SIZE;
+        let dev = self
+            .block_devices
+            .first()
+            .ok_or_else(|| "No block device".to_owned())?;
+        let align = (dev.alignment as u64 / LP_SECTOR_SIZE).max(1);
+        let end = self
+            .partitions
+            .iter()
+            .flat_map(|p| &p.extents)
+            .filter_map(|e| match e.target {
+                ExtentTarget::Linear {
+                    block_device: 0,
+                    sector,
+                } => Some(sector + e.num_sectors),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+            .max(dev.first_logical_sector)
+            .next_multiple_of(align);
+        let dev_sectors = dev.size / LP_SECTOR_SIZE;
+
+        let p = self
+            .partitions
+            .iter_mut()
+            .find(|p| p.name == name)
+            .ok_or_else(|| format!("{name}: no such logical partition"))?;
+        let current: u64 = p.extents.iter().map(|e| e.num_sectors).sum();
+        if sectors > current {
+            let grow = sectors - current;
+            if end + grow > dev_sectors {
+                return Err(format!("{name}: not enough contiguous space"));
+            }
+            p.extents.push(Extent {
+                num_sectors: grow,
+                target: ExtentTarget::Linear {
+                    block_device: 0,
+                    sector: end,
+                },
+            });
+        } else {
+            let mut drop = current - sectors;
+            while drop > 0 {
+                let last = p.extents.last_mut().unwrap();
+                if last.num_sectors <= drop {
+                    drop -= last.num_sectors;
+                    p.extents.pop();
+                } else {
+                    last.num_sectors -= drop;
+                    drop = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Extent, ExtentTarget, LpMetadata, PARTITION_ATTR_READONLY};
+
+    fn metadata() -> LpMetadata {
+        let mut md = LpMetadata::new(64 << 20, 65536, 2);
+        md.add_group("main", 32 << 20).unwrap();
+        md
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut md = metadata();
+        md.add_partition("system_a", PARTITION_ATTR_READONLY, "main")
+            .unwrap();
+        md.add_partition("vendor_a", PARTITION_ATTR_READONLY, "main")
+            .unwrap();
+        md.resize_partition("system_a", 10 << 20).unwrap();
+        md.resize_partition("vendor_a", 5000).unwrap();
+
+        let empty = md.to_empty_image().unwrap();
+        let parsed = LpMetadata::parse_image(&empty).unwrap();
+        assert_eq!(parsed, md);
+        assert_eq!(parsed.to_empty_image().unwrap(), empty);
+        assert_eq!(parsed.partition("system_a").unwrap().size(), 10 << 20);
+        assert_eq!(parsed.partition("vendor_a").unwrap().size(), 8192);
+        assert_eq!(
+            parsed.partition("vendor_a").unwrap().extents[0].target,
+            ExtentTarget::Linear {
+                block_device: 0,
+                sector: 2048 + 2 * 10240,
+            }
+        );
+
+        let mut v12 = md.clone();
+        v12.minor_version = 2;
+        v12.flags = 1;
+        let data = v12.to_super_header().unwrap();
+        assert_eq!(data.len(), 4096 * 3 + 4 * 65536);
+        assert_eq!(LpMetadata::parse_image(&data).unwrap(), v12);
+    }
+
+    #[test]
+    fn test_checksums() {
+        let mut data = metadata().to_empty_image().unwrap();
+        data[4096 + 200] ^= 1;
+        assert!(LpMetadata::parse_image(&data).is_err());
+        let mut data = metadata().to_empty_image().unwrap();
+        data[50] ^= 1;
+        assert!(LpMetadata::parse_image(&data).is_err());
+
+        // So are sizes that overflow, even with matching checksums.
+        let mut md = metadata();
+        md.add_partition("system_a", 0, "main").unwrap();
+        md.partitions[0].extents.push(Extent {
+            num_sectors: u64::MAX / 2,
+            target: ExtentTarget::Zero,
+        });
+        assert_eq!(md.partitions[0].size(), u64::MAX);
+        assert!(md.check_fit(&[("system_a", u64::MAX)]).is_err());
+        let data = md.to_empty_image().unwrap();
+        assert!(LpMetadata::parse_image(&data)
+            .unwrap_err()
+            .contains("invalid size"));
+        let mut md = metadata();
+        md.block_devices[0].first_logical_sector = u64::MAX;
+        assert_eq!(md.usable_size(), 0);
+        let data = md.to_empty_image().unwrap();
+        assert!(LpMetadata::parse_image(&data).is_err());
+
+        // A header too short for its version is rejected before the
+        // checksum is looked at.
+        for header_size in [0u32, 44, 127] {
+            let mut data = metadata().to_empty_image().unwrap();
+            data[4096 + 8..4096 + 12].copy_from_slice(&header_size.to_le_bytes());
+            assert!(LpMetadata::parse_image(&data)
+                .unwrap_err()
+                .contains("header size"));
+        }
+        let mut v12 = metadata();
+        v12.minor_version = 2;
+        let mut data = v12.to_empty_image().unwrap();
+        data[4096 + 8..4096 + 12].copy_from_slice(&128u32.to_le_bytes());
+        assert!(LpMetadata::parse_image(&data)
+            .unwrap_err()
+            .contains("header size"));
+    }
+
+    #[test]
+    fn test_fit() {
+        let mut md = metadata();
+        md.add_partition("system_a", 0, "main").unwrap();
+        md.add_partition("odm_a", 0, "default").unwrap();
+        md.resize_partition("odm_a", 4 << 20).unwrap();
+
+        assert!(md.check_fit(&[("system_a", 30 << 20)]).is_ok());
+        assert!(md.check_fit(&[("system_a", 33 << 20)]).is_err());
+        assert!(md.check_fit(&[("product_a", 1)]).is_err());
+        assert!(md.check_fit(&[("odm_a", 64 << 20)]).is_err());
+        assert!(md.resize_partition("system_a", 40 << 20).is_err());
+
+        md.resize_partition("odm_a", 4096).unwrap();
+        assert_eq!(md.partition("odm_a").unwrap().size(), 4096);
+        md.resize_partition("odm_a", 0).unwrap();
+        assert!(md.partition("odm_a").unwrap().extents.is_empty());
+    }
+}
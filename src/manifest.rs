@@ -0,0 +1,647 @@
+//! Declarative flashing of boards, e.g. U-Boot ones, from a TOML manifest.
+//!
+//! A manifest names the variables it needs, maps partitions to files and
+//! lists the steps to take:
+//!
+//! ```toml
+//! name = "SpacemiT K1"
+//! # Variables the caller has to set, see `Manifest::set`.
+//! required = ["images"]
+//!
+//! # Defaults for other variables. Values, files and commands may refer to
+//! # variables as `${name}`.
+//! [variables]
+//! rootfs = "${images}/rootfs.ext4"
+//!
+//! # Files relative to the manifest, with optional checksums.
+//! [partitions]
+//! bootinfo = "${images}/factory/bootinfo_emmc.bin"
+//! fsbl = { file = "${images}/factory/FSBL.bin", sha256 = "9f86d0..." }
+//! rootfs = { file = "${rootfs}", sparse = true }
+//!
+//! [[steps]]
+//! erase = "env"
+//! optional = true
+//!
+//! [[steps]]
+//! flash = "gpt"
+//! file = "${images}/partition_universal.json"
+//!
+//! [[steps]]
+//! flash = "fsbl"
+//!
+//! [[steps]]
+//! name = "Reset the environment"
+//! oem = "env default"
+//!
+//! [[steps]]
+//! reboot = "system"
+//! ```
+//!
+//! A step does one of `flash`, `erase`, `stage`, `oem`, `reboot` or
+//! `continue = true`. Flash steps may override the `file`, `sha256` and
+//! `sparse` of the partition, stage steps take a file and an optional
+//! `sha256`. Failures of `optional` steps are reported but do not stop the
+//! run.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::fastboot::{Fastboot, FbResult, RebootTarget};
+
+/// How deep variables may refer to other variables.
+const MAX_NESTING: usize = 8;
+
+/// A file to flash into a partition.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "ImageDef")]
+pub struct Image {
+    pub file: String,
+    /// Expected SHA-256 of the file, in hex
+    pub sha256: Option<String>,
+    /// Flash as sparse images, split to fit `max-download-size`.
+    pub sparse: bool,
+}
+
+/// An image is either a file name or a table.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ImageDef {
+    File(String),
+    Table {
+        file: String,
+        sha256: Option<String>,
+        #[serde(default)]
+        sparse: bool,
+    },
+}
+
+impl From<ImageDef> for Image {
+    fn from(def: ImageDef) -> Self {
+        match def {
+            ImageDef::File(file) => Image {
+                file,
+                sha256: None,
+                sparse: false,
+            },
+            ImageDef::Table {
+                file,
+                sha256,
+                sparse,
+            } => Image {
+                file,
+                sha256,
+                sparse,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Flash {
+        partition: String,
+        /// Overrides the file of the partition.
+        file: Option<String>,
+        sha256: Option<String>,
+        sparse: Option<bool>,
+    },
+    Erase(String),
+    /// Stages a file for a following command, e.g. `continue`.
+    Stage {
+        file: String,
+        sha256: Option<String>,
+    },
+    Oem(String),
+    Reboot(RebootTarget),
+    /// Continues booting, e.g. from a boot ROM into the staged bootloader.
+    Continue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "StepDef")]
+pub struct Step {
+    /// What to report instead of the command
+    pub name: Option<String>,
+    /// Keep going if the step fails.
+    pub optional: bool,
+    pub action: Action,
+}
+
+/// A step as written, with one of the actions set.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StepDef {
+    name: Option<String>,
+    #[serde(default)]
+    optional: bool,
+    flash: Option<String>,
+    erase: Option<String>,
+    stage: Option<String>,
+    oem: Option<String>,
+    reboot: Option<String>,
+    #[serde(rename = "continue", default)]
+    continue_boot: bool,
+    file: Option<String>,
+    sha256: Option<String>,
+    sparse: Option<bool>,
+}
+
+impl TryFrom<StepDef> for Step {
+    type Error = String;
+
+    fn try_from(def: StepDef) -> FbResult<Self> {
+        let actions = [
+            def.flash.is_some(),
+            def.erase.is_some(),
+            def.stage.is_some(),
+            def.oem.is_some(),
+            def.reboot.is_some(),
+            def.continue_boot,
+        ];
+        if actions.iter().filter(|a| **a).count() != 1 {
+            return Err(
+                "a step needs one of flash, erase, stage, oem, reboot or continue".to_owned(),
+            );
+        }
+        let no_file = def.file.is_none() && def.sha256.is_none() && def.sparse.is_none();
+        let action = if let Some(partition) = def.flash {
+            Action::Flash {
+                partition,
+                file: def.file,
+                sha256: def.sha256,
+                sparse: def.sparse,
+            }
+        } else if let Some(file) = def.stage {
+            if def.file.is_some() || def.sparse.is_some() {
+                return Err("stage only takes a sha256".to_owned());
+            }
+            Action::Stage {
+                file,
+                sha256: def.sha256,
+            }
+        } else if !no_file {
+            return Err("only flash and stage take a file".to_owned());
+        } else if let Some(partition) = def.erase {
+            Action::Erase(partition)
+        } else if let Some(command) = def.oem {
+            Action::Oem(command)
+        } else if let Some(target) = def.reboot {
+            Action::Reboot(target.parse()?)
+        } else {
+            Action::Continue
+        };
+        Ok(Step {
+            name: def.name,
+            optional: def.optional,
+            action,
+        })
+    }
+}
+
+/// A board flashing manifest.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub name: Option<String>,
+    /// Variables without a default that have to be set
+    #[serde(default)]
+    pub required: Vec<String>,
+    /// Defaults of variables
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+    #[serde(default)]
+    pub partitions: BTreeMap<String, Image>,
+    #[serde(default)]
+    pub steps: Vec<Step>,
+    /// Where relative files are looked up
+    #[serde(skip)]
+    pub base: PathBuf,
+    /// Variables set by the caller
+    #[serde(skip)]
+    values: BTreeMap<String, String>,
+}
+
+impl FromStr for Manifest {
+    type Err = String;
+
+    fn from_str(s: &str) -> FbResult<Self> {
+        let manifest: Manifest = toml::from_str(s).map_err(|e| e.to_string())?;
+        for (i, step) in manifest.steps.iter().enumerate() {
+            if let Action::Flash {
+                partition,
+                file: None,
+                ..
+            } = &step.action
+            {
+                if !manifest.partitions.contains_key(partition) {
+                    return Err(format!("Step {}: no file for {partition}", i + 1));
+                }
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+/// What a step does, with variables and files resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Flash {
+        partition: String,
+        file: PathBuf,
+        sha256: Option<String>,
+        sparse: bool,
+    },
+    Erase(String),
+    Stage {
+        file: PathBuf,
+        sha256: Option<String>,
+    },
+    Oem(String),
+    Reboot(RebootTarget),
+    Continue,
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Flash {
+                partition,
+                file,
+                sparse,
+                ..
+            } => {
+                write!(f, "flash {partition} {}", file.display())?;
+                match sparse {
+                    true => write!(f, " (sparse)"),
+                    false => Ok(()),
+                }
+            }
+            Command::Erase(partition) => write!(f, "erase {partition}"),
+            Command::Stage { file, .. } => write!(f, "stage {}", file.display()),
+            Command::Oem(command) => write!(f, "oem {command}"),
+            Command::Reboot(target) => write!(f, "reboot {target}"),
+            Command::Continue => write!(f, "continue"),
+        }
+    }
+}
+
+// Checks the SHA-256 of the opened `file`, and rewinds it to be read again.
+fn check_sha256(file: &Path, f: &mut File, expected: &str) -> FbResult<()> {
+    let err = |e: io::Error| format!("{}: {e}", file.display());
+    let mut hasher = Sha256::new();
+    io::copy(f, &mut hasher).map_err(err)?;
+    f.rewind().map_err(err)?;
+    let actual: String = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    match actual.eq_ignore_ascii_case(expected.trim()) {
+        true => Ok(()),
+        false => Err(format!(
+            "{}: SHA-256 is {actual}, expected {expected}",
+            file.display()
+        )),
+    }
+}
+
+impl Command {
+    /// Checks that the file of the command is there and has the expected
+    /// checksum.
+    pub fn verify(&self) -> FbResult<()> {
+        let (file, sha256) = match self {
+            Command::Flash { file, sha256, .. } | Command::Stage { file, sha256 } => (file, sha256),
+            _ => return Ok(()),
+        };
+        match sha256 {
+            Some(expected) => {
+                let mut f = File::open(file).map_err(|e| format!("{}: {e}", file.display()))?;
+                check_sha256(file, &mut f, expected)
+            }
+            None => fs::metadata(file)
+                .map(|_| ())
+                .map_err(|e| format!("{}: {e}", file.display())),
+        }
+    }
+
+    fn run<F: Fastboot>(&self, dev: &mut F) -> FbResult<()> {
+        // The checksum is checked again on the handle the data is read from,
+        // so that a file changed since `verify` is not sent.
+        let open = |file: &Path, sha256: &Option<String>| -> FbResult<(File, usize)> {
+            let err = |e: io::Error| format!("{}: {e}", file.display());
+            let mut f = File::open(file).map_err(err)?;
+            if let Some(expected) = sha256 {
+                check_sha256(file, &mut f, expected)?;
+            }
+            let size = f.metadata().map_err(err)?.len() as usize;
+            Ok((f, size))
+        };
+        match self {
+            Command::Flash {
+                partition,
+                file,
+                sha256,
+                sparse: true,
+            } => {
+                let (f, size) = open(file, sha256)?;
+                dev.flash_sparse_from(partition, f, size as u64)
+            }
+            Command::Flash {
+                partition,
+                file,
+                sha256,
+                ..
+            } => {
+                let (f, size) = open(file, sha256)?;
+                dev.download_from(f, size, |_| {})?;
+                dev.flash(partition)
+            }
+            Command::Erase(partition) => dev.erase(partition),
+            Command::Stage { file, sha256 } => {
+                let (f, size) = open(file, sha256)?;
+                dev.stage_from(f, size, |_| {})
+            }
+            Command::Oem(command) => dev.oem(command).map(|_| ()),
+            Command::Reboot(target) => dev.reboot_to(target.clone()),
+            Command::Continue => dev.continue_boot(),
+        }
+    }
+
+    /// Whether the device goes away after the command.
+    fn disconnects(&self) -> bool {
+        matches!(self, Command::Reboot(_) | Command::Continue)
+    }
+}
+
+/// A step ready to run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Resolved {
+    /// The name of the step, or its command
+    pub name: String,
+    pub optional: bool,
+    pub command: Command,
+}
+
+/// What [`Manifest::run`] reports about a step: once when it starts, without
+/// a result, and once when it is done.
+#[derive(Debug)]
+pub struct StepReport<'a> {
+    /// Starting at 1
+    pub index: usize,
+    pub total: usize,
+    pub step: &'a Resolved,
+    pub result: Option<&'a FbResult<()>>,
+    pub elapsed: Duration,
+}
+
+impl Manifest {
+    /// Reads a manifest; its files are relative to its directory.
+    pub fn load<P: AsRef<Path>>(path: P) -> FbResult<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        let mut manifest: Manifest = text
+            .parse()
+            .map_err(|e| format!("{}: {e}", path.display()))?;
+        manifest.base = path.parent().unwrap_or(Path::new("")).to_owned();
+        Ok(manifest)
+    }
+
+    /// Sets a variable, overriding its default.
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_owned(), value.to_owned());
+    }
+
+    fn variable(&self, name: &str) -> Option<&String> {
+        self.values.get(name).or_else(|| self.variables.get(name))
+    }
+
+    /// Replaces the `${name}` references in `s`.
+    fn expand(&self, s: &str, depth: usize) -> FbResult<String> {
+        if depth > MAX_NESTING {
+            return Err(format!("Variables nested too deep in {s}"));
+        }
+        let mut out = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unterminated variable in {s}"))?;
+            let name = &rest[start + 2..start + end];
+            let value = self
+                .variable(name)
+                .ok_or_else(|| format!("Undefined variable {name}"))?;
+            out.push_str(&rest[..start]);
+            out.push_str(&self.expand(value, depth + 1)?);
+            rest = &rest[start + end + 1..];
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+
+    fn path(&self, file: &str) -> FbResult<PathBuf> {
+        Ok(self.base.join(self.expand(file, 0)?))
+    }
+
+    /// Resolves the steps with the variables set, failing if a required one
+    /// is missing.
+    pub fn resolve(&self) -> FbResult<Vec<Resolved>> {
+        let missing: Vec<&str> = self
+            .required
+            .iter()
+            .filter(|v| self.variable(v).is_none())
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            return Err(format!("Variables not set: {}", missing.join(", ")));
+        }
+
+        let mut steps = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            let command = match &step.action {
+                Action::Flash {
+                    partition,
+                    file,
+                    sha256,
+                    sparse,
+                } => {
+                    let image = self.partitions.get(partition);
+                    let file = file
+                        .as_ref()
+                        .or(image.map(|i| &i.file))
+                        .ok_or_else(|| format!("No file for {partition}"))?;
+                    Command::Flash {
+                        partition: self.expand(partition, 0)?,
+                        file: self.path(file)?,
+                        sha256: sha256.clone().or(image.and_then(|i| i.sha256.clone())),
+                        sparse: sparse.or(image.map(|i| i.sparse)).unwrap_or(false),
+                    }
+                }
+                Action::Erase(partition) => Command::Erase(self.expand(partition, 0)?),
+                Action::Stage { file, sha256 } => Command::Stage {
+                    file: self.path(file)?,
+                    sha256: sha256.clone(),
+                },
+                Action::Oem(command) => Command::Oem(self.expand(command, 0)?),
+                Action::Reboot(target) => Command::Reboot(target.clone()),
+                Action::Continue => Command::Continue,
+            };
+            steps.push(Resolved {
+                name: step.name.clone().unwrap_or_else(|| command.to_string()),
+                optional: step.optional,
+                command,
+            });
+        }
+        Ok(steps)
+    }
+
+    /// Checks that all files are there and match their checksums.
+    pub fn verify(&self) -> FbResult<()> {
+        self.resolve()?.iter().try_for_each(|s| s.command.verify())
+    }
+
+    /// Verifies the files and carries out the steps, calling `report` when
+    /// each one starts and when it is done. After a reboot or `continue`,
+    /// `reconnect` is called to get hold of the device again, unless it was
+    /// the last step. Returns the device as last connected.
+    pub fn run<T, C, R>(&self, mut dev: T, mut reconnect: C, mut report: R) -> FbResult<T>
+    where
+        T: Read + Write,
+        C: FnMut() -> FbResult<T>,
+        R: FnMut(&StepReport),
+    {
+        let steps = self.resolve()?;
+        for step in &steps {
+            step.command.verify()?;
+        }
+
+        let total = steps.len();
+        for (i, step) in steps.iter().enumerate() {
+            let mut status = StepReport {
+                index: i + 1,
+                total,
+                step,
+                result: None,
+                elapsed: Duration::ZERO,
+            };
+            report(&status);
+            let start = Instant::now();
+            let result = step.command.run(&mut dev);
+            status.result = Some(&result);
+            status.elapsed = start.elapsed();
+            report(&status);
+
+            match result {
+                Err(e) if !step.optional => return Err(format!("{}: {e}", step.name)),
+                Ok(()) if step.command.disconnects() && i + 1 < total => dev = reconnect()?,
+                _ => (),
+            }
+        }
+        Ok(dev)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Command, Manifest};
+    use crate::fastboot::RebootTarget;
+    use std::path::PathBuf;
+
+    const MANIFEST: &str = r#"
+        name = "board"
+        required = ["images"]
+
+        [variables]
+        rootfs = "${images}/rootfs.ext4"
+        mode = "emmc"
+
+        [partitions]
+        bootinfo = "${images}/bootinfo_${mode}.bin"
+        rootfs = { file = "${rootfs}", sparse = true, sha256 = "ab" }
+
+        [[steps]]
+        erase = "env"
+        optional = true
+
+        [[steps]]
+        flash = "bootinfo"
+
+        [[steps]]
+        flash = "rootfs"
+        sparse = false
+
+        [[steps]]
+        name = "Boot FSBL"
+        stage = "fsbl.bin"
+
+        [[steps]]
+        continue = true
+
+        [[steps]]
+        oem = "env set mode ${mode}"
+
+        [[steps]]
+        reboot = "bootloader"
+    "#;
+
+    #[test]
+    fn test_resolve() {
+        let mut manifest: Manifest = MANIFEST.parse().unwrap();
+        assert_eq!(manifest.steps.len(), 7);
+        assert!(manifest.steps[0].optional);
+        assert_eq!(manifest.steps[4].action, Action::Continue);
+        assert!(manifest.resolve().unwrap_err().contains("images"));
+
+        manifest.base = PathBuf::from("/flash");
+        manifest.set("images", "out");
+        manifest.set("mode", "sd");
+        let steps = manifest.resolve().unwrap();
+        assert_eq!(steps[0].command, Command::Erase("env".to_owned()));
+        assert_eq!(
+            steps[1].command,
+            Command::Flash {
+                partition: "bootinfo".to_owned(),
+                file: PathBuf::from("/flash/out/bootinfo_sd.bin"),
+                sha256: None,
+                sparse: false,
+            }
+        );
+        assert_eq!(
+            steps[2].command,
+            Command::Flash {
+                partition: "rootfs".to_owned(),
+                file: PathBuf::from("/flash/out/rootfs.ext4"),
+                sha256: Some("ab".to_owned()),
+                sparse: false,
+            }
+        );
+        assert_eq!(steps[3].name, "Boot FSBL");
+        assert_eq!(steps[5].name, "oem env set mode sd");
+        assert_eq!(steps[6].command, Command::Reboot(RebootTarget::Bootloader));
+        assert_eq!(steps[6].name, "reboot bootloader");
+
+        manifest.set("images", "${rootfs}");
+        assert!(manifest.resolve().is_err());
+
+        for invalid in [
+            "[[steps]]\nflash = \"boot\"",
+            "[[steps]]\nerase = \"a\"\noem = \"b\"",
+            "[[steps]]\nerase = \"a\"\nfile = \"b\"",
+            "[[steps]]\nreboot = \"to nowhere\"",
+            "[[steps]]\nwipe = \"all\"",
+            "[[steps]]\n",
+            "[partitions]\nboot = { sparse = true }",
+            "board = \"x\"",
+        ] {
+            assert!(invalid.parse::<Manifest>().is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Recording and replaying of device sessions.
+//!
+//! [`Recorder`] wraps any [`Read`] + [`Write`] transport, e.g. a USB device,
+//! and logs everything written to and read from it, with a timestamp, into a
+//! text file. [`Replay`] is a transport that plays such a log back: reads get
+//! the recorded data and writes must match the recorded ones. Since both
+//! implement [`Fastboot`](crate::Fastboot), a failed session on a real board
+//! can be reproduced without the board.
+//!
+//! Each line of the log is an event: microseconds since the start, a tag and
+//! the payload in hex, e.g. `    1042 W 6765747661723a76657273696f6e`.
+//! `W` marks writes, `R` reads, `T` reads that timed out and `E` other read
+//! errors, with the message as payload.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use std::time::Instant;
+
+const HEADER: &str = "# fastboot session v1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Event {
+    Write(Vec<u8>),
+    Read(Vec<u8>),
+    TimedOut,
+    Error(String),
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter()
+        .fold(String::with_capacity(data.len() * 2), |mut s, b| {
+            let _ = write!(s, "{b:02x}");
+            s
+        })
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Records a session on a transport into a log, see the [module](self) docs.
+pub struct Recorder<T: Read + Write, L: Write> {
+    inner: T,
+    log: L,
+    start: Instant,
+}
+
+impl<T: Read + Write> Recorder<T, BufWriter<File>> {
+    /// Records into a new file at `path`.
+    pub fn create<P: AsRef<Path>>(inner: T, path: P) -> io::Result<Self> {
+        Self::new(inner, BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<T: Read + Write, L: Write> Recorder<T, L> {
+    pub fn new(inner: T, mut log: L) -> io::Result<Self> {
+        writeln!(log, "{HEADER}")?;
+        Ok(Recorder {
+            inner,
+            log,
+            start: Instant::now(),
+        })
+    }
+
+    /// Stops recording and returns the transport and the log.
+    pub fn into_inner(mut self) -> io::Result<(T, L)> {
+        self.log.flush()?;
+        Ok((self.inner, self.log))
+    }
+
+    fn record(&mut self, tag: char, payload: &str) -> io::Result<()> {
+        let t = self.start.elapsed().as_micros();
+        writeln!(self.log, "{t:>8} {tag} {payload}")
+    }
+}
+
+impl<T: Read + Write, L: Write> Read for Recorder<T, L> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(n) => {
+                self.record('R', &to_hex(&buf[..n]))?;
+                Ok(n)
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => {
+                self.record('T', "")?;
+                Err(err)
+            }
+            Err(err) => {
+                self.record('E', &to_hex(err.to_string().as_bytes()))?;
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<T: Read + Write, L: Write> Write for Recorder<T, L> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.record('W', &to_hex(&buf[..n]))?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+        self.log.flush()
+    }
+}
+
+/// Plays a recorded session back, see the [module](self) docs.
+///
+/// Writes that differ from the recorded ones fail with an error that shows
+/// where they differ.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    events: Vec<Event>,
+    next: usize,
+    // Part of a recorded read that did not fit into the caller's buffer
+    pending: Vec<u8>,
+}
+
+impl Replay {
+    /// Loads a session recorded into the file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: BufRead>(log: R) -> io::Result<Self> {
+        let mut events = Vec::new();
+        for (i, line) in log.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("line {}: invalid event: {line}", i + 1),
+                )
+            };
+            let mut fields = line.split_whitespace().skip(1);
+            let tag = fields.next().ok_or_else(invalid)?;
+            let payload = from_hex(fields.next().unwrap_or("")).ok_or_else(invalid)?;
+            events.push(match tag {
+                "W" => Event::Write(payload),
+                "R" => Event::Read(payload),
+                "T" => Event::TimedOut,
+                "E" => Event::Error(String::from_utf8_lossy(&payload).into_owned()),
+                _ => return Err(invalid()),
+            });
+        }
+        Ok(Replay {
+            events,
+            next: 0,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Tells whether all recorded events have been played back.
+    pub fn is_done(&self) -> bool {
+        self.pending.is_empty() && self.next == self.events.len()
+    }
+
+    fn mismatch(&self, expected: &str, got: String) -> io::Error {
+        let msg = format!(
+            "replay: event #{}: expected {expected}, got {got}",
+            self.next + 1
+        );
+        io::Error::new(ErrorKind::InvalidData, msg)
+    }
+}
+
+// Shows data as text if it is, e.g. a command, or as hex around `at` if not.
+fn show(data: &[u8], at: usize) -> String {
+    if data.iter().all(|b| b.is_ascii_graphic() || *b == b' ') {
+        return format!("{:?}", String::from_utf8_lossy(data));
+    }
+    let from = at.saturating_sub(8);
+    let to = (at + 8).min(data.len());
+    format!(
+        "{} bytes, at {from}: {}",
+        data.len(),
+        to_hex(&data[from.min(to)..to])
+    )
+}
+
+fn show_event(event: Option<&Event>) -> String {
+    match event {
+        Some(Event::Write(d)) => format!("a write of {}", show(d, 0)),
+        Some(Event::Read(d)) => format!("a read of {}", show(d, 0)),
+        Some(Event::TimedOut) => "a read timeout".to_owned(),
+        Some(Event::Error(e)) => format!("a read error ({e})"),
+        None => "the end of the session".to_owned(),
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.events.get(self.next) {
+                Some(Event::Read(d)) => self.pending = d.clone(),
+                Some(Event::TimedOut) => {
+                    self.next += 1;
+                    return Err(ErrorKind::TimedOut.into());
+                }
+                Some(Event::Error(e)) => {
+                    let err = io::Error::other(e.clone());
+                    self.next += 1;
+                    return Err(err);
+                }
+                other => {
+                    return Err(self.mismatch(&show_event(other), "a read".to_owned()));
+                }
+            }
+            self.next += 1;
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let expected = match self.events.get(self.next) {
+            Some(Event::Write(d)) if self.pending.is_empty() => d,
+            other => {
+                let other = if self.pending.is_empty() { other } else { None };
+                let got = format!("a write of {}", show(buf, 0));
+                return Err(self.mismatch(&show_event(other), got));
+            }
+        };
+        if expected.as_slice() != buf {
+            let at = expected
+                .iter()
+                .zip(buf)
+                .position(|(a, b)| a != b)
+                .unwrap_or(expected.len().min(buf.len()));
+            let (e, g) = (show(expected, at), show(buf, at));
+            return Err(self.mismatch(&format!("a write of {e}"), g));
+        }
+        self.next += 1;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Recorder, Replay};
+    use crate::Fastboot;
+    use std::collections::VecDeque;
+    use std::io::{self, Read, Write};
+
+    // Answers every command with the next of its replies.
+    struct Device {
+        replies: VecDeque<&'static [u8]>,
+    }
+
+    impl Read for Device {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let reply = self.replies.pop_front().ok_or(io::ErrorKind::TimedOut)?;
+            buf[..reply.len()].copy_from_slice(reply);
+            Ok(reply.len())
+        }
+    }
+
+    impl Write for Device {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn record() -> Vec<u8> {
+        let device = Device {
+            replies: VecDeque::from([&b"OKAY0.4"[..], b"DATA00000004", b"OKAY"]),
+        };
+        let mut rec = Recorder::new(device, Vec::new()).unwrap();
+        assert_eq!(Ok("0.4".to_owned()), rec.getvar("version"));
+        assert_eq!(Ok(()), rec.download(&[0, 1, 2, 3]));
+        rec.into_inner().unwrap().1
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let log = record();
+        let text = String::from_utf8(log.clone()).unwrap();
+        assert_eq!(text.lines().count(), 7);
+        assert!(text.lines().nth(5).unwrap().ends_with(" W 00010203"));
+
+        let mut replay = Replay::from_reader(&log[..]).unwrap();
+        assert_eq!(Ok("0.4".to_owned()), replay.getvar("version"));
+        assert_eq!(Ok(()), replay.download(&[0, 1, 2, 3]));
+        assert!(replay.is_done());
+    }
+
+    #[test]
+    fn test_replay_mismatch() {
+        let log = record();
+
+        let mut replay = Replay::from_reader(&log[..]).unwrap();
+        assert_eq!(
+            Err("replay: event #1: expected a write of \"getvar:version\", \
+                 got \"getvar:product\""
+                .to_owned()),
+            replay.getvar("product")
+        );
+
+        let mut replay = Replay::from_reader(&log[..]).unwrap();
+        replay.getvar("version").unwrap();
+        assert_eq!(
+            Err(
+                "replay: event #5: expected a write of 4 bytes, at 0: 00010203, \
+                 got 4 bytes, at 0: 00010303"
+                    .to_owned()
+            ),
+            replay.download(&[0, 1, 3, 3])
+        );
+        assert!(!replay.is_done());
+    }
+}
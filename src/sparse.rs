@@ -0,0 +1,733 @@
+//! Android sparse images, as accepted by `flash:`.
+//!
+//! See `system/core/libsparse/sparse_format.h` in AOSP. A sparse image is a
+//! header followed by chunks of blocks: raw data, a repeated 32 bit fill
+//! pattern or blocks to skip. Images larger than the device can download at
+//! once are split into several sparse images, each covering the whole
+//! partition but only carrying some of the data, like libsparse does.
+
+use std::io::{self, Read};
+
+use crate::bytes::Reader;
+
+pub const SPARSE_MAGIC: u32 = 0xed26_ff3a;
+/// The block size used when converting raw images.
+pub const BLOCK_SIZE: u32 = 4096;
+
+/// The size of the header, which tells the size of the expanded image.
+pub const HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+// Room in every piece for a skipped range before and after the data.
+const OVERHEAD: usize = HEADER_SIZE + 2 * CHUNK_HEADER_SIZE;
+
+const CHUNK_RAW: u16 = 0xcac1;
+const CHUNK_FILL: u16 = 0xcac2;
+const CHUNK_DONT_CARE: u16 = 0xcac3;
+const CHUNK_CRC32: u16 = 0xcac4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    /// Whole blocks of data
+    Raw(Vec<u8>),
+    /// Blocks filled with a little endian pattern
+    Fill { pattern: u32, blocks: u32 },
+    /// Blocks left as they are on the device
+    DontCare(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SparseImage {
+    pub block_size: u32,
+    pub total_blocks: u32,
+    pub chunks: Vec<Chunk>,
+}
+
+/// Tells whether `data` starts like a sparse image.
+pub fn is_sparse(data: &[u8]) -> bool {
+    data.starts_with(&SPARSE_MAGIC.to_le_bytes())
+}
+
+/// The size of the raw image a sparse image expands to, given at least its
+/// first [`HEADER_SIZE`] bytes. `None` if `data` is not a sparse image.
+pub fn expanded_size(data: &[u8]) -> Option<u64> {
+    if !is_sparse(data) {
+        return None;
+    }
+    let mut r = Reader::new(data.get(12..HEADER_SIZE)?);
+    let block_size = r.u32_le().ok()?;
+    let total_blocks = r.u32_le().ok()?;
+    Some(block_size as u64 * total_blocks as u64)
+}
+
+impl Chunk {
+    fn blocks(&self, block_size: u32) -> u32 {
+        match self {
+            Chunk::Raw(data) => (data.len() / block_size as usize) as u32,
+            Chunk::Fill { blocks, .. } | Chunk::DontCare(blocks) => *blocks,
+        }
+    }
+
+    /// The size of the chunk in the image, with its header.
+    fn size(&self) -> usize {
+        CHUNK_HEADER_SIZE
+            + match self {
+                Chunk::Raw(data) => data.len(),
+                Chunk::Fill { .. } => 4,
+                Chunk::DontCare(_) => 0,
+            }
+    }
+}
+
+impl SparseImage {
+    /// Converts a raw image. Blocks of a single repeated pattern become fill
+    /// chunks, and the last block is padded with zeros.
+    pub fn from_raw(data: &[u8], block_size: u32) -> Result<Self, String> {
+        if block_size == 0 || !block_size.is_multiple_of(4) {
+            return Err(format!("Invalid sparse block size {block_size}"));
+        }
+        let mut source = RawChunks {
+            source: data,
+            block_size: block_size as usize,
+            pending: None,
+        };
+        let mut chunks = Vec::new();
+        while let Some(chunk) = source.next_chunk(usize::MAX)? {
+            chunks.push(chunk);
+        }
+        let total_blocks = u32::try_from(data.len().div_ceil(block_size as usize))
+            .map_err(|_| "Image too large for a sparse image".to_owned())?;
+        Ok(SparseImage {
+            block_size,
+            total_blocks,
+            chunks,
+        })
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader::new(data);
+        if r.u32_le()? != SPARSE_MAGIC {
+            return Err("Not a sparse image".to_owned());
+        }
+        let major = r.u16_le()?;
+        let _minor = r.u16_le()?;
+        let header_size = r.u16_le()? as usize;
+        let chunk_header_size = r.u16_le()? as usize;
+        let block_size = r.u32_le()?;
+        let total_blocks = r.u32_le()?;
+        let count = r.u32_le()?;
+        // Every chunk takes at least its header.
+        if major != 1
+            || header_size < HEADER_SIZE
+            || chunk_header_size < CHUNK_HEADER_SIZE
+            || block_size == 0
+            || count as usize > data.len() / chunk_header_size
+        {
+            return Err("Invalid sparse image header".to_owned());
+        }
+        r.bytes(header_size - HEADER_SIZE + 4)?;
+
+        let mut chunks = Vec::with_capacity(count as usize);
+        let mut blocks_seen: u64 = 0;
+        for i in 0..count {
+            let mut header = Reader::new(r.bytes(chunk_header_size)?);
+            let kind = header.u16_le()?;
+            header.u16_le()?;
+            let blocks = header.u32_le()?;
+            let total_size = header.u32_le()? as usize;
+            let body = r.bytes(
+                total_size
+                    .checked_sub(chunk_header_size)
+                    .ok_or_else(|| format!("Sparse chunk {i} is truncated"))?,
+            )?;
+            let chunk = match kind {
+                CHUNK_RAW if body.len() as u64 == blocks as u64 * block_size as u64 => {
+                    Chunk::Raw(body.to_vec())
+                }
+                CHUNK_FILL if body.len() == 4 => Chunk::Fill {
+                    pattern: Reader::new(body).u32_le()?,
+                    blocks,
+                },
+                CHUNK_DONT_CARE => Chunk::DontCare(blocks),
+                CHUNK_CRC32 => continue,
+                _ => return Err(format!("Invalid sparse chunk {i}")),
+            };
+            blocks_seen += blocks as u64;
+            chunks.push(chunk);
+        }
+        if blocks_seen != total_blocks as u64 {
+            return Err("Sparse chunks do not cover the image".to_owned());
+        }
+        Ok(SparseImage {
+            block_size,
+            total_blocks,
+            chunks,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.size());
+        // Reading from memory cannot fail.
+        self.reader().read_to_end(&mut out).unwrap();
+        out
+    }
+
+    /// The size of the image as returned by [`to_bytes`](Self::to_bytes).
+    pub fn size(&self) -> usize {
+        HEADER_SIZE + self.chunks.iter().map(Chunk::size).sum::<usize>()
+    }
+
+    /// Reads the image as returned by [`to_bytes`](Self::to_bytes), without
+    /// copying the data of its chunks.
+    pub fn reader(&self) -> ImageReader<'_> {
+        let mut head = Vec::with_capacity(HEADER_SIZE);
+        head.extend(SPARSE_MAGIC.to_le_bytes());
+        head.extend(1u16.to_le_bytes());
+        head.extend(0u16.to_le_bytes());
+        head.extend((HEADER_SIZE as u16).to_le_bytes());
+        head.extend((CHUNK_HEADER_SIZE as u16).to_le_bytes());
+        head.extend(self.block_size.to_le_bytes());
+        head.extend(self.total_blocks.to_le_bytes());
+        head.extend((self.chunks.len() as u32).to_le_bytes());
+        head.extend(0u32.to_le_bytes());
+        ImageReader {
+            block_size: self.block_size,
+            chunks: self.chunks.iter(),
+            head,
+            data: &[],
+        }
+    }
+
+    /// Expands the image, with zeros for the blocks to skip.
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in &self.chunks {
+            let len = chunk.blocks(self.block_size) as usize * self.block_size as usize;
+            match chunk {
+                Chunk::Raw(data) => out.extend(data),
+                Chunk::Fill { pattern, .. } => {
+                    out.extend(pattern.to_le_bytes().iter().cycle().take(len))
+                }
+                Chunk::DontCare(_) => out.resize(out.len() + len, 0),
+            }
+        }
+        out
+    }
+
+    /// Splits the image into images of at most `max_size` bytes each. Every
+    /// one of them covers all blocks, skipping those carried by the others.
+    pub fn split(&self, max_size: usize) -> Result<Vec<SparseImage>, String> {
+        let chunks = MemChunks {
+            chunks: &self.chunks,
+            offset: 0,
+        };
+        Pieces::new(chunks, self.block_size, self.total_blocks, max_size)?.collect()
+    }
+}
+
+/// Reads a [`SparseImage`] in its binary form, see [`SparseImage::reader`].
+pub struct ImageReader<'a> {
+    block_size: u32,
+    chunks: std::slice::Iter<'a, Chunk>,
+    // The header left to read, before `data`.
+    head: Vec<u8>,
+    data: &'a [u8],
+}
+
+impl Read for ImageReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.head.is_empty() {
+                let n = buf.len().min(self.head.len());
+                buf[..n].copy_from_slice(&self.head[..n]);
+                self.head.drain(..n);
+                return Ok(n);
+            }
+            if !self.data.is_empty() {
+                let n = buf.len().min(self.data.len());
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data = &self.data[n..];
+                return Ok(n);
+            }
+            let Some(chunk) = self.chunks.next() else {
+                return Ok(0);
+            };
+            let kind = match chunk {
+                Chunk::Raw(_) => CHUNK_RAW,
+                Chunk::Fill { .. } => CHUNK_FILL,
+                Chunk::DontCare(_) => CHUNK_DONT_CARE,
+            };
+            self.head.extend(kind.to_le_bytes());
+            self.head.extend(0u16.to_le_bytes());
+            self.head
+                .extend(chunk.blocks(self.block_size).to_le_bytes());
+            self.head.extend((chunk.size() as u32).to_le_bytes());
+            match chunk {
+                Chunk::Raw(data) => self.data = data,
+                Chunk::Fill { pattern, .. } => self.head.extend(pattern.to_le_bytes()),
+                Chunk::DontCare(_) => (),
+            }
+        }
+    }
+}
+
+/// Splits the raw or sparse image of `size` bytes in `source` like
+/// [`SparseImage::split`], but reads and builds one piece at a time, so that
+/// large images need not fit into memory. Raw images are converted with
+/// blocks of [`BLOCK_SIZE`].
+pub fn split_from<'a, R: Read + 'a>(
+    mut source: R,
+    size: u64,
+    max_size: usize,
+) -> Result<Pieces<'a>, String> {
+    let err = |e: io::Error| e.to_string();
+    let mut magic = [0; 4];
+    let n = size.min(4) as usize;
+    source.read_exact(&mut magic[..n]).map_err(err)?;
+    if n < 4 || u32::from_le_bytes(magic) != SPARSE_MAGIC {
+        let total_blocks = u32::try_from(size.div_ceil(BLOCK_SIZE as u64))
+            .map_err(|_| "Image too large for a sparse image".to_owned())?;
+        let chunks = RawChunks {
+            source: io::Cursor::new(magic[..n].to_vec()).chain(source.take(size - n as u64)),
+            block_size: BLOCK_SIZE as usize,
+            pending: None,
+        };
+        return Pieces::new(chunks, BLOCK_SIZE, total_blocks, max_size);
+    }
+
+    let mut header = [0; HEADER_SIZE - 4];
+    source.read_exact(&mut header).map_err(err)?;
+    let mut r = Reader::new(&header);
+    let major = r.u16_le()?;
+    let _minor = r.u16_le()?;
+    let header_size = r.u16_le()? as usize;
+    let chunk_header_size = r.u16_le()? as usize;
+    let block_size = r.u32_le()?;
+    let total_blocks = r.u32_le()?;
+    let count = r.u32_le()?;
+    if major != 1
+        || header_size < HEADER_SIZE
+        || chunk_header_size < CHUNK_HEADER_SIZE
+        || block_size == 0
+    {
+        return Err("Invalid sparse image header".to_owned());
+    }
+    skip(&mut source, (header_size - HEADER_SIZE) as u64)?;
+    let chunks = SparseChunks {
+        source,
+        chunk_header_size,
+        block_size,
+        total_blocks,
+        count,
+        index: 0,
+        blocks_seen: 0,
+        raw_left: 0,
+    };
+    Pieces::new(chunks, block_size, total_blocks, max_size)
+}
+
+fn skip<R: Read>(source: &mut R, len: u64) -> Result<(), String> {
+    match io::copy(&mut source.take(len), &mut io::sink()) {
+        Ok(n) if n == len => Ok(()),
+        Ok(_) => Err("Sparse image is truncated".to_owned()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn read_vec<R: Read>(source: &mut R, len: usize) -> Result<Vec<u8>, String> {
+    let mut data = vec![0; len];
+    source.read_exact(&mut data).map_err(|e| e.to_string())?;
+    Ok(data)
+}
+
+/// The chunks of an image in order, with raw data handed out in parts of at
+/// most `max_raw` bytes.
+trait ChunkSource {
+    fn next_chunk(&mut self, max_raw: usize) -> Result<Option<Chunk>, String>;
+}
+
+struct MemChunks<'a> {
+    chunks: &'a [Chunk],
+    // Into the first raw chunk
+    offset: usize,
+}
+
+impl ChunkSource for MemChunks<'_> {
+    fn next_chunk(&mut self, max_raw: usize) -> Result<Option<Chunk>, String> {
+        let Some((chunk, rest)) = self.chunks.split_first() else {
+            return Ok(None);
+        };
+        let chunk = match chunk {
+            Chunk::Raw(data) => {
+                let end = data.len().min(self.offset + max_raw);
+                let part = Chunk::Raw(data[self.offset..end].to_vec());
+                self.offset = end;
+                if end < data.len() {
+                    return Ok(Some(part));
+                }
+                part
+            }
+            chunk => chunk.clone(),
+        };
+        self.chunks = rest;
+        self.offset = 0;
+        Ok(Some(chunk))
+    }
+}
+
+/// Converts a raw image like [`SparseImage::from_raw`].
+struct RawChunks<R> {
+    source: R,
+    block_size: usize,
+    // A block read ahead
+    pending: Option<Vec<u8>>,
+}
+
+impl<R: Read> RawChunks<R> {
+    fn block(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if let Some(block) = self.pending.take() {
+            return Ok(Some(block));
+        }
+        let mut block = Vec::with_capacity(self.block_size);
+        let n = (&mut self.source)
+            .take(self.block_size as u64)
+            .read_to_end(&mut block)
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Ok(None);
+        }
+        block.resize(self.block_size, 0);
+        Ok(Some(block))
+    }
+}
+
+fn fill_pattern(block: &[u8]) -> Option<u32> {
+    let pattern = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+    block
+        .chunks(4)
+        .all(|w| w == pattern.to_le_bytes().as_slice())
+        .then_some(pattern)
+}
+
+impl<R: Read> ChunkSource for RawChunks<R> {
+    fn next_chunk(&mut self, max_raw: usize) -> Result<Option<Chunk>, String> {
+        let Some(first) = self.block()? else {
+            return Ok(None);
+        };
+        if let Some(pattern) = fill_pattern(&first) {
+            let mut blocks = 1;
+            while let Some(block) = self.block()? {
+                if fill_pattern(&block) != Some(pattern) {
+                    self.pending = Some(block);
+                    break;
+                }
+                blocks += 1;
+            }
+            return Ok(Some(Chunk::Fill { pattern, blocks }));
+        }
+        let mut data = first;
+        while data.len() + self.block_size <= max_raw {
+            match self.block()? {
+                Some(block) if fill_pattern(&block).is_none() => data.extend(block),
+                block => {
+                    self.pending = block;
+                    break;
+                }
+            }
+        }
+        Ok(Some(Chunk::Raw(data)))
+    }
+}
+
+/// Reads the chunks of a sparse image, see [`SparseImage::parse`].
+struct SparseChunks<R> {
+    source: R,
+    chunk_header_size: usize,
+    block_size: u32,
+    total_blocks: u32,
+    count: u32,
+    index: u32,
+    blocks_seen: u64,
+    // Of the current raw chunk
+    raw_left: usize,
+}
+
+impl<R: Read> ChunkSource for SparseChunks<R> {
+    fn next_chunk(&mut self, max_raw: usize) -> Result<Option<Chunk>, String> {
+        while self.raw_left == 0 {
+            if self.index == self.count {
+                if self.blocks_seen != self.total_blocks as u64 {
+                    return Err("Sparse chunks do not cover the image".to_owned());
+                }
+                return Ok(None);
+            }
+            let i = self.index;
+            self.index += 1;
+            let header = read_vec(&mut self.source, self.chunk_header_size)?;
+            let mut header = Reader::new(&header);
+            let kind = header.u16_le()?;
+            header.u16_le()?;
+            let blocks = header.u32_le()?;
+            let body = (header.u32_le()? as usize)
+                .checked_sub(self.chunk_header_size)
+                .ok_or_else(|| format!("Sparse chunk {i} is truncated"))?;
+            let chunk = match kind {
+                CHUNK_RAW if body as u64 == blocks as u64 * self.block_size as u64 => {
+                    self.raw_left = body;
+                    None
+                }
+                CHUNK_FILL if body == 4 => Some(Chunk::Fill {
+                    pattern: Reader::new(&read_vec(&mut self.source, 4)?).u32_le()?,
+                    blocks,
+                }),
+                CHUNK_DONT_CARE => {
+                    skip(&mut self.source, body as u64)?;
+                    Some(Chunk::DontCare(blocks))
+                }
+                CHUNK_CRC32 => {
+                    skip(&mut self.source, body as u64)?;
+                    continue;
+                }
+                _ => return Err(format!("Invalid sparse chunk {i}")),
+            };
+            self.blocks_seen += blocks as u64;
+            if chunk.is_some() {
+                return Ok(chunk);
+            }
+        }
+        let len = self.raw_left.min(max_raw);
+        self.raw_left -= len;
+        Ok(Some(Chunk::Raw(read_vec(&mut self.source, len)?)))
+    }
+}
+
+/// The pieces of a split image, built as they are needed, see
+/// [`split_from`].
+pub struct Pieces<'a> {
+    source: Box<dyn ChunkSource + 'a>,
+    block_size: u32,
+    total_blocks: u32,
+    max_size: usize,
+    // A chunk that did not fit into the previous piece
+    pending: Option<Chunk>,
+    block: u32,
+    started: bool,
+    done: bool,
+}
+
+impl<'a> Pieces<'a> {
+    fn new<S: ChunkSource + 'a>(
+        source: S,
+        block_size: u32,
+        total_blocks: u32,
+        max_size: usize,
+    ) -> Result<Self, String> {
+        if max_size < OVERHEAD + CHUNK_HEADER_SIZE + block_size as usize {
+            return Err(format!("{max_size} bytes are too few for a sparse image"));
+        }
+        Ok(Pieces {
+            source: Box::new(source),
+            block_size,
+            total_blocks,
+            max_size,
+            pending: None,
+            block: 0,
+            started: false,
+            done: false,
+        })
+    }
+
+    fn next_piece(&mut self) -> Result<Option<SparseImage>, String> {
+        let block_size = self.block_size as usize;
+        // The most raw data a single piece carries
+        let max_raw = (self.max_size - OVERHEAD - CHUNK_HEADER_SIZE) / block_size * block_size;
+        let start = self.block;
+        let mut chunks: Vec<Chunk> = Vec::new();
+        let mut used = OVERHEAD;
+        loop {
+            let chunk = match self.pending.take() {
+                Some(chunk) => chunk,
+                None => match self.source.next_chunk(max_raw)? {
+                    Some(chunk) => chunk,
+                    None => {
+                        self.done = true;
+                        break;
+                    }
+                },
+            };
+            // Raw data following raw data joins its chunk.
+            let header = match (chunks.last(), &chunk) {
+                (Some(Chunk::Raw(_)), Chunk::Raw(_)) => 0,
+                _ => CHUNK_HEADER_SIZE,
+            };
+            let mut chunk = chunk;
+            if used + chunk.size() - CHUNK_HEADER_SIZE + header > self.max_size {
+                match chunk {
+                    // Fill the piece with as many blocks as fit.
+                    Chunk::Raw(mut data) if used + header + block_size <= self.max_size => {
+                        let fit = (self.max_size - used - header) / block_size * block_size;
+                        self.pending = Some(Chunk::Raw(data.split_off(fit)));
+                        chunk = Chunk::Raw(data);
+                    }
+                    chunk => {
+                        self.pending = Some(chunk);
+                        break;
+                    }
+                }
+            }
+            used += chunk.size() - CHUNK_HEADER_SIZE + header;
+            self.block += chunk.blocks(self.block_size);
+            match (chunks.last_mut(), chunk) {
+                (Some(Chunk::Raw(raw)), Chunk::Raw(data)) => raw.extend(data),
+                (_, chunk) => chunks.push(chunk),
+            }
+        }
+        if chunks.is_empty() && self.started {
+            return Ok(None);
+        }
+        self.started = true;
+        Ok(Some(self.piece(start, chunks)))
+    }
+
+    /// An image with `chunks` for the blocks from `start` to the current one.
+    fn piece(&self, start: u32, chunks: Vec<Chunk>) -> SparseImage {
+        let mut piece = Vec::with_capacity(chunks.len() + 2);
+        if start > 0 {
+            piece.push(Chunk::DontCare(start));
+        }
+        piece.extend(chunks);
+        if self.block < self.total_blocks {
+            piece.push(Chunk::DontCare(self.total_blocks - self.block));
+        }
+        SparseImage {
+            block_size: self.block_size,
+            total_blocks: self.total_blocks,
+            chunks: piece,
+        }
+    }
+}
+
+impl Iterator for Pieces<'_> {
+    type Item = Result<SparseImage, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done && self.pending.is_none() {
+            return None;
+        }
+        let piece = self.next_piece();
+        if piece.is_err() {
+            self.done = true;
+            self.pending = None;
+        }
+        piece.transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expanded_size, is_sparse, split_from, Chunk, SparseImage};
+
+    #[test]
+    fn test_convert_and_split() {
+        let mut raw = vec![0u8; 3 * 1024];
+        raw.extend((0..5120u32).map(|i| (i % 251) as u8));
+        raw.extend([0xaa, 0xbb, 0xcc, 0xdd].repeat(512));
+        raw.extend([1, 2, 3]);
+
+        let image = SparseImage::from_raw(&raw, 1024).unwrap();
+        assert_eq!(image.total_blocks, 11);
+        assert_eq!(expanded_size(&image.to_bytes()), Some(11 * 1024));
+        assert_eq!(expanded_size(&image.to_bytes()[..27]), None);
+        assert_eq!(expanded_size(&raw), None);
+        assert_eq!(
+            image.chunks[0],
+            Chunk::Fill {
+                pattern: 0,
+                blocks: 3
+            }
+        );
+        assert!(matches!(&image.chunks[1], Chunk::Raw(data) if data.len() == 5 * 1024));
+        assert_eq!(
+            image.chunks[2],
+            Chunk::Fill {
+                pattern: 0xddccbbaa,
+                blocks: 2
+            }
+        );
+        assert_eq!(image.chunks.len(), 4);
+
+        let data = image.to_bytes();
+        assert!(is_sparse(&data));
+        let parsed = SparseImage::parse(&data).unwrap();
+        assert_eq!(parsed, image);
+        let mut expanded = parsed.to_raw();
+        assert_eq!(expanded.len(), 11 * 1024);
+        assert_eq!(expanded[..raw.len()], raw[..]);
+
+        // Every piece fits and, written over each other, they give the image.
+        let pieces = image.split(3000).unwrap();
+        assert_eq!(pieces.len(), 3);
+        expanded.fill(0);
+        for piece in &pieces {
+            let data = piece.to_bytes();
+            assert_eq!(data.len(), piece.size());
+            assert!(data.len() <= 3000);
+            let piece = SparseImage::parse(&data).unwrap();
+            assert_eq!(piece.total_blocks, 11);
+            let mut offset = 0;
+            for chunk in &piece.chunks {
+                let len = chunk.blocks(1024) as usize * 1024;
+                if !matches!(chunk, Chunk::DontCare(_)) {
+                    let single = SparseImage {
+                        block_size: 1024,
+                        total_blocks: len as u32 / 1024,
+                        chunks: vec![chunk.clone()],
+                    };
+                    expanded[offset..offset + len].copy_from_slice(&single.to_raw());
+                }
+                offset += len;
+            }
+        }
+        assert_eq!(expanded[..raw.len()], raw[..]);
+        assert_eq!(image.split(1 << 20).unwrap(), vec![image.clone()]);
+        assert!(image.split(1000).is_err());
+
+        assert!(SparseImage::parse(&data[..data.len() - 1]).is_err());
+        let mut huge = data.clone();
+        huge[20..24].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SparseImage::parse(&huge).is_err());
+        assert!(SparseImage::parse(&raw).is_err());
+        assert!(SparseImage::from_raw(&raw, 1023).is_err());
+    }
+
+    #[test]
+    fn test_split_from() {
+        let mut raw = vec![0u8; 3 * 4096];
+        raw.extend((0..5 * 4096u32).map(|i| (i % 251) as u8));
+        raw.extend([0xaa, 0xbb, 0xcc, 0xdd].repeat(2048));
+        raw.extend([1, 2, 3]);
+        let image = SparseImage::from_raw(&raw, 4096).unwrap();
+        let size = raw.len() as u64;
+
+        // Raw and sparse sources give the pieces of the image in memory.
+        for max_size in [4200, 12000, 1 << 20] {
+            let pieces = image.split(max_size).unwrap();
+            let streamed: Result<Vec<_>, _> =
+                split_from(&raw[..], size, max_size).unwrap().collect();
+            assert_eq!(streamed.unwrap(), pieces, "{max_size}");
+            let data = image.to_bytes();
+            let streamed: Result<Vec<_>, _> = split_from(&data[..], data.len() as u64, max_size)
+                .unwrap()
+                .collect();
+            assert_eq!(streamed.unwrap(), pieces, "{max_size}");
+        }
+        let empty: Vec<_> = split_from(&[][..], 0, 4200).unwrap().collect();
+        assert_eq!(empty, [SparseImage::from_raw(&[], 4096)]);
+
+        let data = image.to_bytes();
+        let truncated = &data[..data.len() - 1];
+        let mut pieces = split_from(truncated, truncated.len() as u64, 12000).unwrap();
+        assert!(pieces.any(|p| p.is_err()));
+        assert!(pieces.next().is_none());
+        assert!(split_from(&raw[..], size, 4000).is_err());
+    }
+}
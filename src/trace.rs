@@ -0,0 +1,95 @@
+//! Protocol trace logging, enabled with the `log` feature.
+//!
+//! Every command sent and every reply received is logged with the `fastboot`
+//! target, replies along with the time since their command was sent. Data
+//! phases are only logged by size, and the arguments of `oem` commands are
+//! left out, since both may carry secrets such as unlock tokens. Enabling the
+//! `trace` level adds the leading bytes of downloads and the `oem` arguments;
+//! the data of `stage`, which typically carries keys, is never shown.
+//! Without the feature, all of this compiles to nothing.
+
+#[cfg(feature = "log")]
+mod imp {
+    use std::cell::Cell;
+    use std::time::{Duration, Instant};
+
+    const TARGET: &str = "fastboot";
+    // Enough to tell e.g. a boot image or sparse image apart.
+    const PREVIEW_LEN: usize = 8;
+    // Data following these commands is never shown.
+    const REDACTED: &[&[u8]] = &[b"stage:"];
+    // Arguments of these commands are only shown at the trace level.
+    const SENSITIVE_ARGS: &str = "oem ";
+
+    thread_local! {
+        static EXCHANGE: Cell<Option<(Instant, bool)>> = const { Cell::new(None) };
+    }
+
+    fn elapsed() -> Duration {
+        EXCHANGE.with(|e| e.get().map(|(t, _)| t.elapsed()).unwrap_or_default())
+    }
+
+    fn redacted() -> bool {
+        EXCHANGE.with(|e| e.get().is_some_and(|(_, r)| r))
+    }
+
+    fn verbose() -> bool {
+        log::log_enabled!(target: TARGET, log::Level::Trace)
+    }
+
+    pub(crate) fn command(cmd: &[u8]) {
+        let redact = REDACTED.iter().any(|r| cmd.starts_with(r));
+        EXCHANGE.with(|e| e.set(Some((Instant::now(), redact))));
+        let cmd = String::from_utf8_lossy(cmd);
+        let args = cmd
+            .strip_prefix(SENSITIVE_ARGS)
+            .and_then(|c| c.split_once(' '));
+        match args {
+            Some((name, _)) if !verbose() => {
+                log::debug!(target: TARGET, "-> {SENSITIVE_ARGS}{name} [redacted]");
+            }
+            _ => log::debug!(target: TARGET, "-> {cmd}"),
+        }
+    }
+
+    pub(crate) fn reply(kind: &str, message: &str) {
+        let t = elapsed();
+        log::debug!(target: TARGET, "<- {kind} {message:?} ({t:?})");
+    }
+
+    pub(crate) fn unknown_reply(kind: &[u8], message: &str) {
+        log::warn!(target: TARGET, "<- unknown reply kind {kind:02x?}: {message:?}");
+    }
+
+    pub(crate) fn data_out(data: &[u8], total: usize) {
+        let t = elapsed();
+        if redacted() || !verbose() {
+            log::debug!(target: TARGET, "=> {total} bytes ({t:?})");
+        } else {
+            let preview = &data[..data.len().min(PREVIEW_LEN)];
+            log::debug!(target: TARGET, "=> {total} bytes {preview:02x?}... ({t:?})");
+        }
+    }
+
+    pub(crate) fn data_in(total: usize) {
+        let t = elapsed();
+        log::debug!(target: TARGET, "<= {total} bytes ({t:?})");
+    }
+}
+
+#[cfg(not(feature = "log"))]
+mod imp {
+    pub(crate) fn command(_cmd: &[u8]) {}
+
+    pub(crate) fn reply(_kind: &str, _message: &str) {}
+
+    pub(crate) fn unknown_reply(kind: &[u8], message: &str) {
+        eprintln!("Received: {kind:08x?}: {message}");
+    }
+
+    pub(crate) fn data_out(_data: &[u8], _total: usize) {}
+
+    pub(crate) fn data_in(_total: usize) {}
+}
+
+pub(crate) use imp::*;
@@ -0,0 +1,515 @@
+//! Android Verified Boot (AVB) vbmeta images.
+//!
+//! See `external/avb/libavb/avb_vbmeta_image.h` in AOSP. A vbmeta image is a
+//! 256 byte header followed by an authentication block with the hash and
+//! signature and an auxiliary block with the public key and descriptors.
+//! Descriptors tell how to verify other partitions. All fields are big
+//! endian. Images of other partitions may carry their own vbmeta, found via
+//! a footer at their end.
+
+use sha2::{Digest, Sha256, Sha512};
+
+use crate::bytes::Reader;
+
+pub const VBMETA_MAGIC: &[u8; 4] = b"AVB0";
+pub const FOOTER_MAGIC: &[u8; 4] = b"AVBf";
+
+/// Don't set up dm-verity for hashtree partitions, as `--disable-verity`.
+pub const FLAG_HASHTREE_DISABLED: u32 = 1;
+/// Don't verify anything, as `--disable-verification`.
+pub const FLAG_VERIFICATION_DISABLED: u32 = 2;
+
+const HEADER_SIZE: usize = 256;
+const FLAGS_OFFSET: usize = 120;
+const FOOTER_SIZE: usize = 64;
+
+const TAG_PROPERTY: u64 = 0;
+const TAG_HASHTREE: u64 = 1;
+const TAG_HASH: u64 = 2;
+const TAG_KERNEL_CMDLINE: u64 = 3;
+const TAG_CHAIN_PARTITION: u64 = 4;
+
+/// Describes a partition verified by hashing it as a whole.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashDescriptor {
+    pub partition_name: String,
+    pub image_size: u64,
+    pub hash_algorithm: String,
+    pub salt: Vec<u8>,
+    pub digest: Vec<u8>,
+    pub flags: u32,
+}
+
+/// Describes a partition verified block by block with dm-verity.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashtreeDescriptor {
+    pub partition_name: String,
+    pub dm_verity_version: u32,
+    pub image_size: u64,
+    pub tree_offset: u64,
+    pub tree_size: u64,
+    pub data_block_size: u32,
+    pub hash_block_size: u32,
+    pub fec_num_roots: u32,
+    pub fec_offset: u64,
+    pub fec_size: u64,
+    pub hash_algorithm: String,
+    pub salt: Vec<u8>,
+    pub root_digest: Vec<u8>,
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Descriptor {
+    Property {
+        key: String,
+        value: Vec<u8>,
+    },
+    Hashtree(HashtreeDescriptor),
+    Hash(HashDescriptor),
+    KernelCmdline {
+        flags: u32,
+        cmdline: String,
+    },
+    /// Delegates verification of a partition to the vbmeta in it, signed
+    /// with `public_key`.
+    ChainPartition {
+        partition_name: String,
+        rollback_index_location: u32,
+        public_key: Vec<u8>,
+        flags: u32,
+    },
+    Unknown {
+        tag: u64,
+        data: Vec<u8>,
+    },
+}
+
+/// A parsed vbmeta image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VbMeta {
+    pub required_libavb_version: (u32, u32),
+    /// `AvbAlgorithmType`, 0 for unsigned images
+    pub algorithm: u32,
+    pub rollback_index: u64,
+    pub flags: u32,
+    pub rollback_index_location: u32,
+    pub release: String,
+    pub public_key: Vec<u8>,
+    pub descriptors: Vec<Descriptor>,
+    /// Whether the hash in the authentication block matches the image.
+    /// The signature itself is not checked.
+    pub hash_ok: bool,
+}
+
+fn digest(algorithm: &str, parts: &[&[u8]]) -> Result<Vec<u8>, String> {
+    match algorithm {
+        "sha256" => {
+            let mut h = Sha256::new();
+            parts.iter().for_each(|p| h.update(p));
+            Ok(h.finalize().to_vec())
+        }
+        "sha512" => {
+            let mut h = Sha512::new();
+            parts.iter().for_each(|p| h.update(p));
+            Ok(h.finalize().to_vec())
+        }
+        _ => Err(format!("Unsupported hash algorithm {algorithm}")),
+    }
+}
+
+fn slice(data: &[u8], offset: u64, size: u64) -> Result<&[u8], String> {
+    usize::try_from(offset)
+        .ok()
+        .zip(usize::try_from(size).ok())
+        .and_then(|(o, s)| data.get(o..o.checked_add(s)?))
+        .ok_or_else(|| "vbmeta field out of bounds".to_owned())
+}
+
+fn string(b: &[u8]) -> String {
+    String::from_utf8_lossy(b).into_owned()
+}
+
+/// Finds the offset of the vbmeta image in `data`: at its start for vbmeta
+/// partitions, or where the footer at its end points to.
+fn find(data: &[u8]) -> Result<usize, String> {
+    if data.starts_with(VBMETA_MAGIC) {
+        return Ok(0);
+    }
+    let footer = data
+        .len()
+        .checked_sub(FOOTER_SIZE)
+        .map(|start| &data[start..])
+        .filter(|f| f.starts_with(FOOTER_MAGIC))
+        .ok_or_else(|| "No vbmeta image or AVB footer found".to_owned())?;
+    let mut r = Reader::new(&footer[12..]);
+    let _original_image_size = r.u64_be()?;
+    let offset = r.u64_be()?;
+    usize::try_from(offset)
+        .ok()
+        .filter(|&o| data.get(o..).is_some_and(|v| v.starts_with(VBMETA_MAGIC)))
+        .ok_or_else(|| "AVB footer points to no vbmeta image".to_owned())
+}
+
+fn parse_descriptor(tag: u64, body: &[u8]) -> Result<Descriptor, String> {
+    let mut r = Reader::new(body);
+    let d = match tag {
+        TAG_PROPERTY => {
+            let key_len = r.u64_be()? as usize;
+            let value_len = r.u64_be()? as usize;
+            let key = string(r.bytes(key_len)?);
+            r.bytes(1)?;
+            Descriptor::Property {
+                key,
+                value: r.bytes(value_len)?.to_vec(),
+            }
+        }
+        TAG_HASHTREE => {
+            let dm_verity_version = r.u32_be()?;
+            let image_size = r.u64_be()?;
+            let tree_offset = r.u64_be()?;
+            let tree_size = r.u64_be()?;
+            let data_block_size = r.u32_be()?;
+            let hash_block_size = r.u32_be()?;
+            let fec_num_roots = r.u32_be()?;
+            let fec_offset = r.u64_be()?;
+            let fec_size = r.u64_be()?;
+            let hash_algorithm = r.cstr(32)?;
+            let name_len = r.u32_be()? as usize;
+            let salt_len = r.u32_be()? as usize;
+            let digest_len = r.u32_be()? as usize;
+            let flags = r.u32_be()?;
+            r.bytes(60)?;
+            Descriptor::Hashtree(HashtreeDescriptor {
+                partition_name: string(r.bytes(name_len)?),
+                dm_verity_version,
+                image_size,
+                tree_offset,
+                tree_size,
+                data_block_size,
+                hash_block_size,
+                fec_num_roots,
+                fec_offset,
+                fec_size,
+                hash_algorithm,
+                salt: r.bytes(salt_len)?.to_vec(),
+                root_digest: r.bytes(digest_len)?.to_vec(),
+                flags,
+            })
+        }
+        TAG_HASH => {
+            let image_size = r.u64_be()?;
+            let hash_algorithm = r.cstr(32)?;
+            let name_len = r.u32_be()? as usize;
+            let salt_len = r.u32_be()? as usize;
+            let digest_len = r.u32_be()? as usize;
+            let flags = r.u32_be()?;
+            r.bytes(60)?;
+            Descriptor::Hash(HashDescriptor {
+                partition_name: string(r.bytes(name_len)?),
+                image_size,
+                hash_algorithm,
+                salt: r.bytes(salt_len)?.to_vec(),
+                digest: r.bytes(digest_len)?.to_vec(),
+                flags,
+            })
+        }
+        TAG_KERNEL_CMDLINE => {
+            let flags = r.u32_be()?;
+            let len = r.u32_be()? as usize;
+            Descriptor::KernelCmdline {
+                flags,
+                cmdline: string(r.bytes(len)?),
+            }
+        }
+        TAG_CHAIN_PARTITION => {
+            let rollback_index_location = r.u32_be()?;
+            let name_len = r.u32_be()? as usize;
+            let key_len = r.u32_be()? as usize;
+            let flags = r.u32_be()?;
+            r.bytes(60)?;
+            Descriptor::ChainPartition {
+                partition_name: string(r.bytes(name_len)?),
+                rollback_index_location,
+                public_key: r.bytes(key_len)?.to_vec(),
+                flags,
+            }
+        }
+        _ => Descriptor::Unknown {
+            tag,
+            data: body.to_vec(),
+        },
+    };
+    Ok(d)
+}
+
+impl VbMeta {
+    /// Parses a vbmeta image, or the vbmeta in the footer of another image.
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let data = &data[find(data)?..];
+        let mut r = Reader::new(data);
+        r.bytes(VBMETA_MAGIC.len())?;
+        let required_libavb_version = (r.u32_be()?, r.u32_be()?);
+        let auth_size = r.u64_be()?;
+        let aux_size = r.u64_be()?;
+        let algorithm = r.u32_be()?;
+        let hash = (r.u64_be()?, r.u64_be()?);
+        let _signature = (r.u64_be()?, r.u64_be()?);
+        let public_key = (r.u64_be()?, r.u64_be()?);
+        let _public_key_metadata = (r.u64_be()?, r.u64_be()?);
+        let descriptors = (r.u64_be()?, r.u64_be()?);
+        let rollback_index = r.u64_be()?;
+        let flags = r.u32_be()?;
+        let rollback_index_location = r.u32_be()?;
+        let release = r.cstr(48)?;
+
+        let header = slice(data, 0, HEADER_SIZE as u64)?;
+        let auth = slice(data, HEADER_SIZE as u64, auth_size)?;
+        let aux = slice(data, HEADER_SIZE as u64 + auth_size, aux_size)?;
+
+        let hash_ok = match algorithm {
+            0 => true,
+            1..=3 => slice(auth, hash.0, hash.1)? == digest("sha256", &[header, aux])?,
+            4..=6 => slice(auth, hash.0, hash.1)? == digest("sha512", &[header, aux])?,
+            _ => return Err(format!("Unknown vbmeta algorithm {algorithm}")),
+        };
+
+        let mut list = Vec::new();
+        let mut r = Reader::new(slice(aux, descriptors.0, descriptors.1)?);
+        while let Ok(tag) = r.u64_be() {
+            let len = r.u64_be()?;
+            let body = r.bytes(usize::try_from(len).map_err(|_| "Invalid descriptor")?)?;
+            list.push(parse_descriptor(tag, body)?);
+        }
+
+        Ok(VbMeta {
+            required_libavb_version,
+            algorithm,
+            rollback_index,
+            flags,
+            rollback_index_location,
+            release,
+            public_key: slice(aux, public_key.0, public_key.1)?.to_vec(),
+            descriptors: list,
+            hash_ok,
+        })
+    }
+
+    /// Finds the hash descriptor of a partition. A slot suffix on
+    /// `partition` is ignored, as descriptors name partitions without it.
+    pub fn hash_descriptor(&self, partition: &str) -> Option<&HashDescriptor> {
+        let base = partition
+            .strip_suffix("_a")
+            .or_else(|| partition.strip_suffix("_b"))
+            .unwrap_or(partition);
+        self.descriptors.iter().find_map(|d| match d {
+            Descriptor::Hash(h) if h.partition_name == partition || h.partition_name == base => {
+                Some(h)
+            }
+            _ => None,
+        })
+    }
+
+    /// Checks an image against the hash descriptor of its partition.
+    pub fn verify_image(&self, partition: &str, image: &[u8]) -> Result<(), String> {
+        self.hash_descriptor(partition)
+            .ok_or_else(|| format!("vbmeta has no hash descriptor for {partition}"))?
+            .verify(image)
+    }
+}
+
+impl HashDescriptor {
+    /// Checks the digest of `image`. Only the first `image_size` bytes are
+    /// hashed, so an image may carry an AVB footer.
+    pub fn verify(&self, image: &[u8]) -> Result<(), String> {
+        let name = &self.partition_name;
+        let content = usize::try_from(self.image_size)
+            .ok()
+            .and_then(|size| image.get(..size))
+            .ok_or_else(|| format!("{name}: image is smaller than {}", self.image_size))?;
+        match digest(&self.hash_algorithm, &[&self.salt, content])? == self.digest {
+            true => Ok(()),
+            false => Err(format!("{name}: digest does not match vbmeta")),
+        }
+    }
+}
+
+fn flags_mut(data: &mut [u8]) -> Result<&mut [u8], String> {
+    let offset = find(data)? + FLAGS_OFFSET;
+    data.get_mut(offset..offset + 4)
+        .ok_or_else(|| "vbmeta header is truncated".to_owned())
+}
+
+/// Replaces the header flags of the vbmeta image in `data` in place, e.g.
+/// with [`FLAG_HASHTREE_DISABLED`] | [`FLAG_VERIFICATION_DISABLED`]. The
+/// flags are not covered by the signature check of unlocked devices.
+pub fn set_flags(data: &mut [u8], flags: u32) -> Result<(), String> {
+    flags_mut(data)?.copy_from_slice(&flags.to_be_bytes());
+    Ok(())
+}
+
+/// Like [`set_flags`], but keeps the flags already set in the header, as
+/// `fastboot --disable-verity` does.
+pub fn add_flags(data: &mut [u8], flags: u32) -> Result<(), String> {
+    let field = flags_mut(data)?;
+    let old = u32::from_be_bytes(field[..].try_into().unwrap());
+    field.copy_from_slice(&(old | flags).to_be_bytes());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        add_flags, set_flags, Descriptor, VbMeta, FLAG_HASHTREE_DISABLED,
+        FLAG_VERIFICATION_DISABLED,
+    };
+    use sha2::{Digest, Sha256};
+
+    fn descriptor(out: &mut Vec<u8>, tag: u64, body: &[u8]) {
+        let len = body.len().next_multiple_of(8);
+        out.extend(tag.to_be_bytes());
+        out.extend((len as u64).to_be_bytes());
+        out.extend(body);
+        out.resize(out.len() + len - body.len(), 0);
+    }
+
+    fn hash_descriptor(name: &str, salt: &[u8], image: &[u8]) -> Vec<u8> {
+        let mut d = Vec::new();
+        d.extend((image.len() as u64).to_be_bytes());
+        d.extend(b"sha256");
+        d.resize(8 + 32, 0);
+        d.extend((name.len() as u32).to_be_bytes());
+        d.extend((salt.len() as u32).to_be_bytes());
+        d.extend(32u32.to_be_bytes());
+        d.extend([0; 64]);
+        d.extend(name.as_bytes());
+        d.extend(salt);
+        d.extend(
+            Sha256::new()
+                .chain_update(salt)
+                .chain_update(image)
+                .finalize(),
+        );
+        d
+    }
+
+    // Like `avbtool make_vbmeta_image --algorithm SHA256_RSA2048`, except
+    // for the signature, which is left empty.
+    fn vbmeta(boot: &[u8]) -> Vec<u8> {
+        let mut aux = Vec::new();
+        descriptor(&mut aux, 2, &hash_descriptor("boot", b"salt", boot));
+        let mut cmdline = Vec::new();
+        cmdline.extend(0u32.to_be_bytes());
+        cmdline.extend(13u32.to_be_bytes());
+        cmdline.extend(b"console=ttyS0");
+        descriptor(&mut aux, 3, &cmdline);
+        let mut property = Vec::new();
+        property.extend(3u64.to_be_bytes());
+        property.extend(2u64.to_be_bytes());
+        property.extend(b"key\0v1\0");
+        descriptor(&mut aux, 0, &property);
+        let descriptors = aux.len() as u64;
+        aux.extend([0xaa; 8]);
+
+        let mut header = Vec::new();
+        header.extend(b"AVB0");
+        header.extend(1u32.to_be_bytes());
+        header.extend(0u32.to_be_bytes());
+        header.extend(64u64.to_be_bytes());
+        header.extend((aux.len() as u64).to_be_bytes());
+        header.extend(1u32.to_be_bytes());
+        for field in [
+            0,
+            32,
+            32,
+            32,
+            descriptors,
+            8,
+            descriptors + 8,
+            0,
+            0,
+            descriptors,
+        ] {
+            header.extend(field.to_be_bytes());
+        }
+        header.extend(7u64.to_be_bytes());
+        header.extend(0u32.to_be_bytes());
+        header.extend(0u32.to_be_bytes());
+        header.extend(b"avbtool 1.2.0");
+        header.resize(256, 0);
+
+        let mut auth = Sha256::new()
+            .chain_update(&header)
+            .chain_update(&aux)
+            .finalize()
+            .to_vec();
+        auth.resize(64, 0);
+        [header, auth, aux].concat()
+    }
+
+    #[test]
+    fn test_parse() {
+        let boot = vec![0x42; 3000];
+        let data = vbmeta(&boot);
+        let vbmeta = VbMeta::parse(&data).unwrap();
+        assert!(vbmeta.hash_ok);
+        assert_eq!(vbmeta.required_libavb_version, (1, 0));
+        assert_eq!(vbmeta.rollback_index, 7);
+        assert_eq!(vbmeta.release, "avbtool 1.2.0");
+        assert_eq!(vbmeta.public_key, [0xaa; 8]);
+        assert_eq!(vbmeta.descriptors.len(), 3);
+        assert_eq!(
+            vbmeta.descriptors[1],
+            Descriptor::KernelCmdline {
+                flags: 0,
+                cmdline: "console=ttyS0".to_owned()
+            }
+        );
+        assert_eq!(
+            vbmeta.descriptors[2],
+            Descriptor::Property {
+                key: "key".to_owned(),
+                value: b"v1".to_vec()
+            }
+        );
+
+        assert_eq!(Ok(()), vbmeta.verify_image("boot", &boot));
+        assert_eq!(
+            Ok(()),
+            vbmeta.verify_image("boot_b", &[&boot[..], b"AVBf"].concat())
+        );
+        assert!(vbmeta.verify_image("boot", &boot[1..]).is_err());
+        assert!(vbmeta.verify_image("boot", &[0x43; 3000]).is_err());
+        assert!(vbmeta.verify_image("vendor_boot", &boot).is_err());
+
+        let mut tampered = data.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(!VbMeta::parse(&tampered).unwrap().hash_ok);
+    }
+
+    #[test]
+    fn test_footer_and_flags() {
+        let boot = vec![0x42; 4096];
+        let mut image = boot.clone();
+        image.extend(vbmeta(&boot));
+        image.resize(12288 - 64, 0);
+        image.extend(b"AVBf");
+        image.extend(1u32.to_be_bytes());
+        image.extend(0u32.to_be_bytes());
+        image.extend(4096u64.to_be_bytes());
+        image.extend(4096u64.to_be_bytes());
+        image.resize(12288, 0);
+
+        let vbmeta = VbMeta::parse(&image).unwrap();
+        assert_eq!(Ok(()), vbmeta.verify_image("boot", &image));
+
+        let flags = FLAG_HASHTREE_DISABLED | FLAG_VERIFICATION_DISABLED;
+        set_flags(&mut image, flags).unwrap();
+        assert_eq!(VbMeta::parse(&image).unwrap().flags, 3);
+        set_flags(&mut image, FLAG_HASHTREE_DISABLED).unwrap();
+        add_flags(&mut image, 4).unwrap();
+        assert_eq!(VbMeta::parse(&image).unwrap().flags, 5);
+        assert!(set_flags(&mut boot.clone(), 2).is_err());
+        assert!(add_flags(&mut boot.clone(), 2).is_err());
+    }
+}
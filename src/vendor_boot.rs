@@ -0,0 +1,426 @@
+//! Android vendor boot images (`vendor_boot.img`), header versions 3 and 4.
+//!
+//! See `system/tools/mkbootimg/include/bootimg/bootimg.h` in AOSP. The image
+//! carries what the generic boot image of version 3 and later leaves out:
+//! load addresses, the vendor ramdisk, the DTB and the vendor part of the
+//! kernel command line. Version 4 splits the vendor ramdisk into fragments,
+//! listed in the vendor ramdisk table, and adds a bootconfig section.
+
+use crate::bytes::{align_up, pad_to, put_cstr, Reader};
+
+pub const VENDOR_BOOT_MAGIC: &[u8; 8] = b"VNDRBOOT";
+
+const CMDLINE_SIZE: usize = 2048;
+const NAME_SIZE: usize = 16;
+const RAMDISK_NAME_SIZE: usize = 32;
+const BOARD_ID_SIZE: usize = 16;
+
+const V3_HEADER_SIZE: usize = 2112;
+const V4_HEADER_SIZE: usize = 2128;
+const TABLE_ENTRY_SIZE: usize = 108;
+
+/// What a vendor ramdisk fragment is for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamdiskType {
+    None,
+    Platform,
+    Recovery,
+    /// Vendor kernel modules
+    Dlkm,
+    Other(u32),
+}
+
+impl From<u32> for RamdiskType {
+    fn from(t: u32) -> Self {
+        match t {
+            0 => RamdiskType::None,
+            1 => RamdiskType::Platform,
+            2 => RamdiskType::Recovery,
+            3 => RamdiskType::Dlkm,
+            t => RamdiskType::Other(t),
+        }
+    }
+}
+
+impl From<RamdiskType> for u32 {
+    fn from(t: RamdiskType) -> Self {
+        match t {
+            RamdiskType::None => 0,
+            RamdiskType::Platform => 1,
+            RamdiskType::Recovery => 2,
+            RamdiskType::Dlkm => 3,
+            RamdiskType::Other(t) => t,
+        }
+    }
+}
+
+/// A fragment of the vendor ramdisk. Version 3 images have exactly one,
+/// without a type or name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VendorRamdisk {
+    pub kind: RamdiskType,
+    pub name: String,
+    pub board_id: [u32; BOARD_ID_SIZE],
+    pub data: Vec<u8>,
+}
+
+impl VendorRamdisk {
+    pub fn new(kind: RamdiskType, name: &str, data: Vec<u8>) -> Self {
+        VendorRamdisk {
+            kind,
+            name: name.to_owned(),
+            board_id: [0; BOARD_ID_SIZE],
+            data,
+        }
+    }
+}
+
+/// A parsed vendor boot image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VendorBootImage {
+    pub header_version: u32,
+    pub page_size: u32,
+    pub kernel_addr: u32,
+    pub ramdisk_addr: u32,
+    pub tags_addr: u32,
+    pub dtb_addr: u64,
+    /// Vendor part of the kernel command line
+    pub cmdline: String,
+    /// Product name
+    pub name: String,
+    /// Vendor ramdisk fragments, concatenated in this order
+    pub ramdisks: Vec<VendorRamdisk>,
+    pub dtb: Vec<u8>,
+    /// Bootconfig parameters, as `key = value` lines (version 4)
+    pub bootconfig: String,
+    /// Anything after the last section, e.g. an AVB footer.
+    pub tail: Vec<u8>,
+}
+
+impl VendorBootImage {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let mut r = Reader::new(data);
+        if r.bytes(8)? != VENDOR_BOOT_MAGIC {
+            return Err("Not a vendor boot image".to_owned());
+        }
+        let header_version = r.u32_le()?;
+        if !(3..=4).contains(&header_version) {
+            return Err(format!(
+                "Unsupported vendor boot header version {header_version}"
+            ));
+        }
+        let page_size = r.u32_le()?;
+        if page_size == 0 || !page_size.is_power_of_two() {
+            return Err(format!("Invalid page size {page_size}"));
+        }
+        let kernel_addr = r.u32_le()?;
+        let ramdisk_addr = r.u32_le()?;
+        let ramdisk_size = r.u32_le()? as usize;
+        let cmdline = r.cstr(CMDLINE_SIZE)?;
+        let tags_addr = r.u32_le()?;
+        let name = r.cstr(NAME_SIZE)?;
+        r.u32_le()?; // header_size
+        let dtb_size = r.u32_le()? as usize;
+        let dtb_addr = r.u64_le()?;
+        let (table_size, entry_num, entry_size, bootconfig_size) = match header_version {
+            3 => (0, 0, 0, 0),
+            _ => (
+                r.u32_le()? as usize,
+                r.u32_le()? as usize,
+                r.u32_le()? as usize,
+                r.u32_le()? as usize,
+            ),
+        };
+
+        // Sections follow the header, each starting on a page boundary
+        let page = page_size as usize;
+        let header_size = match header_version {
+            3 => V3_HEADER_SIZE,
+            _ => V4_HEADER_SIZE,
+        };
+        let mut offset = align_up(header_size, page);
+        let mut section = |size: usize| -> Result<&[u8], String> {
+            let s = data
+                .get(offset..offset + size)
+                .ok_or_else(|| "Vendor boot image is truncated".to_owned())?;
+            offset += align_up(size, page);
+            Ok(s)
+        };
+        let ramdisk = section(ramdisk_size)?;
+        let dtb = section(dtb_size)?.to_vec();
+        let table = section(table_size)?;
+        let bootconfig = section(bootconfig_size)?;
+        let bootconfig = String::from_utf8(bootconfig.to_vec())
+            .map_err(|_| "Bootconfig is not valid UTF-8".to_owned())?;
+        let tail = data.get(offset..).unwrap_or(&[]).to_vec();
+
+        let ramdisks = match header_version {
+            3 => vec![VendorRamdisk::new(RamdiskType::None, "", ramdisk.to_vec())],
+            _ => {
+                if entry_size < TABLE_ENTRY_SIZE || entry_num * entry_size > table_size {
+                    return Err("Invalid vendor ramdisk table".to_owned());
+                }
+                let mut ramdisks = Vec::with_capacity(entry_num);
+                for e in table.chunks(entry_size).take(entry_num) {
+                    let mut r = Reader::new(e);
+                    let size = r.u32_le()? as usize;
+                    let off = r.u32_le()? as usize;
+                    let kind = RamdiskType::from(r.u32_le()?);
+                    let name = r.cstr(RAMDISK_NAME_SIZE)?;
+                    let mut board_id = [0; BOARD_ID_SIZE];
+                    for id in board_id.iter_mut() {
+                        *id = r.u32_le()?;
+                    }
+                    let data = ramdisk
+                        .get(off..off + size)
+                        .ok_or_else(|| format!("Vendor ramdisk {name} out of bounds"))?
+                        .to_vec();
+                    ramdisks.push(VendorRamdisk {
+                        kind,
+                        name,
+                        board_id,
+                        data,
+                    });
+                }
+                ramdisks
+            }
+        };
+
+        Ok(VendorBootImage {
+            header_version,
+            page_size,
+            kernel_addr,
+            ramdisk_addr,
+            tags_addr,
+            dtb_addr,
+            cmdline,
+            name,
+            ramdisks,
+            dtb,
+            bootconfig,
+            tail,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let v = self.header_version;
+        let page = self.page_size as usize;
+        if page == 0 || !page.is_power_of_two() {
+            return Err(format!("Invalid page size {page}"));
+        }
+        if v == 3 && self.ramdisks.len() > 1 {
+            return Err("Version 3 supports a single vendor ramdisk".to_owned());
+        }
+        let size = |n: usize| -> Result<[u8; 4], String> {
+            u32::try_from(n)
+                .map(u32::to_le_bytes)
+                .map_err(|_| "Section exceeds 4 GiB".to_owned())
+        };
+        let ramdisk_size: usize = self.ramdisks.iter().map(|r| r.data.len()).sum();
+
+        let mut out = Vec::new();
+        out.extend_from_slice(VENDOR_BOOT_MAGIC);
+        out.extend(v.to_le_bytes());
+        out.extend(self.page_size.to_le_bytes());
+        out.extend(self.kernel_addr.to_le_bytes());
+        out.extend(self.ramdisk_addr.to_le_bytes());
+        out.extend(size(ramdisk_size)?);
+        put_cstr(&mut out, &self.cmdline, CMDLINE_SIZE)?;
+        out.extend(self.tags_addr.to_le_bytes());
+        put_cstr(&mut out, &self.name, NAME_SIZE)?;
+        let header_size = match v {
+            3 => V3_HEADER_SIZE,
+            4 => V4_HEADER_SIZE,
+            v => return Err(format!("Unsupported vendor boot header version {v}")),
+        };
+        out.extend(size(header_size)?);
+        out.extend(size(self.dtb.len())?);
+        out.extend(self.dtb_addr.to_le_bytes());
+
+        let mut table = Vec::new();
+        if v == 4 {
+            let mut offset = 0;
+            for rd in &self.ramdisks {
+                table.extend(size(rd.data.len())?);
+                table.extend(size(offset)?);
+                table.extend(u32::from(rd.kind).to_le_bytes());
+                put_cstr(&mut table, &rd.name, RAMDISK_NAME_SIZE)?;
+                for id in rd.board_id {
+                    table.extend(id.to_le_bytes());
+                }
+                offset += rd.data.len();
+            }
+            out.extend(size(table.len())?);
+            out.extend(size(self.ramdisks.len())?);
+            out.extend(size(TABLE_ENTRY_SIZE)?);
+            out.extend(size(self.bootconfig.len())?);
+        }
+        debug_assert_eq!(out.len(), header_size);
+        pad_to(&mut out, page);
+
+        for rd in &self.ramdisks {
+            out.extend_from_slice(&rd.data);
+        }
+        pad_to(&mut out, page);
+        out.extend_from_slice(&self.dtb);
+        pad_to(&mut out, page);
+        out.extend_from_slice(&table);
+        pad_to(&mut out, page);
+        out.extend_from_slice(self.bootconfig.as_bytes());
+        pad_to(&mut out, page);
+        out.extend_from_slice(&self.tail);
+        Ok(out)
+    }
+
+    /// Gets a vendor ramdisk fragment by name.
+    pub fn ramdisk(&self, name: &str) -> Option<&VendorRamdisk> {
+        self.ramdisks.iter().find(|r| r.name == name)
+    }
+
+    /// Adds a vendor ramdisk fragment, or replaces the one of the same name.
+    pub fn put_ramdisk(&mut self, ramdisk: VendorRamdisk) {
+        match self.ramdisks.iter_mut().find(|r| r.name == ramdisk.name) {
+            Some(r) => *r = ramdisk,
+            None => self.ramdisks.push(ramdisk),
+        }
+    }
+
+    /// Removes a vendor ramdisk fragment by name.
+    pub fn remove_ramdisk(&mut self, name: &str) -> Option<VendorRamdisk> {
+        let i = self.ramdisks.iter().position(|r| r.name == name)?;
+        Some(self.ramdisks.remove(i))
+    }
+
+    /// Gets the value of a bootconfig parameter.
+    pub fn bootconfig_get(&self, key: &str) -> Option<&str> {
+        self.bootconfig
+            .lines()
+            .filter_map(split_param)
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Sets a bootconfig parameter, replacing an existing one of that key.
+    pub fn bootconfig_set(&mut self, key: &str, value: &str) {
+        let line = format!("{key} = {value}");
+        let mut found = false;
+        let mut lines: Vec<String> = self
+            .bootconfig
+            .lines()
+            .map(|l| match split_param(l) {
+                Some((k, _)) if k == key => {
+                    found = true;
+                    line.clone()
+                }
+                _ => l.to_owned(),
+            })
+            .collect();
+        if !found {
+            lines.push(line);
+        }
+        self.bootconfig = lines.join("\n") + "\n";
+    }
+
+    /// Removes a bootconfig parameter.
+    pub fn bootconfig_remove(&mut self, key: &str) {
+        let lines: Vec<&str> = self
+            .bootconfig
+            .lines()
+            .filter(|l| !matches!(split_param(l), Some((k, _)) if k == key))
+            .collect();
+        self.bootconfig = match lines.is_empty() {
+            true => String::new(),
+            false => lines.join("\n") + "\n",
+        };
+    }
+}
+
+fn split_param(line: &str) -> Option<(&str, &str)> {
+    let (k, v) = line.split_once('=')?;
+    Some((k.trim(), v.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RamdiskType, VendorBootImage, VendorRamdisk};
+
+    fn image(header_version: u32) -> VendorBootImage {
+        VendorBootImage {
+            header_version,
+            page_size: 4096,
+            kernel_addr: 0x4008_0000,
+            ramdisk_addr: 0x4600_0000,
+            tags_addr: 0x4400_0000,
+            dtb_addr: 0x4800_0000,
+            cmdline: "androidboot.hardware=k1".to_owned(),
+            name: "k1".to_owned(),
+            ramdisks: vec![VendorRamdisk::new(RamdiskType::None, "", vec![1; 5000])],
+            dtb: vec![0xd0, 0x0d, 0xfe, 0xed],
+            bootconfig: String::new(),
+            tail: vec![0xff; 16],
+        }
+    }
+
+    #[test]
+    fn test_round_trip_v3() {
+        let img = image(3);
+        let data = img.to_bytes().unwrap();
+        assert_eq!(data.len(), 4 * 4096 + 16);
+        assert_eq!(&data[2096..2100], &2112u32.to_le_bytes());
+
+        let parsed = VendorBootImage::parse(&data).unwrap();
+        assert_eq!(parsed, img);
+        assert_eq!(parsed.to_bytes().unwrap(), data);
+    }
+
+    #[test]
+    fn test_round_trip_v4() {
+        let mut img = image(4);
+        img.ramdisks[0].kind = RamdiskType::Platform;
+        img.ramdisks[0].name = "platform".to_owned();
+        let mut dlkm = VendorRamdisk::new(RamdiskType::Dlkm, "dlkm", vec![2; 300]);
+        dlkm.board_id[0] = 0x1234;
+        img.ramdisks.push(dlkm);
+        img.bootconfig = "androidboot.serialno = 1234\n".to_owned();
+
+        let data = img.to_bytes().unwrap();
+        assert_eq!(&data[2096..2100], &2128u32.to_le_bytes());
+        // Table: two entries of 108 bytes
+        assert_eq!(&data[2112..2116], &216u32.to_le_bytes());
+
+        let parsed = VendorBootImage::parse(&data).unwrap();
+        assert_eq!(parsed, img);
+        assert_eq!(parsed.to_bytes().unwrap(), data);
+        assert_eq!(parsed.ramdisk("dlkm").unwrap().data, vec![2; 300]);
+    }
+
+    #[test]
+    fn test_edit() {
+        let mut img = image(4);
+        img.put_ramdisk(VendorRamdisk::new(RamdiskType::Recovery, "rec", vec![3]));
+        img.put_ramdisk(VendorRamdisk::new(RamdiskType::Recovery, "rec", vec![4]));
+        assert_eq!(img.ramdisks.len(), 2);
+        assert_eq!(img.ramdisk("rec").unwrap().data, vec![4]);
+        assert!(img.remove_ramdisk("rec").is_some());
+        assert!(img.ramdisk("rec").is_none());
+
+        img.bootconfig_set("androidboot.console", "ttyS0");
+        img.bootconfig_set("androidboot.baudrate", "115200");
+        img.bootconfig_set("androidboot.console", "ttyS1");
+        assert_eq!(img.bootconfig_get("androidboot.console"), Some("ttyS1"));
+        img.bootconfig_remove("androidboot.console");
+        assert_eq!(img.bootconfig, "androidboot.baudrate = 115200\n");
+
+        let parsed = VendorBootImage::parse(&img.to_bytes().unwrap()).unwrap();
+        assert_eq!(
+            parsed.bootconfig_get("androidboot.baudrate"),
+            Some("115200")
+        );
+
+        assert!(image(3).to_bytes().is_ok());
+        let mut v3 = image(3);
+        v3.ramdisks
+            .push(VendorRamdisk::new(RamdiskType::Dlkm, "x", vec![]));
+        assert!(v3.to_bytes().is_err());
+    }
+}
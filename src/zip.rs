@@ -0,0 +1,303 @@
+//! A minimal zip reader for update packages.
+//!
+//! Only what `fastboot update` packages need is supported: stored and
+//! deflated entries, optionally with zip64 sizes and offsets. Entries are
+//! streamed out of the archive, so large images need not fit in memory.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use flate2::read::DeflateDecoder;
+
+use crate::bytes::{crc32_update, Reader};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const EOCD_SIG: u32 = 0x0605_4b50;
+const ZIP64_EOCD_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const ZIP64_EXTRA_ID: u16 = 1;
+
+const EOCD_SIZE: usize = 22;
+const ZIP64_LOCATOR_SIZE: usize = 20;
+const LOCAL_HEADER_SIZE: usize = 30;
+const MAX_COMMENT: usize = u16::MAX as usize;
+
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ZipEntry {
+    pub(crate) name: String,
+    method: u16,
+    crc: u32,
+    compressed_size: u64,
+    pub(crate) size: u64,
+    header_offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ZipArchive {
+    path: PathBuf,
+    pub(crate) entries: Vec<ZipEntry>,
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Finds the central directory: its offset and number of entries.
+fn central_directory(file: &mut File) -> Result<(u64, u64), String> {
+    let len = file.seek(SeekFrom::End(0)).map_err(|e| e.to_string())?;
+    let tail_len = len.min((EOCD_SIZE + MAX_COMMENT) as u64) as usize;
+    let tail = read_at(file, len - tail_len as u64, tail_len).map_err(|e| e.to_string())?;
+    let eocd = (0..tail_len.saturating_sub(EOCD_SIZE - 1))
+        .rev()
+        .find(|&i| tail[i..].starts_with(&EOCD_SIG.to_le_bytes()))
+        .ok_or_else(|| "Not a zip archive".to_owned())?;
+
+    let mut r = Reader::new(&tail[eocd + 10..]);
+    let entries = r.u16_le()? as u64;
+    let _size = r.u32_le()?;
+    let offset = r.u32_le()? as u64;
+    if entries != u16::MAX as u64 && offset != u32::MAX as u64 {
+        return Ok((offset, entries));
+    }
+
+    // Zip64: a locator right before the end of central directory record
+    // points to the zip64 one.
+    let locator = eocd
+        .checked_sub(ZIP64_LOCATOR_SIZE)
+        .map(|l| &tail[l..eocd])
+        .filter(|l| l.starts_with(&ZIP64_LOCATOR_SIG.to_le_bytes()))
+        .ok_or_else(|| "Missing zip64 locator".to_owned())?;
+    let eocd64 = Reader::new(&locator[8..]).u64_le()?;
+    let record = read_at(file, eocd64, 56).map_err(|e| e.to_string())?;
+    let mut r = Reader::new(&record);
+    if r.u32_le()? != ZIP64_EOCD_SIG {
+        return Err("Invalid zip64 end of central directory".to_owned());
+    }
+    r.bytes(20)?;
+    let _entries_on_disk = r.u64_le()?;
+    let entries = r.u64_le()?;
+    let _size = r.u64_le()?;
+    Ok((r.u64_le()?, entries))
+}
+
+impl ZipArchive {
+    pub(crate) fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref().to_owned();
+        let err = |e: io::Error| format!("{}: {e}", path.display());
+        let mut file = File::open(&path).map_err(err)?;
+        let (offset, count) = central_directory(&mut file)?;
+        file.seek(SeekFrom::Start(offset)).map_err(err)?;
+        let mut file = io::BufReader::new(file);
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let mut header = [0; 46];
+            file.read_exact(&mut header).map_err(err)?;
+            let mut r = Reader::new(&header);
+            if r.u32_le()? != CENTRAL_HEADER_SIG {
+                return Err("Invalid zip central directory".to_owned());
+            }
+            r.bytes(4)?;
+            let flags = r.u16_le()?;
+            let method = r.u16_le()?;
+            r.bytes(4)?;
+            let crc = r.u32_le()?;
+            let mut compressed_size = r.u32_le()? as u64;
+            let mut size = r.u32_le()? as u64;
+            let name_len = r.u16_le()? as usize;
+            let extra_len = r.u16_le()? as usize;
+            let comment_len = r.u16_le()? as usize;
+            r.bytes(8)?;
+            let mut header_offset = r.u32_le()? as u64;
+
+            let mut rest = vec![0; name_len + extra_len + comment_len];
+            file.read_exact(&mut rest).map_err(err)?;
+            let name = String::from_utf8_lossy(&rest[..name_len]).into_owned();
+            if flags & 1 != 0 {
+                return Err(format!("{name}: encrypted entries are not supported"));
+            }
+
+            // Sizes and offsets that do not fit are in the zip64 extra field,
+            // in this order.
+            let mut extra = Reader::new(&rest[name_len..name_len + extra_len]);
+            while let (Ok(id), Ok(len)) = (extra.u16_le(), extra.u16_le()) {
+                let mut field = Reader::new(extra.bytes(len as usize)?);
+                if id != ZIP64_EXTRA_ID {
+                    continue;
+                }
+                for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                    if *value == u32::MAX as u64 {
+                        *value = field.u64_le()?;
+                    }
+                }
+            }
+            entries.push(ZipEntry {
+                name,
+                method,
+                crc,
+                compressed_size,
+                size,
+                header_offset,
+            });
+        }
+        Ok(ZipArchive { path, entries })
+    }
+
+    pub(crate) fn entry(&self, name: &str) -> Option<&ZipEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+
+    /// Streams the contents of an entry. The read that reaches the end fails
+    /// if the data does not match its CRC.
+    pub(crate) fn open_entry(&self, name: &str) -> Result<impl Read, String> {
+        let entry = self
+            .entry(name)
+            .ok_or_else(|| format!("{name}: not in {}", self.path.display()))?;
+        let err = |e: io::Error| format!("{}: {e}", self.path.display());
+        let mut file = File::open(&self.path).map_err(err)?;
+        let header = read_at(&mut file, entry.header_offset, LOCAL_HEADER_SIZE).map_err(err)?;
+        let mut r = Reader::new(&header);
+        if r.u32_le()? != LOCAL_HEADER_SIG {
+            return Err(format!("{name}: invalid local header"));
+        }
+        r.bytes(22)?;
+        let data = entry.header_offset
+            + LOCAL_HEADER_SIZE as u64
+            + r.u16_le()? as u64
+            + r.u16_le()? as u64;
+        file.seek(SeekFrom::Start(data)).map_err(err)?;
+
+        let raw = io::BufReader::new(file).take(entry.compressed_size);
+        let inner: Box<dyn Read> = match entry.method {
+            STORED => Box::new(raw),
+            DEFLATED => Box::new(DeflateDecoder::new(raw)),
+            m => return Err(format!("{name}: unsupported compression method {m}")),
+        };
+        Ok(CrcReader {
+            inner: inner.take(entry.size),
+            crc: 0,
+            expected: entry.crc,
+            remaining: entry.size,
+        })
+    }
+}
+
+struct CrcReader<R> {
+    inner: R,
+    crc: u32,
+    expected: u32,
+    remaining: u64,
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    // The read that delivers the last byte fails on a mismatch, as callers
+    // like `read_exact` never read past the end.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc32_update(self.crc, &buf[..n]);
+        self.remaining -= n as u64;
+        if n == 0 && !buf.is_empty() && self.remaining > 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if !buf.is_empty() && self.remaining == 0 && self.crc != self.expected {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "CRC mismatch"));
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::ZipArchive;
+    use crate::bytes::crc32;
+    use flate2::write::DeflateEncoder;
+    use flate2::Compression;
+    use std::io::{Read, Write};
+
+    /// Builds a zip archive, deflating the entries whose name ends in `.img`.
+    pub(crate) fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for (name, data) in files {
+            let (method, stored) = match name.ends_with(".img") {
+                true => {
+                    let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
+                    e.write_all(data).unwrap();
+                    (8u16, e.finish().unwrap())
+                }
+                false => (0, data.to_vec()),
+            };
+            let mut fields = Vec::new();
+            fields.extend(20u16.to_le_bytes());
+            fields.extend(0u16.to_le_bytes());
+            fields.extend(method.to_le_bytes());
+            fields.extend([0; 4]);
+            fields.extend(crc32(data).to_le_bytes());
+            fields.extend((stored.len() as u32).to_le_bytes());
+            fields.extend((data.len() as u32).to_le_bytes());
+            fields.extend((name.len() as u16).to_le_bytes());
+            fields.extend(0u16.to_le_bytes());
+
+            central.extend(0x0201_4b50u32.to_le_bytes());
+            central.extend(20u16.to_le_bytes());
+            central.extend(&fields);
+            central.extend([0; 10]);
+            central.extend((out.len() as u32).to_le_bytes());
+            central.extend(name.as_bytes());
+
+            out.extend(0x0403_4b50u32.to_le_bytes());
+            out.extend(&fields);
+            out.extend(name.as_bytes());
+            out.extend(stored);
+        }
+        let offset = out.len() as u32;
+        out.extend(&central);
+        out.extend(0x0605_4b50u32.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend((files.len() as u16).to_le_bytes());
+        out.extend((files.len() as u16).to_le_bytes());
+        out.extend((central.len() as u32).to_le_bytes());
+        out.extend(offset.to_le_bytes());
+        out.extend(0u16.to_le_bytes());
+        out
+    }
+
+    fn read(archive: &ZipArchive, name: &str) -> Result<Vec<u8>, String> {
+        let mut data = Vec::new();
+        let mut entry = archive.open_entry(name)?;
+        entry.read_to_end(&mut data).map_err(|e| e.to_string())?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_read() {
+        let path = std::env::temp_dir().join(format!("fastboot-zip-{}.zip", std::process::id()));
+        let boot: Vec<u8> = (0..10000u32).map(|i| (i % 7) as u8).collect();
+        let mut data = zip(&[("android-info.txt", b"board=x\n"), ("boot.img", &boot)]);
+        std::fs::write(&path, &data).unwrap();
+
+        let archive = ZipArchive::open(&path).unwrap();
+        assert_eq!(archive.entries.len(), 2);
+        assert_eq!(archive.entry("boot.img").unwrap().size, 10000);
+        assert_eq!(read(&archive, "android-info.txt").unwrap(), b"board=x\n");
+        assert_eq!(read(&archive, "boot.img").unwrap(), boot);
+        assert!(read(&archive, "system.img").is_err());
+
+        // Corrupt the stored entry
+        data[30 + 16] ^= 1;
+        std::fs::write(&path, &data).unwrap();
+        assert!(read(&archive, "android-info.txt").is_err());
+
+        std::fs::write(&path, b"not a zip").unwrap();
+        assert!(ZipArchive::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+//! Measures bulk OUT throughput of `UsbDevice`.
+//!
+//! Without a bootloader at hand, the Linux USB gadget zero (`g_zero`) serves as
+//! a stand-in: its first configuration sinks everything written to it.
+
+use std::io::Write;
+use std::time::Instant;
+
+use getopts::Options;
+use usbio::{poll_dev, UsbDevice};
+
+// Linux USB gadget zero (source/sink, loopback)
+const DEFAULT_VID: u16 = 0x1a0a;
+const DEFAULT_PID: u16 = 0xbadd;
+
+fn usage(program: &str, opts: &Options) {
+    let ver = env!("CARGO_PKG_VERSION");
+    let brief = format!("Version: {ver}\nUsage: {program} [options]");
+    println!("{}", opts.usage(&brief));
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let program = args[0].clone();
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "Print help");
+    opts.optopt("", "vid", "Vendor ID", "<hex>");
+    opts.optopt("", "pid", "Product ID", "<hex>");
+    opts.optopt("", "size", "MiB to write in total", "<size>");
+    opts.optopt("", "transfer-size", "KiB per bulk transfer", "<size>");
+    opts.optopt("", "queue-depth", "Transfers in flight", "<depth>");
+
+    let matches = opts.parse(&args[1..]).unwrap_or_else(|err| {
+        eprintln!("{} failed to parse arguments ({})!", &program, err);
+        usage(&program, &opts);
+        std::process::exit(-1);
+    });
+
+    if matches.opt_present("h") {
+        usage(&program, &opts);
+        std::process::exit(0);
+    }
+
+    let vid = match matches.opt_str("vid") {
+        Some(v) => u16::from_str_radix(&v, 16).expect("Parsing vendor ID failed"),
+        None => DEFAULT_VID,
+    };
+    let pid = match matches.opt_str("pid") {
+        Some(v) => u16::from_str_radix(&v, 16).expect("Parsing product ID failed"),
+        None => DEFAULT_PID,
+    };
+    let size: usize = match matches.opt_str("size") {
+        Some(v) => str::parse(&v).expect("Parsing size failed"),
+        None => 256,
+    };
+
+    let di = poll_dev(vid, pid).expect("Device not found, is it connected?");
+    let mut dev = UsbDevice::new(di);
+
+    if let Some(v) = matches.opt_str("transfer-size") {
+        let kib: usize = str::parse(&v).expect("Parsing transfer size failed");
+        dev.set_transfer_size(kib * 1024);
+    }
+    if let Some(v) = matches.opt_str("queue-depth") {
+        dev.set_queue_depth(str::parse(&v).expect("Parsing queue depth failed"));
+    }
+
+    let data = vec![0x5a; size * 1024 * 1024];
+    let start = Instant::now();
+    dev.write_all(&data).expect("Writing failed");
+    dev.flush().expect("Writing failed");
+    let secs = start.elapsed().as_secs_f64();
+
+    let mbps = data.len() as f64 / secs / 1e6;
+    println!("{size} MiB in {secs:.3} s: {mbps:.1} MB/s");
+}
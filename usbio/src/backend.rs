@@ -0,0 +1,201 @@
+//! Bulk transfer backends for [`UsbDevice`](crate::UsbDevice).
+//!
+//! [`NusbBackend`] talks to real hardware. Everything above it, i.e. endpoint
+//! selection, timeouts, ZLPs, buffering and stall recovery, lives in
+//! `UsbDevice`, so that it can be tested against [`FakeBackend`](crate::fake::FakeBackend).
+
+use std::fmt;
+use std::io::{self, ErrorKind, ErrorKind::TimedOut, Result};
+use std::time::Duration;
+
+use async_io::{block_on, Timer};
+use futures_lite::FutureExt;
+use nusb::{
+    transfer::{EndpointType, RequestBuffer, TransferError},
+    DeviceInfo, Interface,
+};
+
+/// A bulk endpoint as described by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Endpoint {
+    pub address: u8,
+    pub max_packet_size: usize,
+}
+
+impl Endpoint {
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+}
+
+/// Bulk transfers on the endpoints of a claimed interface.
+pub trait Backend {
+    /// Lists the bulk endpoints of the interface.
+    fn endpoints(&self) -> Vec<Endpoint>;
+
+    /// Receives a single transfer of up to `len` bytes from endpoint `ep`.
+    fn bulk_in(&mut self, ep: u8, len: usize, timeout: Duration) -> Result<Vec<u8>>;
+
+    /// Sends `transfers` in order on endpoint `ep`, with up to `depth` of them
+    /// in flight at a time. `timeout` applies to each transfer. Returns the
+    /// number of bytes sent.
+    fn bulk_out(
+        &mut self,
+        ep: u8,
+        transfers: &[&[u8]],
+        depth: usize,
+        timeout: Duration,
+    ) -> Result<usize>;
+
+    /// Clears a halt (stall) condition on endpoint `ep`.
+    fn clear_halt(&mut self, ep: u8) -> Result<()>;
+}
+
+/// Transfer failures that callers may want to react to, carried inside the
+/// [`io::Error`]s returned by [`Backend`]s and [`UsbDevice`](crate::UsbDevice).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsbError {
+    /// The device stalled the endpoint; it needs a clear-halt to recover.
+    Stall { endpoint: u8 },
+    /// The device is gone.
+    Disconnected,
+    /// Any other transfer error.
+    Transfer(TransferError),
+}
+
+impl UsbError {
+    /// Gets the `UsbError` an [`io::Error`] was created from, if any.
+    pub fn of(err: &io::Error) -> Option<UsbError> {
+        err.get_ref()?.downcast_ref::<UsbError>().copied()
+    }
+}
+
+impl fmt::Display for UsbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UsbError::Stall { endpoint } => write!(f, "endpoint {endpoint:#04x} stalled"),
+            UsbError::Disconnected => write!(f, "device disconnected"),
+            UsbError::Transfer(err) => write!(f, "transfer failed: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for UsbError {}
+
+impl From<UsbError> for io::Error {
+    fn from(err: UsbError) -> Self {
+        let kind = match err {
+            UsbError::Stall { .. } => ErrorKind::BrokenPipe,
+            UsbError::Disconnected => ErrorKind::NotConnected,
+            UsbError::Transfer(_) => ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+pub(crate) fn transfer_error(ep: u8, err: TransferError) -> io::Error {
+    match err {
+        TransferError::Stall => UsbError::Stall { endpoint: ep },
+        TransferError::Disconnected => UsbError::Disconnected,
+        err => UsbError::Transfer(err),
+    }
+    .into()
+}
+
+async fn timeout<T>(d: Duration) -> Result<T> {
+    Timer::after(d).await;
+    Err(TimedOut.into())
+}
+
+/// The [`Backend`] for real devices, based on [`nusb`].
+pub struct NusbBackend {
+    i: Interface,
+    endpoints: Vec<Endpoint>,
+}
+
+impl NusbBackend {
+    pub fn open(di: &DeviceInfo) -> Result<Self> {
+        // Just use the first interface - might need improvement
+        let ii = di
+            .interfaces()
+            .next()
+            .ok_or_else(|| io::Error::other("device has no interfaces"))?
+            .interface_number();
+        let d = di.open()?;
+        let i = d.claim_interface(ii)?;
+
+        let c = d
+            .configurations()
+            .next()
+            .ok_or_else(|| io::Error::other("device has no configurations"))?;
+        let endpoints = c
+            .interface_alt_settings()
+            .find(|s| s.interface_number() == ii)
+            .ok_or_else(|| io::Error::other("interface has no descriptor"))?
+            .endpoints()
+            .filter(|e| e.transfer_type() == EndpointType::Bulk)
+            .map(|e| Endpoint {
+                address: e.address(),
+                max_packet_size: e.max_packet_size(),
+            })
+            .collect();
+
+        Ok(NusbBackend { i, endpoints })
+    }
+}
+
+impl Backend for NusbBackend {
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.endpoints.clone()
+    }
+
+    fn bulk_in(&mut self, ep: u8, len: usize, t: Duration) -> Result<Vec<u8>> {
+        let fut = async {
+            let comp = self.i.bulk_in(ep, RequestBuffer::new(len)).await;
+            comp.status.map_err(|e| transfer_error(ep, e))?;
+            Ok(comp.data)
+        };
+        block_on(fut.or(timeout(t)))
+    }
+
+    // The host controller always has the next transfer at hand when one
+    // completes, which keeps the bus busy.
+    fn bulk_out(
+        &mut self,
+        ep: u8,
+        transfers: &[&[u8]],
+        depth: usize,
+        t: Duration,
+    ) -> Result<usize> {
+        let mut q = self.i.bulk_out_queue(ep);
+        let mut transfers = transfers.iter();
+        let mut spare: Vec<Vec<u8>> = Vec::new();
+        let mut n = 0;
+
+        block_on(async {
+            loop {
+                while q.pending() < depth {
+                    let Some(t) = transfers.next() else {
+                        break;
+                    };
+                    let mut b = spare.pop().unwrap_or_default();
+                    b.clear();
+                    b.extend_from_slice(t);
+                    q.submit(b);
+                }
+                if q.pending() == 0 {
+                    return Ok(n);
+                }
+
+                let comp = async { Ok(q.next_complete().await) }.or(timeout(t)).await?;
+                comp.status.map_err(|e| transfer_error(ep, e))?;
+                n += comp.data.actual_length();
+                spare.push(comp.data.reuse());
+            }
+        })
+    }
+
+    fn clear_halt(&mut self, ep: u8) -> Result<()> {
+        self.i.clear_halt(ep)
+    }
+}
@@ -0,0 +1,151 @@
+//! Buffering for bulk IN transfers.
+//!
+//! A bulk IN transfer must be a multiple of the max packet size, and the device
+//! may send more than the caller asked for in a single `read`. Whatever is not
+//! consumed is kept for the next call instead of being dropped.
+
+use std::io::Result;
+
+#[derive(Debug, Default)]
+pub(crate) struct ReadBuffer {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadBuffer {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads into `out`, serving leftover bytes from a previous transfer first.
+    /// Only when none are left, `transfer` is called with the number of bytes
+    /// to request: `out.len()` rounded up to a multiple of `mps`, capped at
+    /// `max_len`.
+    pub(crate) fn read<F>(
+        &mut self,
+        out: &mut [u8],
+        mps: usize,
+        max_len: usize,
+        transfer: F,
+    ) -> Result<usize>
+    where
+        F: FnOnce(usize) -> Result<Vec<u8>>,
+    {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.buf.len() {
+            let len = request_len(out.len(), mps, max_len);
+            self.buf = transfer(len)?;
+            self.pos = 0;
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn request_len(len: usize, mps: usize, max_len: usize) -> usize {
+    let len = if mps == 0 {
+        len
+    } else {
+        len.div_ceil(mps) * mps
+    };
+    len.min(max_len.max(mps)).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{request_len, ReadBuffer};
+    use std::collections::VecDeque;
+    use std::io::{Error, ErrorKind, Result};
+
+    const MPS: usize = 512;
+
+    // A bulk IN endpoint that returns scripted transfers and records how many
+    // bytes were requested for each.
+    struct FakeEndpoint {
+        transfers: VecDeque<Vec<u8>>,
+        requested: Vec<usize>,
+    }
+
+    impl FakeEndpoint {
+        fn new(transfers: &[&[u8]]) -> Self {
+            FakeEndpoint {
+                transfers: transfers.iter().map(|t| t.to_vec()).collect(),
+                requested: Vec::new(),
+            }
+        }
+
+        fn transfer(&mut self, len: usize) -> Result<Vec<u8>> {
+            self.requested.push(len);
+            let mut t = self
+                .transfers
+                .pop_front()
+                .ok_or_else(|| Error::from(ErrorKind::TimedOut))?;
+            t.truncate(len);
+            Ok(t)
+        }
+    }
+
+    #[test]
+    fn test_request_len() {
+        assert_eq!(request_len(4, MPS, 1 << 20), MPS);
+        assert_eq!(request_len(512, MPS, 1 << 20), MPS);
+        assert_eq!(request_len(513, MPS, 1 << 20), 2 * MPS);
+        assert_eq!(request_len(4 << 20, MPS, 1 << 20), 1 << 20);
+        assert_eq!(request_len(100, 0, 1 << 20), 100);
+    }
+
+    #[test]
+    fn test_small_reads_keep_leftover() {
+        let mut ep = FakeEndpoint::new(&[b"OKAY1.0", b"FAIL"]);
+        let mut rb = ReadBuffer::new();
+
+        let mut head = [0; 4];
+        let n = rb
+            .read(&mut head, MPS, 1 << 20, |l| ep.transfer(l))
+            .unwrap();
+        assert_eq!(&head[..n], b"OKAY");
+        let mut rest = [0; 64];
+        let n = rb
+            .read(&mut rest, MPS, 1 << 20, |l| ep.transfer(l))
+            .unwrap();
+        assert_eq!(&rest[..n], b"1.0");
+        let n = rb
+            .read(&mut rest, MPS, 1 << 20, |l| ep.transfer(l))
+            .unwrap();
+        assert_eq!(&rest[..n], b"FAIL");
+
+        assert_eq!(ep.requested, vec![MPS, MPS]);
+    }
+
+    #[test]
+    fn test_large_read_in_one_transfer() {
+        let data = vec![0xa5; 3 * MPS];
+        let mut ep = FakeEndpoint::new(&[&data]);
+        let mut rb = ReadBuffer::new();
+
+        let mut buf = vec![0; 4 * MPS];
+        let n = rb.read(&mut buf, MPS, 1 << 20, |l| ep.transfer(l)).unwrap();
+        assert_eq!(n, 3 * MPS);
+        assert_eq!(&buf[..n], &data[..]);
+        assert_eq!(ep.requested, vec![4 * MPS]);
+    }
+
+    #[test]
+    fn test_error_keeps_nothing() {
+        let mut ep = FakeEndpoint::new(&[]);
+        let mut rb = ReadBuffer::new();
+
+        let mut buf = [0; 4];
+        let err = rb.read(&mut buf, MPS, 1 << 20, |l| ep.transfer(l));
+        assert_eq!(err.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(
+            rb.read(&mut [], MPS, 1 << 20, |l| ep.transfer(l)).unwrap(),
+            0
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! An in-memory [`Backend`] for tests.
+//!
+//! Results of IN and OUT transfers are scripted ahead of time with
+//! [`FakeBackend::push_in`] and [`FakeBackend::push_out`]. All OUT transfers
+//! and IN requests are recorded for later inspection.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind::TimedOut, Result};
+use std::time::Duration;
+
+use nusb::transfer::TransferError;
+
+use crate::backend::{transfer_error, Backend, Endpoint};
+
+/// The result of a single scripted transfer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The transfer completes. For IN transfers, this is the data the device
+    /// sends; it is truncated to the requested length. For OUT transfers, the
+    /// data is ignored and the transfer is accepted as a whole.
+    Data(Vec<u8>),
+    /// The device stalls the endpoint.
+    Stall,
+    /// The device is gone.
+    Disconnect,
+    /// Nothing happens until the timeout expires.
+    Timeout,
+}
+
+impl Outcome {
+    fn into_result(self, ep: u8) -> Result<Vec<u8>> {
+        match self {
+            Outcome::Data(d) => Ok(d),
+            Outcome::Stall => Err(transfer_error(ep, TransferError::Stall)),
+            Outcome::Disconnect => Err(transfer_error(ep, TransferError::Disconnected)),
+            Outcome::Timeout => Err(TimedOut.into()),
+        }
+    }
+}
+
+pub const FAKE_EP_IN: u8 = 0x81;
+pub const FAKE_EP_OUT: u8 = 0x01;
+
+#[derive(Debug, Clone)]
+pub struct FakeBackend {
+    endpoints: Vec<Endpoint>,
+    ins: VecDeque<Outcome>,
+    outs: VecDeque<Outcome>,
+    requested: Vec<usize>,
+    written: Vec<Vec<u8>>,
+    timeouts: Vec<Duration>,
+    cleared: Vec<u8>,
+}
+
+impl Default for FakeBackend {
+    fn default() -> Self {
+        Self::new(512)
+    }
+}
+
+impl FakeBackend {
+    /// Creates a backend with one bulk IN and one bulk OUT endpoint of the
+    /// given max packet size.
+    pub fn new(max_packet_size: usize) -> Self {
+        Self::with_endpoints(vec![
+            Endpoint {
+                address: FAKE_EP_IN,
+                max_packet_size,
+            },
+            Endpoint {
+                address: FAKE_EP_OUT,
+                max_packet_size,
+            },
+        ])
+    }
+
+    pub fn with_endpoints(endpoints: Vec<Endpoint>) -> Self {
+        FakeBackend {
+            endpoints,
+            ins: VecDeque::new(),
+            outs: VecDeque::new(),
+            requested: Vec::new(),
+            written: Vec::new(),
+            timeouts: Vec::new(),
+            cleared: Vec::new(),
+        }
+    }
+
+    /// Scripts the next IN transfer. Once the script runs out, IN transfers
+    /// time out.
+    pub fn push_in(&mut self, outcome: Outcome) {
+        self.ins.push_back(outcome);
+    }
+
+    /// Scripts the next OUT transfer. Once the script runs out, OUT transfers
+    /// succeed.
+    pub fn push_out(&mut self, outcome: Outcome) {
+        self.outs.push_back(outcome);
+    }
+
+    /// Lengths requested by IN transfers so far.
+    pub fn requested(&self) -> &[usize] {
+        &self.requested
+    }
+
+    /// Data of the OUT transfers that completed so far.
+    pub fn written(&self) -> &[Vec<u8>] {
+        &self.written
+    }
+
+    /// Timeouts passed with each transfer so far.
+    pub fn timeouts(&self) -> &[Duration] {
+        &self.timeouts
+    }
+
+    /// Endpoints that had their halt cleared so far.
+    pub fn cleared(&self) -> &[u8] {
+        &self.cleared
+    }
+}
+
+impl Backend for FakeBackend {
+    fn endpoints(&self) -> Vec<Endpoint> {
+        self.endpoints.clone()
+    }
+
+    fn bulk_in(&mut self, ep: u8, len: usize, timeout: Duration) -> Result<Vec<u8>> {
+        self.requested.push(len);
+        self.timeouts.push(timeout);
+        let mut d = self
+            .ins
+            .pop_front()
+            .unwrap_or(Outcome::Timeout)
+            .into_result(ep)?;
+        d.truncate(len);
+        Ok(d)
+    }
+
+    fn bulk_out(
+        &mut self,
+        ep: u8,
+        transfers: &[&[u8]],
+        _depth: usize,
+        timeout: Duration,
+    ) -> Result<usize> {
+        let mut n = 0;
+        for t in transfers {
+            self.timeouts.push(timeout);
+            let outcome = self.outs.pop_front().unwrap_or(Outcome::Data(Vec::new()));
+            outcome.into_result(ep)?;
+            self.written.push(t.to_vec());
+            n += t.len();
+        }
+        Ok(n)
+    }
+
+    fn clear_halt(&mut self, ep: u8) -> Result<()> {
+        self.cleared.push(ep);
+        Ok(())
+    }
+}
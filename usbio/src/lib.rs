@@ -1,2 +1,6 @@
+pub mod backend;
+mod buffer;
+pub mod fake;
 mod usbio;
-pub use usbio::{poll_dev, UsbDevice};
+pub use backend::UsbError;
+pub use usbio::{poll_dev, Quirks, UsbDevice};
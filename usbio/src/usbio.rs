@@ -1,24 +1,63 @@
 //! Android protocol specification:
 //! https://android.googlesource.com/platform/system/core/+/master/fastboot/README.md
 
-use std::io::{self, ErrorKind::TimedOut, Read, Result, Write};
+use std::io::{self, Read, Result, Write};
 use std::thread;
 use std::time::{Duration, Instant};
 
-use async_io::{block_on, Timer};
-use futures_lite::FutureExt;
-use nusb::{
-    transfer::{Direction, EndpointType, RequestBuffer},
-    DeviceInfo, Interface, Speed,
-};
+use crate::backend::{Backend, Endpoint, NusbBackend, UsbError};
+use crate::buffer::ReadBuffer;
+use nusb::DeviceInfo;
 
-pub struct UsbDevice {
-    i: Interface,
-    bufsize: usize,
+/// Device-specific workarounds for bootloaders that deviate from the spec.
+#[derive(Debug, Clone, Copy)]
+pub struct Quirks {
+    /// Terminate bulk OUT transfers whose length is a multiple of the
+    /// endpoint's max packet size with a zero-length packet (ZLP). Without
+    /// it, some bootloaders keep waiting for more data and hang.
+    ///
+    /// NOTE: Everything written up to a [`flush`](Write::flush) or a read
+    /// counts as one transfer, so that data streamed in multiple writes is
+    /// only terminated once.
+    pub zlp: bool,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks { zlp: true }
+    }
+}
+
+pub struct UsbDevice<B: Backend = NusbBackend> {
+    backend: B,
     e_in: u8,
     e_out: u8,
+    mps_in: usize,
+    mps_out: usize,
+    quirks: Quirks,
+    timeout: Duration,
+    transfer_size: usize,
+    queue_depth: usize,
+    stall_retries: u32,
+    last_cmd: Option<Vec<u8>>,
+    // The last reply was final (OKAY or FAIL), so the next write is a command
+    // rather than data.
+    idle: bool,
+    unterminated: usize,
+    rb: ReadBuffer,
 }
 
+// Per spec, commands are limited to 64 bytes. Anything longer is data.
+const MAX_CMD_LEN: usize = 64;
+
+// Per transfer; the fastboot trait retries reads that time out.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Large enough to saturate High Speed, small enough for old usbfs limits.
+const DEFAULT_TRANSFER_SIZE: usize = 1024 * 1024;
+// Keeps the bus busy while we complete and resubmit a transfer.
+const DEFAULT_QUEUE_DEPTH: usize = 4;
+
 // this should be plenty
 const POLL_DEV_TIMEOUT: Duration = Duration::from_secs(100);
 // some devices only show up only briefly, so we have to be quick
@@ -51,92 +90,438 @@ pub fn poll_dev(vid: u16, pid: u16) -> std::result::Result<DeviceInfo, String> {
 // - 64 bytes for full-speed
 // - 512 bytes for high-speed
 // - 1024 bytes for Super Speed USB.
+// We take it from the endpoint descriptors instead of inferring it from the
+// speed, since some devices report the speed incorrectly.
 impl UsbDevice {
     pub fn new(di: DeviceInfo) -> Self {
-        // Just use the first interface - might need improvement
-        let ii = di.interfaces().next().unwrap().interface_number();
-        let d = di.open().unwrap();
-        let i = d.claim_interface(ii).unwrap();
-
-        let speed = di.speed().unwrap();
-        let bufsize = match speed {
-            Speed::Full | Speed::Low => 64,
-            Speed::High => 512,
-            Speed::Super | Speed::SuperPlus => 1024,
-            _ => panic!("Unknown USB device speed {speed:?}"),
-        };
+        Self::with_quirks(di, Quirks::default())
+    }
 
-        // Per spec, there must be two endpoints - bulk in and bulk out
-        // TODO: Nice error messages when either is not found
-        let c = d.configurations().next().unwrap();
-        let s = c.interface_alt_settings().next().unwrap();
-        let mut es = s
-            .endpoints()
-            .filter(|e| e.transfer_type() == EndpointType::Bulk);
-        let e_in = es
-            .find(|e| e.direction() == Direction::In)
-            .unwrap()
-            .address();
-        let e_out = es
-            .find(|e| e.direction() == Direction::Out)
-            .unwrap()
-            .address();
+    pub fn with_quirks(di: DeviceInfo, quirks: Quirks) -> Self {
+        let backend = NusbBackend::open(&di).unwrap();
+        Self::with_backend(backend, quirks).unwrap()
+    }
+}
+
+impl<B: Backend> UsbDevice<B> {
+    pub fn with_backend(backend: B, quirks: Quirks) -> Result<Self> {
+        let (ep_in, ep_out) = select_endpoints(&backend.endpoints())?;
+
+        Ok(UsbDevice {
+            backend,
+            e_in: ep_in.address,
+            e_out: ep_out.address,
+            mps_in: ep_in.max_packet_size,
+            mps_out: ep_out.max_packet_size,
+            quirks,
+            timeout: DEFAULT_TIMEOUT,
+            transfer_size: DEFAULT_TRANSFER_SIZE,
+            queue_depth: DEFAULT_QUEUE_DEPTH,
+            stall_retries: 0,
+            last_cmd: None,
+            idle: true,
+            unterminated: 0,
+            rb: ReadBuffer::new(),
+        })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Sets the timeout for each individual transfer.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Sets the size of the individual bulk OUT transfers a write is split
+    /// into. It is rounded down to a multiple of the max packet size, so that
+    /// only the last transfer of a write may end in a short packet.
+    pub fn set_transfer_size(&mut self, size: usize) {
+        self.transfer_size = transfer_size(size, self.mps_out);
+    }
+
+    /// Sets how many bulk OUT transfers are kept in flight at once.
+    pub fn set_queue_depth(&mut self, depth: usize) {
+        self.queue_depth = depth.max(1);
+    }
+
+    /// Sets how often the current command is sent again after the device
+    /// stalled an endpoint. The halt is cleared either way, so that the
+    /// session stays usable. Data phases, including short ones, and commands
+    /// the device already answered are never sent again.
+    pub fn set_stall_retries(&mut self, retries: u32) {
+        self.stall_retries = retries;
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let (backend, e_in, timeout) = (&mut self.backend, self.e_in, self.timeout);
+        self.rb.read(buf, self.mps_in, self.transfer_size, |len| {
+            backend.bulk_in(e_in, len, timeout)
+        })
+    }
+
+    fn send(&mut self, buf: &[u8]) -> Result<usize> {
+        let transfers: Vec<&[u8]> = buf.chunks(self.transfer_size).collect();
+        self.backend
+            .bulk_out(self.e_out, &transfers, self.queue_depth, self.timeout)
+    }
+
+    // Ends the current transfer, with a ZLP if needed.
+    fn terminate(&mut self) -> Result<()> {
+        let len = std::mem::take(&mut self.unterminated);
+        if self.quirks.zlp && needs_zlp(len, self.mps_out) {
+            self.backend.bulk_out(self.e_out, &[&[]], 1, self.timeout)?;
+        }
+        Ok(())
+    }
 
-        UsbDevice {
-            i,
-            bufsize,
-            e_in,
-            e_out,
+    // Clears the halt on a stalled endpoint and tells whether the command
+    // should be retried.
+    fn recover(&mut self, err: &io::Error, retries: &mut u32) -> Result<bool> {
+        let Some(UsbError::Stall { endpoint }) = UsbError::of(err) else {
+            return Ok(false);
+        };
+        self.backend.clear_halt(endpoint)?;
+        if *retries == 0 || self.last_cmd.is_none() {
+            return Ok(false);
         }
+        *retries -= 1;
+        Ok(true)
+    }
+}
+
+// Per spec, there must be two endpoints - bulk in and bulk out
+fn select_endpoints(endpoints: &[Endpoint]) -> Result<(Endpoint, Endpoint)> {
+    let ep_in = endpoints
+        .iter()
+        .find(|e| e.is_in())
+        .ok_or_else(|| io::Error::other("no bulk IN endpoint found"))?;
+    let ep_out = endpoints
+        .iter()
+        .find(|e| !e.is_in())
+        .ok_or_else(|| io::Error::other("no bulk OUT endpoint found"))?;
+    Ok((*ep_in, *ep_out))
+}
+
+fn transfer_size(size: usize, mps: usize) -> usize {
+    if mps == 0 {
+        return size.max(1);
     }
+    (size - size % mps).max(mps)
+}
+
+// OKAY and FAIL end a command; INFO and DATA do not.
+fn is_final_reply(reply: &[u8]) -> bool {
+    reply.starts_with(b"OKAY") || reply.starts_with(b"FAIL")
 }
 
-impl Read for UsbDevice {
+// A transfer that ends on a packet boundary is only terminated by a ZLP.
+fn needs_zlp(len: usize, mps: usize) -> bool {
+    len > 0 && len.is_multiple_of(mps)
+}
+
+impl<B: Backend> Read for UsbDevice<B> {
+    // Small reads (e.g. a reply header) and large ones (e.g. upload data) are
+    // both served through the read buffer, which requests as much as the
+    // caller wants in one transfer and keeps what it does not consume.
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
 
-        let timeout = Duration::from_secs(3);
-        let fut = async {
-            let b = RequestBuffer::new(self.bufsize);
-            let comp = self.i.bulk_in(self.e_in, b).await;
-            comp.status.map_err(io::Error::other)?;
-
-            let n = comp.data.len();
-            buf[..n].copy_from_slice(&comp.data);
-            Ok(n)
-        };
-
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
+        self.terminate()?;
+        let mut retries = self.stall_retries;
+        loop {
+            let err = match self.receive(buf) {
+                Ok(n) => {
+                    // The command was answered, so it must not be sent again.
+                    self.last_cmd = None;
+                    self.idle = is_final_reply(&buf[..n]);
+                    return Ok(n);
+                }
+                Err(err) => err,
+            };
+            if !self.recover(&err, &mut retries)? {
+                return Err(err);
+            }
+            // The device stalled instead of replying; ask again.
+            if let Some(cmd) = self.last_cmd.clone() {
+                self.unterminated = self.send(&cmd)?;
+                self.terminate()?;
+            }
+        }
     }
 }
 
-impl Write for UsbDevice {
+impl<B: Backend> Write for UsbDevice<B> {
+    // The buffer is split into transfers of `transfer_size`, of which up to
+    // `queue_depth` are in flight at a time.
     fn write(&mut self, buf: &[u8]) -> Result<usize> {
         if buf.is_empty() {
             return Ok(0);
         }
 
-        let timeout = Duration::from_secs(3);
-        let fut = async {
-            let comp = self.i.bulk_out(self.e_out, buf.to_vec()).await;
-            comp.status.map_err(io::Error::other)?;
+        // Only a write right after a final reply is a command; anything else,
+        // however short, belongs to a data phase.
+        self.last_cmd = (self.idle && buf.len() <= MAX_CMD_LEN).then(|| buf.to_vec());
+        self.idle = false;
+        let mut retries = self.stall_retries;
+        loop {
+            let err = match self.send(buf) {
+                Ok(n) => {
+                    self.unterminated += n;
+                    return Ok(n);
+                }
+                Err(err) => err,
+            };
+            if !self.recover(&err, &mut retries)? {
+                return Err(err);
+            }
+        }
+    }
 
-            let n = comp.data.actual_length();
-            Ok(n)
-        };
+    fn flush(&mut self) -> Result<()> {
+        self.terminate()
+    }
+}
 
-        block_on(fut.or(async {
-            Timer::after(timeout).await;
-            Err(TimedOut.into())
-        }))
+#[cfg(test)]
+mod tests {
+    use super::{needs_zlp, transfer_size, Quirks, UsbDevice};
+    use crate::backend::{Endpoint, UsbError};
+    use crate::fake::{FakeBackend, Outcome, FAKE_EP_IN, FAKE_EP_OUT};
+    use std::io::{ErrorKind, Read, Write};
+
+    #[test]
+    fn test_needs_zlp() {
+        assert!(!needs_zlp(0, 512));
+        assert!(!needs_zlp(4, 512));
+        assert!(needs_zlp(512, 512));
+        assert!(needs_zlp(4096, 512));
+        assert!(!needs_zlp(4097, 512));
+        assert!(needs_zlp(64, 64));
     }
 
-    fn flush(&mut self) -> Result<()> {
-        Ok(())
+    #[test]
+    fn test_transfer_size() {
+        assert_eq!(transfer_size(1024 * 1024, 512), 1024 * 1024);
+        assert_eq!(transfer_size(1000, 512), 512);
+        assert_eq!(transfer_size(100, 512), 512);
+        assert_eq!(transfer_size(1000, 0), 1000);
+    }
+
+    #[test]
+    fn test_endpoint_selection() {
+        let fake = FakeBackend::with_endpoints(vec![Endpoint {
+            address: FAKE_EP_IN,
+            max_packet_size: 512,
+        }]);
+        let err = UsbDevice::with_backend(fake, Quirks::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "no bulk OUT endpoint found");
+
+        let fake = FakeBackend::with_endpoints(vec![
+            Endpoint {
+                address: FAKE_EP_OUT,
+                max_packet_size: 64,
+            },
+            Endpoint {
+                address: FAKE_EP_IN,
+                max_packet_size: 512,
+            },
+        ]);
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        dev.write_all(&[0; 64]).unwrap();
+        dev.flush().unwrap();
+        assert_eq!(dev.backend().written(), &[vec![0; 64], vec![]]);
+    }
+
+    #[test]
+    fn test_write_zlp_quirk() {
+        let mut dev = UsbDevice::with_backend(FakeBackend::new(512), Quirks::default()).unwrap();
+        dev.set_transfer_size(1024);
+        dev.write_all(&[1; 2048]).unwrap();
+        dev.flush().unwrap();
+        assert_eq!(
+            dev.backend().written(),
+            &[vec![1; 1024], vec![1; 1024], vec![]]
+        );
+
+        let quirks = Quirks { zlp: false };
+        let mut dev = UsbDevice::with_backend(FakeBackend::new(512), quirks).unwrap();
+        dev.write_all(&[1; 512]).unwrap();
+        dev.flush().unwrap();
+        assert_eq!(dev.backend().written(), &[vec![1; 512]]);
+    }
+
+    #[test]
+    fn test_zlp_ends_streamed_transfer() {
+        // Data streamed in several writes is one transfer, terminated once by
+        // the read of the reply.
+        let mut dev = UsbDevice::with_backend(FakeBackend::new(512), Quirks::default()).unwrap();
+        dev.write_all(&[1; 512]).unwrap();
+        dev.write_all(&[2; 512]).unwrap();
+        assert_eq!(dev.backend().written(), &[vec![1; 512], vec![2; 512]]);
+        dev.read(&mut [0; 64]).unwrap_err();
+        assert_eq!(
+            dev.backend().written(),
+            &[vec![1; 512], vec![2; 512], vec![]]
+        );
+
+        // Or by a flush, which only terminates once.
+        dev.write_all(&[3; 100]).unwrap();
+        dev.write_all(&[4; 412]).unwrap();
+        dev.flush().unwrap();
+        dev.flush().unwrap();
+        assert_eq!(dev.backend().written().len(), 6);
+        assert_eq!(dev.backend().written()[5], vec![]);
+
+        // A short packet needs no ZLP.
+        dev.write_all(&[5; 100]).unwrap();
+        dev.flush().unwrap();
+        assert_eq!(dev.backend().written().len(), 7);
+    }
+
+    #[test]
+    fn test_read_timeout_and_errors() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_in(Outcome::Timeout);
+        fake.push_in(Outcome::Data(b"OKAY".to_vec()));
+        fake.push_in(Outcome::Disconnect);
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        dev.set_timeout(std::time::Duration::from_millis(10));
+
+        let mut buf = [0; 64];
+        let err = dev.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert_eq!(dev.read(&mut buf).unwrap(), 4);
+        assert!(dev.read(&mut buf).is_err());
+        assert_eq!(dev.backend().requested(), &[512, 512, 512]);
+        assert_eq!(
+            dev.backend().timeouts(),
+            &[std::time::Duration::from_millis(10); 3]
+        );
+    }
+
+    #[test]
+    fn test_write_stall() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_out(Outcome::Stall);
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        let err = dev.write_all(b"getvar:version").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::BrokenPipe);
+        assert_eq!(
+            UsbError::of(&err),
+            Some(UsbError::Stall {
+                endpoint: FAKE_EP_OUT
+            })
+        );
+        assert!(dev.backend().written().is_empty());
+        assert_eq!(dev.backend().cleared(), &[FAKE_EP_OUT]);
+
+        let mut fake = FakeBackend::new(512);
+        fake.push_out(Outcome::Stall);
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        dev.set_stall_retries(1);
+        dev.write_all(b"getvar:version").unwrap();
+        assert_eq!(dev.backend().written(), &[b"getvar:version".to_vec()]);
+        assert_eq!(dev.backend().cleared(), &[FAKE_EP_OUT]);
+    }
+
+    #[test]
+    fn test_read_stall_retries_command() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_in(Outcome::Stall);
+        fake.push_in(Outcome::Data(b"OKAY1.0".to_vec()));
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        dev.set_stall_retries(2);
+
+        dev.write_all(b"getvar:version").unwrap();
+        let mut buf = [0; 64];
+        let n = dev.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"OKAY1.0");
+        assert_eq!(dev.backend().cleared(), &[FAKE_EP_IN]);
+        assert_eq!(
+            dev.backend().written(),
+            &[b"getvar:version".to_vec(), b"getvar:version".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_data_phase_not_retried() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_out(Outcome::Data(vec![]));
+        fake.push_out(Outcome::Stall);
+        let mut dev = UsbDevice::with_backend(fake, Quirks { zlp: false }).unwrap();
+        dev.set_stall_retries(3);
+
+        dev.write_all(b"download:00000100").unwrap();
+        assert!(dev.write_all(&[0; 256]).is_err());
+        assert_eq!(dev.backend().cleared(), &[FAKE_EP_OUT]);
+    }
+
+    #[test]
+    fn test_small_data_phase_not_retried() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_in(Outcome::Data(b"DATA00000010".to_vec()));
+        fake.push_in(Outcome::Stall);
+        let mut dev = UsbDevice::with_backend(fake, Quirks { zlp: false }).unwrap();
+        dev.set_stall_retries(3);
+
+        dev.write_all(b"download:00000010").unwrap();
+        assert_eq!(dev.read(&mut [0; 64]).unwrap(), 12);
+        dev.write_all(&[7; 16]).unwrap();
+        assert!(dev.read(&mut [0; 64]).is_err());
+        assert_eq!(
+            dev.backend().written(),
+            &[b"download:00000010".to_vec(), vec![7; 16]]
+        );
+    }
+
+    #[test]
+    fn test_answered_command_not_retried() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_in(Outcome::Data(b"OKAY".to_vec()));
+        fake.push_in(Outcome::Stall);
+        fake.push_in(Outcome::Stall);
+        fake.push_in(Outcome::Data(b"OKAY".to_vec()));
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        dev.set_stall_retries(3);
+
+        dev.write_all(b"flash:boot").unwrap();
+        assert_eq!(dev.read(&mut [0; 64]).unwrap(), 4);
+        assert!(dev.read(&mut [0; 64]).is_err());
+        assert_eq!(dev.backend().written(), &[b"flash:boot".to_vec()]);
+
+        // The next command is retried again.
+        dev.write_all(b"erase:misc").unwrap();
+        assert_eq!(dev.read(&mut [0; 64]).unwrap(), 4);
+        assert_eq!(
+            dev.backend().written(),
+            &[
+                b"flash:boot".to_vec(),
+                b"erase:misc".to_vec(),
+                b"erase:misc".to_vec()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let mut fake = FakeBackend::new(512);
+        fake.push_in(Outcome::Disconnect);
+        let mut dev = UsbDevice::with_backend(fake, Quirks::default()).unwrap();
+        dev.set_stall_retries(1);
+
+        let err = dev.read(&mut [0; 64]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotConnected);
+        assert_eq!(UsbError::of(&err), Some(UsbError::Disconnected));
+        assert!(dev.backend().cleared().is_empty());
     }
 }